@@ -0,0 +1,279 @@
+use std::{fmt::Write, sync::Arc};
+
+use argon2::{
+	password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+	Argon2,
+};
+use parking_lot::Mutex as SyncMutex;
+use rand_core::RngCore;
+use rusqlite::{params, OptionalExtension};
+
+use crate::{database::get_unix_timestamp, limits};
+
+const ACCOUNTS_DATABASE_PATH: &str = "accounts.db";
+
+// Size of the login token before hex encoding
+const TOKEN_SIZE_BYTES: usize = 32;
+
+pub struct Account {
+	pub name: String, // Nickname reserved by this account
+	pub admin: bool,
+	pub created_at: u64,
+}
+
+struct AccountRecord {
+	account: Account,
+	password_hash: String,      // Argon2 PHC string
+	token_hash: Option<String>, // SHA-1 of the login token, tokens are random so they don't need to be salted
+}
+
+// Credentials sent in the announce packet
+pub enum Credentials {
+	None,
+	Password(String),
+	Token(String),
+}
+
+pub enum AuthResult {
+	Guest,              // No credentials and the nickname isn't registered
+	LoggedIn(Account),  // Valid credentials
+	NicknameReserved,   // No credentials, but the nickname belongs to an account
+	InvalidCredentials, // Unknown account or wrong password/token
+}
+
+pub fn hash_password(password: &str) -> anyhow::Result<String> {
+	let salt = SaltString::generate(&mut OsRng);
+	match Argon2::default().hash_password(password.as_bytes(), &salt) {
+		Ok(hash) => Ok(hash.to_string()),
+		Err(e) => anyhow::bail!("Failed to hash password: {e}"),
+	}
+}
+
+pub fn verify_password(password: &str, hash: &str) -> bool {
+	PasswordHash::new(hash).is_ok_and(|hash| {
+		Argon2::default()
+			.verify_password(password.as_bytes(), &hash)
+			.is_ok()
+	})
+}
+
+// Accepts both an Argon2 PHC string and a plain text secret (legacy settings.json `admin_password`)
+pub fn verify_secret(password: &str, expected: &str) -> bool {
+	if expected.starts_with("$argon2") {
+		verify_password(password, expected)
+	} else {
+		password == expected
+	}
+}
+
+fn hash_token(token: &str) -> String {
+	sha1_smol::Sha1::from(token).digest().to_string()
+}
+
+fn generate_token() -> String {
+	let mut raw = [0u8; TOKEN_SIZE_BYTES];
+	OsRng.fill_bytes(&mut raw);
+	raw.iter().fold(String::new(), |mut out, byte| {
+		let _ = write!(out, "{byte:02x}");
+		out
+	})
+}
+
+pub fn validate_password(password: &str) -> anyhow::Result<()> {
+	if password.len() < limits::PASSWORD_LEN_MIN as usize
+		|| password.len() > limits::PASSWORD_LEN_MAX as usize
+	{
+		anyhow::bail!(
+			"Invalid password length (min {}, max {})",
+			limits::PASSWORD_LEN_MIN,
+			limits::PASSWORD_LEN_MAX
+		);
+	}
+	Ok(())
+}
+
+// Server-wide user accounts, independent of rooms.
+// Queries run on the blocking thread pool, so a slow disk never stalls the async runtime.
+pub struct AccountSystem {
+	conn: Arc<SyncMutex<rusqlite::Connection>>,
+}
+
+impl AccountSystem {
+	pub fn new() -> rusqlite::Result<Self> {
+		Self::open(ACCOUNTS_DATABASE_PATH)
+	}
+
+	fn open(path: &str) -> rusqlite::Result<Self> {
+		let conn = rusqlite::Connection::open(path)?;
+		conn.execute("CREATE TABLE IF NOT EXISTS accounts(name TEXT NOT NULL PRIMARY KEY COLLATE NOCASE, password_hash TEXT NOT NULL, token_hash TEXT, admin INT NOT NULL, created INT64 NOT NULL)", [])?;
+		log::info!("Account database at path {path} loaded");
+		Ok(Self {
+			conn: Arc::new(SyncMutex::new(conn)),
+		})
+	}
+
+	async fn query<F, ResultType>(&self, callback: F) -> anyhow::Result<ResultType>
+	where
+		F: FnOnce(&rusqlite::Connection) -> rusqlite::Result<ResultType> + Send + 'static,
+		ResultType: Send + 'static,
+	{
+		let conn = self.conn.clone();
+		Ok(tokio::task::spawn_blocking(move || callback(&conn.lock())).await??)
+	}
+
+	fn get(conn: &rusqlite::Connection, name: &str) -> rusqlite::Result<Option<AccountRecord>> {
+		conn
+			.query_row(
+				"SELECT name, password_hash, token_hash, admin, created FROM accounts WHERE name=?",
+				params![name],
+				|row| {
+					Ok(AccountRecord {
+						account: Account {
+							name: row.get(0)?,
+							admin: row.get(3)?,
+							created_at: row.get(4)?,
+						},
+						password_hash: row.get(1)?,
+						token_hash: row.get(2)?,
+					})
+				},
+			)
+			.optional()
+	}
+
+	pub async fn list(&self) -> anyhow::Result<Vec<Account>> {
+		self
+			.query(|conn| {
+				let mut stmt = conn.prepare("SELECT name, admin, created FROM accounts ORDER BY name")?;
+				let res: Vec<_> = stmt
+					.query_map([], |row| {
+						Ok(Account {
+							name: row.get(0)?,
+							admin: row.get(1)?,
+							created_at: row.get(2)?,
+						})
+					})?
+					.flatten()
+					.collect();
+				Ok(res)
+			})
+			.await
+	}
+
+	pub async fn set_admin(&self, name: &str, admin: bool) -> anyhow::Result<bool> {
+		let name = String::from(name);
+		let changed = self
+			.query(move |conn| {
+				conn.execute(
+					"UPDATE accounts SET admin=? WHERE name=?",
+					params![admin, name],
+				)
+			})
+			.await?;
+		Ok(changed > 0)
+	}
+
+	pub async fn remove(&self, name: &str) -> anyhow::Result<bool> {
+		let name = String::from(name);
+		let changed = self
+			.query(move |conn| conn.execute("DELETE FROM accounts WHERE name=?", params![name]))
+			.await?;
+		Ok(changed > 0)
+	}
+
+	pub async fn authenticate(
+		&self,
+		name: &str,
+		credentials: Credentials,
+	) -> anyhow::Result<AuthResult> {
+		let owned_name = String::from(name);
+		let record = self.query(move |conn| Self::get(conn, &owned_name)).await?;
+
+		let Some(record) = record else {
+			return Ok(match credentials {
+				Credentials::None => AuthResult::Guest,
+				_ => AuthResult::InvalidCredentials,
+			});
+		};
+
+		let valid = match credentials {
+			Credentials::None => return Ok(AuthResult::NicknameReserved),
+			Credentials::Password(password) => {
+				let hash = record.password_hash;
+				tokio::task::spawn_blocking(move || verify_password(&password, &hash)).await?
+			}
+			Credentials::Token(token) => record.token_hash == Some(hash_token(&token)),
+		};
+
+		Ok(if valid {
+			AuthResult::LoggedIn(record.account)
+		} else {
+			AuthResult::InvalidCredentials
+		})
+	}
+
+	pub async fn register(&self, name: &str, password: String) -> anyhow::Result<()> {
+		validate_password(&password)?;
+		let hash = tokio::task::spawn_blocking(move || hash_password(&password)).await??;
+
+		// The name is the primary key, so a concurrent registration of the same name fails here
+		let owned_name = String::from(name);
+		let inserted = self
+			.query(move |conn| {
+				conn.execute(
+					"INSERT OR IGNORE INTO accounts (name, password_hash, token_hash, admin, created) VALUES (?,?,NULL,0,?)",
+					params![owned_name, hash, get_unix_timestamp()],
+				)
+			})
+			.await?;
+
+		if inserted == 0 {
+			anyhow::bail!("Account {name} already exists");
+		}
+
+		log::info!("Registered account {name}");
+		Ok(())
+	}
+
+	// Also revokes the login token
+	pub async fn set_password(&self, name: &str, password: String) -> anyhow::Result<()> {
+		validate_password(&password)?;
+		let hash = tokio::task::spawn_blocking(move || hash_password(&password)).await??;
+
+		let owned_name = String::from(name);
+		let changed = self
+			.query(move |conn| {
+				conn.execute(
+					"UPDATE accounts SET password_hash=?, token_hash=NULL WHERE name=?",
+					params![hash, owned_name],
+				)
+			})
+			.await?;
+
+		if changed == 0 {
+			anyhow::bail!("Account {name} does not exist");
+		}
+		Ok(())
+	}
+
+	// Replaces the previous login token of the account
+	pub async fn issue_token(&self, name: &str) -> anyhow::Result<String> {
+		let token = generate_token();
+
+		let token_hash = hash_token(&token);
+		let owned_name = String::from(name);
+		let changed = self
+			.query(move |conn| {
+				conn.execute(
+					"UPDATE accounts SET token_hash=? WHERE name=?",
+					params![token_hash, owned_name],
+				)
+			})
+			.await?;
+
+		if changed == 0 {
+			anyhow::bail!("Account {name} does not exist");
+		}
+		Ok(token)
+	}
+}
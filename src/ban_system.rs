@@ -0,0 +1,330 @@
+use std::{fmt, net::IpAddr, sync::Arc};
+
+use parking_lot::Mutex as SyncMutex;
+use rusqlite::params;
+use tokio::sync::Mutex;
+
+use crate::{database::get_unix_timestamp, room::RoomSessionData, session::SessionInstance};
+
+const BANS_DATABASE_PATH: &str = "bans.db";
+
+#[derive(Clone, PartialEq, Eq)]
+pub enum BanTarget {
+	Ip(IpAddr),
+	Account(String), // Compared case-insensitively, like account names
+}
+
+impl BanTarget {
+	// Parses an IP address, anything else is treated as an account name
+	pub fn parse(text: &str) -> Self {
+		text
+			.parse()
+			.map_or_else(|_| Self::Account(String::from(text)), Self::Ip)
+	}
+
+	fn matches(&self, ip: Option<IpAddr>, account: Option<&str>) -> bool {
+		match self {
+			Self::Ip(banned) => ip == Some(*banned),
+			Self::Account(banned) => account.is_some_and(|account| account.eq_ignore_ascii_case(banned)),
+		}
+	}
+
+	fn to_row(&self) -> (&'static str, String) {
+		match self {
+			Self::Ip(ip) => ("ip", ip.to_string()),
+			Self::Account(account) => ("account", account.to_lowercase()),
+		}
+	}
+
+	fn from_row(kind: &str, value: &str) -> Option<Self> {
+		match kind {
+			"ip" => value.parse().ok().map(Self::Ip),
+			"account" => Some(Self::Account(String::from(value))),
+			_ => None,
+		}
+	}
+}
+
+impl fmt::Display for BanTarget {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Self::Ip(ip) => write!(f, "IP {ip}"),
+			Self::Account(account) => write!(f, "account {account}"),
+		}
+	}
+}
+
+#[derive(Clone)]
+pub struct Ban {
+	pub target: BanTarget,
+	pub expires_at: Option<u64>, // Unix timestamp, permanent if None
+}
+
+impl fmt::Display for Ban {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self.expires_at {
+			Some(expires_at) => write!(f, "{} (until {expires_at})", self.target),
+			None => write!(f, "{}", self.target),
+		}
+	}
+}
+
+impl Ban {
+	// Message shown to the banned user
+	pub fn describe(&self) -> String {
+		self.expires_at.map_or_else(
+			|| String::from("You are banned from this server"),
+			|expires_at| {
+				let remaining_m = expires_at.saturating_sub(get_unix_timestamp()).div_ceil(60);
+				format!("You are banned from this server for {remaining_m} more minutes")
+			},
+		)
+	}
+
+	fn is_expired(&self, now: u64) -> bool {
+		self.expires_at.is_some_and(|expires_at| expires_at <= now)
+	}
+}
+
+// Server-wide bans, kept in memory so every connection can be checked cheaply.
+// Changes are written on the blocking thread pool, without holding the ban system lock.
+pub struct BanSystem {
+	conn: Arc<SyncMutex<rusqlite::Connection>>,
+	bans: Vec<Ban>,
+}
+
+pub type BanSystemMutex = Arc<Mutex<BanSystem>>;
+
+impl BanSystem {
+	pub fn new() -> rusqlite::Result<Self> {
+		Self::open(BANS_DATABASE_PATH)
+	}
+
+	fn open(path: &str) -> rusqlite::Result<Self> {
+		let conn = rusqlite::Connection::open(path)?;
+		conn.execute("CREATE TABLE IF NOT EXISTS bans(kind TEXT NOT NULL, value TEXT NOT NULL, expires INT64, created INT64 NOT NULL, UNIQUE(kind, value))", [])?;
+		conn.execute(
+			"DELETE FROM bans WHERE expires IS NOT NULL AND expires<=?",
+			params![get_unix_timestamp()],
+		)?;
+
+		let bans: Vec<Ban> = {
+			let mut stmt = conn.prepare("SELECT kind, value, expires FROM bans")?;
+			let rows: Vec<(String, String, Option<u64>)> = stmt
+				.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+				.flatten()
+				.collect();
+			rows
+				.into_iter()
+				.filter_map(|(kind, value, expires_at)| {
+					Some(Ban {
+						target: BanTarget::from_row(&kind, &value)?,
+						expires_at,
+					})
+				})
+				.collect()
+		};
+
+		log::info!("Ban database at path {path} loaded ({} bans)", bans.len());
+		Ok(Self {
+			conn: Arc::new(SyncMutex::new(conn)),
+			bans,
+		})
+	}
+
+	pub fn list(&mut self) -> &[Ban] {
+		self.remove_expired();
+		&self.bans
+	}
+
+	// Returns the ban applying to this IP address or account, if any
+	pub fn check(&mut self, ip: Option<IpAddr>, account: Option<&str>) -> Option<Ban> {
+		self.remove_expired();
+		self
+			.bans
+			.iter()
+			.find(|ban| ban.target.matches(ip, account))
+			.cloned()
+	}
+
+	async fn execute<F>(bans: &BanSystemMutex, callback: F) -> anyhow::Result<usize>
+	where
+		F: FnOnce(&rusqlite::Connection) -> rusqlite::Result<usize> + Send + 'static,
+	{
+		let conn = bans.lock().await.conn.clone();
+		Ok(tokio::task::spawn_blocking(move || callback(&conn.lock())).await??)
+	}
+
+	// Replaces the previous ban of the same target
+	pub async fn add(
+		bans: &BanSystemMutex,
+		target: BanTarget,
+		duration_s: Option<u64>,
+	) -> anyhow::Result<()> {
+		let now = get_unix_timestamp();
+		let expires_at = duration_s.map(|duration_s| now.saturating_add(duration_s));
+
+		let (kind, value) = target.to_row();
+		Self::execute(bans, move |conn| {
+			conn.execute(
+				"INSERT OR REPLACE INTO bans (kind, value, expires, created) VALUES (?,?,?,?)",
+				params![kind, value, expires_at, now],
+			)
+		})
+		.await?;
+
+		let mut ban_system = bans.lock().await;
+		ban_system
+			.bans
+			.retain(|ban| ban.target.to_row() != target.to_row());
+		log::info!("Banned {target}");
+		ban_system.bans.push(Ban { target, expires_at });
+		Ok(())
+	}
+
+	// Returns false if the target wasn't banned
+	pub async fn remove(bans: &BanSystemMutex, target: &BanTarget) -> anyhow::Result<bool> {
+		let (kind, value) = target.to_row();
+		let changed = Self::execute(bans, move |conn| {
+			conn.execute(
+				"DELETE FROM bans WHERE kind=? AND value=?",
+				params![kind, value],
+			)
+		})
+		.await?;
+
+		bans
+			.lock()
+			.await
+			.bans
+			.retain(|ban| ban.target.to_row() != target.to_row());
+		Ok(changed > 0)
+	}
+
+	// Ban the user with this nick name in `candidates` (both their IP address and account) or the given IP address or account.
+	// Disconnects all affected sessions and returns the banned targets.
+	pub async fn ban_user(
+		bans: &BanSystemMutex,
+		candidates: &[RoomSessionData],
+		sessions: &[RoomSessionData],
+		target: &str,
+		duration_s: Option<u64>,
+	) -> anyhow::Result<Vec<BanTarget>> {
+		let mut targets = Vec::new();
+		for session in candidates {
+			let state = session.state.lock();
+			if state.nick_name != target {
+				continue;
+			}
+			if let Some(ip) = state.ip {
+				targets.push(BanTarget::Ip(ip));
+			}
+			if let Some(account) = &state.account {
+				targets.push(BanTarget::Account(account.clone()));
+			}
+		}
+
+		if targets.is_empty() {
+			targets.push(BanTarget::parse(target));
+		}
+
+		for target in &targets {
+			Self::add(bans, target.clone(), duration_s).await?;
+		}
+
+		let mut ban_system = bans.lock().await;
+
+		for session in sessions {
+			let ban = {
+				let state = session.state.lock();
+				ban_system.check(state.ip, state.account.as_deref())
+			};
+			if let Some(ban) = ban {
+				SessionInstance::disconnect(session.instance_mtx.clone(), ban.describe());
+			}
+		}
+
+		Ok(targets)
+	}
+
+	// Expired rows stay in the database until the next start
+	fn remove_expired(&mut self) {
+		let now = get_unix_timestamp();
+		self.bans.retain(|ban| !ban.is_expired(now));
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn open_bans(dir: &tempfile::TempDir) -> BanSystemMutex {
+		let path = dir.path().join("bans.db");
+		Arc::new(Mutex::new(BanSystem::open(path.to_str().unwrap()).unwrap()))
+	}
+
+	#[test]
+	fn target_parses_ip_or_account() {
+		assert!(matches!(BanTarget::parse("10.0.0.1"), BanTarget::Ip(_)));
+		assert!(matches!(BanTarget::parse("::1"), BanTarget::Ip(_)));
+		assert!(matches!(BanTarget::parse("alice"), BanTarget::Account(_)));
+	}
+
+	#[tokio::test]
+	async fn check_matches_ip_and_account() {
+		let dir = tempfile::tempdir().unwrap();
+		let bans = open_bans(&dir);
+		let ip: IpAddr = "10.0.0.1".parse().unwrap();
+
+		BanSystem::add(&bans, BanTarget::Ip(ip), None)
+			.await
+			.unwrap();
+		BanSystem::add(&bans, BanTarget::parse("Alice"), None)
+			.await
+			.unwrap();
+
+		let mut ban_system = bans.lock().await;
+		assert!(ban_system.check(Some(ip), None).is_some());
+		assert!(ban_system.check(None, Some("ALICE")).is_some());
+		assert!(ban_system
+			.check(Some("10.0.0.2".parse().unwrap()), Some("bob"))
+			.is_none());
+		assert!(ban_system.check(None, None).is_none());
+	}
+
+	#[tokio::test]
+	async fn bans_persist_until_removed() {
+		let dir = tempfile::tempdir().unwrap();
+		let bans = open_bans(&dir);
+		let target = BanTarget::parse("alice");
+		BanSystem::add(&bans, target.clone(), None).await.unwrap();
+
+		assert_eq!(open_bans(&dir).lock().await.list().len(), 1);
+
+		assert!(BanSystem::remove(&bans, &target).await.unwrap());
+		assert!(!BanSystem::remove(&bans, &target).await.unwrap());
+		assert!(bans.lock().await.check(None, Some("alice")).is_none());
+		assert!(open_bans(&dir).lock().await.list().is_empty());
+	}
+
+	#[tokio::test]
+	async fn expired_bans_are_dropped() {
+		let dir = tempfile::tempdir().unwrap();
+		let bans = open_bans(&dir);
+		BanSystem::add(&bans, BanTarget::parse("alice"), Some(3600))
+			.await
+			.unwrap();
+		BanSystem::add(&bans, BanTarget::parse("bob"), Some(0))
+			.await
+			.unwrap();
+
+		let mut ban_system = bans.lock().await;
+		assert!(ban_system.check(None, Some("alice")).is_some());
+		assert!(ban_system.check(None, Some("bob")).is_none());
+		assert_eq!(ban_system.list().len(), 1);
+		drop(ban_system);
+
+		// Also deleted from the database on the next start
+		assert_eq!(open_bans(&dir).lock().await.list().len(), 1);
+	}
+}
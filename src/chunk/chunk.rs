@@ -11,6 +11,7 @@ use crate::{
 	event_queue::EventQueue,
 	gen_id,
 	limits::{self, CHUNK_SIZE_PX},
+	metrics::METRICS,
 	packet_server::{self, prepare_packet_pixel_pack},
 	pixel::ColorRGBA,
 	preview_system::PreviewSystemQueuedChunks,
@@ -18,7 +19,10 @@ use crate::{
 	signal::Signal,
 };
 
-use super::compositor::{self, Compositor};
+use super::{
+	compositor::{self, Compositor},
+	journal::{JournalRecord, StrokeJournalRef},
+};
 
 #[derive(Clone)]
 pub struct ChunkPixelRGBA {
@@ -49,10 +53,11 @@ pub struct ChunkInstance {
 	compressed_image_data: Option<Arc<Vec<u8>>>,
 	preview_system_queued_chunks: PreviewSystemQueuedChunks,
 	signal_garbage_collect: Signal,
+	journal: Option<StrokeJournalRef>,
 }
 
 // Returns LZ4-compressed empty, blank chunk. Generates once.
-fn get_empty_chunk_rgba() -> &'static std::sync::Mutex<Arc<Vec<u8>>> {
+pub fn get_empty_chunk_rgba() -> &'static std::sync::Mutex<Arc<Vec<u8>>> {
 	static CHUNK_DATA: OnceLock<std::sync::Mutex<Arc<Vec<u8>>>> = OnceLock::new();
 	CHUNK_DATA.get_or_init(|| {
 		let stub_img: Vec<u8> = vec![0; limits::CHUNK_IMAGE_SIZE_BYTES_RGBA];
@@ -93,6 +98,7 @@ impl ChunkInstance {
 		preview_system_queued_chunks: PreviewSystemQueuedChunks,
 		signal_garbage_collect: Signal,
 		compressed_image_data: Option<Vec<u8>>,
+		journal: Option<StrokeJournalRef>,
 	) -> Self {
 		Self {
 			new_chunk: compressed_image_data.is_some(),
@@ -100,6 +106,7 @@ impl ChunkInstance {
 			position,
 			preview_system_queued_chunks,
 			signal_garbage_collect,
+			journal,
 			compressed_image_data: compressed_image_data.map(Arc::new),
 			main_layer: LayerRGBA::new(),
 			compositor: compositor::Compositor::new(),
@@ -135,6 +142,11 @@ impl ChunkInstance {
 		}
 	}
 
+	// Saving the encoded data failed, keeps the chunk modified so the next save retries
+	pub fn mark_unsaved(&self) {
+		*self.refs.main_modified.lock() = true;
+	}
+
 	pub fn get_pixel_main(&self, chunk_pixel_pos: U8Vec2) -> ColorRGBA {
 		self.main_layer.get_pixel(chunk_pixel_pos)
 	}
@@ -144,6 +156,9 @@ impl ChunkInstance {
 	}
 
 	pub fn replace_layer_main(&mut self, layer: LayerRGBA) {
+		if let Some(journal) = &self.journal {
+			journal.record_chunk_image(self.position, &layer.compress_lz4());
+		}
 		self.main_layer = layer;
 		self.set_main_modified(true);
 		self.send_chunk_data_to_all();
@@ -289,6 +304,10 @@ impl ChunkInstance {
 
 	pub fn set_pixels(&mut self, pixels: &[ChunkPixelRGBA], send_whole_chunk: bool) {
 		debug_assert!(self.main_layer.read().is_some());
+		METRICS.pixels_written.add(pixels.len() as u64);
+		if let Some(journal) = &self.journal {
+			journal.record_pixels(self.position, pixels);
+		}
 
 		if send_whole_chunk {
 			self.set_pixels_whole_chunk(pixels);
@@ -297,6 +316,30 @@ impl ChunkInstance {
 		}
 	}
 
+	// Applies a journal record written before a crash, without recording it again
+	pub fn replay_journal_record(&mut self, record: &JournalRecord) {
+		self.allocate_image();
+
+		match record {
+			JournalRecord::Pixels(_, pixels) => self.set_pixels_internal(pixels),
+			JournalRecord::ChunkImage(_, compressed) => {
+				let Some(raw) =
+					compression::decompress_lz4(compressed, limits::CHUNK_IMAGE_SIZE_BYTES_RGBA)
+				else {
+					log::warn!(
+						"Failed to decompress journaled chunk at {}x{}",
+						self.position.x,
+						self.position.y
+					);
+					return;
+				};
+				self.main_layer.set_data(RGBAData(raw));
+			}
+		}
+
+		self.set_main_modified(true);
+	}
+
 	pub fn send_pixel_updates(&self, coords: &[U8Vec2]) {
 		debug_assert!(self.main_layer.read().is_some());
 
@@ -0,0 +1,276 @@
+use std::{
+	fs::{File, OpenOptions},
+	io::{self, Read, Write},
+	sync::Arc,
+};
+
+use bytes::{Buf, BufMut};
+use glam::{IVec2, U8Vec2};
+use parking_lot::Mutex as SyncMutex;
+
+use crate::{config::JournalDurability, pixel::ColorRGBA};
+
+use super::chunk::ChunkPixelRGBA;
+
+// Append-only log of chunk writes since the last autosave, replayed when the room is loaded after a crash.
+//
+// Every record is framed as u32 payload size, u32 CRC32 of the payload, payload (little endian).
+// Payload: u8 kind, s32 chunk x, s32 chunk y, followed by
+//  - RECORD_PIXELS: u32 count, count * (u8 x, u8 y, u8 r, u8 g, u8 b, u8 a)
+//  - RECORD_CHUNK_IMAGE: LZ4-compressed RGBA image of the whole chunk
+//
+// Autosave rotates the journal into a second file before collecting modified chunks and removes it once
+// they are saved, so writes made during the autosave are kept in the new journal.
+
+const RECORD_PIXELS: u8 = 0;
+const RECORD_CHUNK_IMAGE: u8 = 1;
+const RECORD_HEADER_SIZE: usize = 8;
+
+pub enum JournalRecord {
+	Pixels(IVec2, Vec<ChunkPixelRGBA>),
+	ChunkImage(IVec2, Vec<u8>),
+}
+
+impl JournalRecord {
+	pub const fn chunk_pos(&self) -> IVec2 {
+		match self {
+			Self::Pixels(pos, _) | Self::ChunkImage(pos, _) => *pos,
+		}
+	}
+}
+
+pub struct StrokeJournal {
+	path: String,
+	rotated_path: String,
+	durability: JournalDurability,
+	buffer: SyncMutex<Vec<u8>>,    // Records not written to the file yet
+	file: SyncMutex<Option<File>>, // Opened on the first flush, also serializes file operations
+}
+
+pub type StrokeJournalRef = Arc<StrokeJournal>;
+
+fn encode_record(buffer: &mut Vec<u8>, payload: &[u8]) {
+	buffer.put_u32_le(payload.len() as u32);
+	buffer.put_u32_le(crc32fast::hash(payload));
+	buffer.put_slice(payload);
+}
+
+fn decode_payload(mut payload: &[u8]) -> Option<JournalRecord> {
+	if payload.remaining() < 9 {
+		return None;
+	}
+	let kind = payload.get_u8();
+	let pos = IVec2::new(payload.get_i32_le(), payload.get_i32_le());
+
+	match kind {
+		RECORD_PIXELS => {
+			if payload.remaining() < 4 {
+				return None;
+			}
+			let count = payload.get_u32_le() as usize;
+			if payload.remaining() != count * 6 {
+				return None;
+			}
+			let pixels = payload
+				.chunks_exact(6)
+				.map(|pixel| ChunkPixelRGBA {
+					pos: U8Vec2::new(pixel[0], pixel[1]),
+					color: ColorRGBA {
+						r: pixel[2],
+						g: pixel[3],
+						b: pixel[4],
+						a: pixel[5],
+					},
+				})
+				.collect();
+			Some(JournalRecord::Pixels(pos, pixels))
+		}
+		RECORD_CHUNK_IMAGE => Some(JournalRecord::ChunkImage(pos, Vec::from(payload))),
+		_ => None,
+	}
+}
+
+// Decodes records until the end of the data or the first damaged record, which is where the server crashed
+fn decode_records(mut data: &[u8], records: &mut Vec<JournalRecord>) -> bool {
+	while data.remaining() >= RECORD_HEADER_SIZE {
+		let size = (&data[0..4]).get_u32_le() as usize;
+		let crc = (&data[4..8]).get_u32_le();
+		let Some(payload) = data.get(RECORD_HEADER_SIZE..RECORD_HEADER_SIZE + size) else {
+			return false;
+		};
+		if crc32fast::hash(payload) != crc {
+			return false;
+		}
+		let Some(record) = decode_payload(payload) else {
+			return false;
+		};
+		records.push(record);
+		data.advance(RECORD_HEADER_SIZE + size);
+	}
+	data.is_empty()
+}
+
+fn read_file(path: &str) -> io::Result<Vec<u8>> {
+	let mut data = Vec::new();
+	match File::open(path) {
+		Ok(mut file) => {
+			file.read_to_end(&mut data)?;
+			Ok(data)
+		}
+		Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(data),
+		Err(e) => Err(e),
+	}
+}
+
+fn remove_file(path: &str) -> io::Result<()> {
+	match std::fs::remove_file(path) {
+		Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+		_ => Ok(()),
+	}
+}
+
+impl StrokeJournal {
+	// Returns None if journaling is disabled
+	pub fn new(path: String, durability: JournalDurability) -> Option<StrokeJournalRef> {
+		if durability == JournalDurability::Off {
+			return None;
+		}
+
+		Some(Arc::new(Self {
+			rotated_path: format!("{path}.old"),
+			path,
+			durability,
+			buffer: SyncMutex::new(Vec::new()),
+			file: SyncMutex::new(None),
+		}))
+	}
+
+	pub fn record_pixels(&self, chunk_pos: IVec2, pixels: &[ChunkPixelRGBA]) {
+		let mut payload = Vec::with_capacity(13 + pixels.len() * 6);
+		payload.put_u8(RECORD_PIXELS);
+		payload.put_i32_le(chunk_pos.x);
+		payload.put_i32_le(chunk_pos.y);
+		payload.put_u32_le(pixels.len() as u32);
+		for pixel in pixels {
+			payload.put_u8(pixel.pos.x);
+			payload.put_u8(pixel.pos.y);
+			payload.put_u8(pixel.color.r);
+			payload.put_u8(pixel.color.g);
+			payload.put_u8(pixel.color.b);
+			payload.put_u8(pixel.color.a);
+		}
+		encode_record(&mut self.buffer.lock(), &payload);
+	}
+
+	pub fn record_chunk_image(&self, chunk_pos: IVec2, compressed: &[u8]) {
+		let mut payload = Vec::with_capacity(9 + compressed.len());
+		payload.put_u8(RECORD_CHUNK_IMAGE);
+		payload.put_i32_le(chunk_pos.x);
+		payload.put_i32_le(chunk_pos.y);
+		payload.put_slice(compressed);
+		encode_record(&mut self.buffer.lock(), &payload);
+	}
+
+	fn flush_locked(&self, file: &mut Option<File>) -> io::Result<()> {
+		let data = std::mem::take(&mut *self.buffer.lock());
+		if data.is_empty() {
+			return Ok(());
+		}
+
+		if file.is_none() {
+			*file = Some(
+				OpenOptions::new()
+					.create(true)
+					.append(true)
+					.open(&self.path)?,
+			);
+		}
+
+		let file = file.as_mut().unwrap();
+		file.write_all(&data)?;
+		if self.durability == JournalDurability::Sync {
+			file.sync_data()?;
+		}
+		Ok(())
+	}
+
+	fn rotate_locked(&self, file: &mut Option<File>) -> io::Result<()> {
+		self.flush_locked(file)?;
+		*file = None;
+
+		if !std::fs::exists(&self.path)? {
+			return Ok(());
+		}
+
+		if std::fs::exists(&self.rotated_path)? {
+			// The previous autosave failed, keep its records too
+			let data = read_file(&self.path)?;
+			let mut rotated = OpenOptions::new().append(true).open(&self.rotated_path)?;
+			rotated.write_all(&data)?;
+			if self.durability == JournalDurability::Sync {
+				rotated.sync_data()?;
+			}
+			std::fs::remove_file(&self.path)
+		} else {
+			std::fs::rename(&self.path, &self.rotated_path)
+		}
+	}
+
+	fn read_locked(&self, file: &mut Option<File>) -> io::Result<Vec<JournalRecord>> {
+		self.flush_locked(file)?;
+
+		let mut records = Vec::new();
+		for path in [&self.rotated_path, &self.path] {
+			let data = read_file(path)?;
+			if !decode_records(&data, &mut records) {
+				log::warn!("Journal {path} ends with a damaged record, ignoring the rest of it");
+			}
+		}
+		Ok(records)
+	}
+
+	fn clear_locked(&self, file: &mut Option<File>) -> io::Result<()> {
+		self.buffer.lock().clear();
+		*file = None;
+		remove_file(&self.rotated_path)?;
+		remove_file(&self.path)
+	}
+
+	// Runs a file operation on the blocking thread pool
+	async fn with_file<F, R>(self: &Arc<Self>, callback: F) -> io::Result<R>
+	where
+		F: FnOnce(&Self, &mut Option<File>) -> io::Result<R> + Send + 'static,
+		R: Send + 'static,
+	{
+		let journal = self.clone();
+		tokio::task::spawn_blocking(move || callback(&journal, &mut journal.file.lock())).await?
+	}
+
+	// Writes buffered records to the file, called every tick
+	pub async fn flush(self: &Arc<Self>) -> io::Result<()> {
+		self.with_file(Self::flush_locked).await
+	}
+
+	// Moves all records written so far aside, called before collecting the chunks to save
+	pub async fn rotate(self: &Arc<Self>) -> io::Result<()> {
+		self.with_file(Self::rotate_locked).await
+	}
+
+	// Removes the rotated records once their chunks are saved
+	pub async fn discard_rotated(self: &Arc<Self>) -> io::Result<()> {
+		let journal = self.clone();
+		self
+			.with_file(move |_, _| remove_file(&journal.rotated_path))
+			.await
+	}
+
+	// All records not saved yet, oldest first
+	pub async fn read(self: &Arc<Self>) -> io::Result<Vec<JournalRecord>> {
+		self.with_file(Self::read_locked).await
+	}
+
+	// Removes all records, after they were replayed and saved
+	pub async fn clear(self: &Arc<Self>) -> io::Result<()> {
+		self.with_file(Self::clear_locked).await
+	}
+}
@@ -2,6 +2,7 @@ pub mod cache;
 #[allow(clippy::module_inception)]
 pub mod chunk;
 pub mod compositor;
+pub mod journal;
 pub mod layer;
 pub mod system;
 pub mod writer;
@@ -1,6 +1,7 @@
 use std::{
 	collections::HashMap,
 	sync::{Arc, Weak},
+	time::Instant,
 };
 
 use parking_lot::Mutex as SyncMutex;
@@ -13,15 +14,20 @@ use tokio::{
 
 use crate::{
 	chunk::chunk::{ChunkInstance, ChunkInstanceMutex, ChunkInstanceRefs, ChunkInstanceWeak},
+	config::SnapshotRetention,
 	database::{Database, DatabaseFunc},
 	event_queue::NotifySender,
 	limits::CHUNK_SIZE_PX,
+	metrics::METRICS,
 	preview_system::{PreviewSystem, PreviewSystemMutex},
 	signal::Signal,
 	time::get_millis,
 };
 
-use super::compositor::{Compositor, LayerID};
+use super::{
+	compositor::{Compositor, LayerID},
+	journal::StrokeJournalRef,
+};
 
 #[derive(Clone)]
 struct ChunkCell {
@@ -47,12 +53,17 @@ pub struct ChunkSystem {
 	cleaned_up: bool,
 	autosave_interval_ms: u32,
 	last_autosave_timestamp: u64,
+	snapshot_retention: Option<SnapshotRetention>,
+	last_compaction_timestamp: u64,
 	task_processor: Option<JoinHandle<()>>,
 	task_tick: Option<JoinHandle<()>>,
+	task_compaction: Option<JoinHandle<()>>,
 	preview_system: PreviewSystemMutex,
 	notifier: Arc<Notify>,
 	signal_garbage_collect: Signal,
 	receiver: broadcast::Receiver<ChunkSystemSignal>,
+	journal: Option<StrokeJournalRef>,
+	journal_needed: bool, // A chunk failed to save, keep the journal until a full save succeeds
 }
 
 const fn modulo(x: i32, n: i32) -> i32 {
@@ -62,6 +73,7 @@ const fn modulo(x: i32, n: i32) -> i32 {
 impl Drop for ChunkSystem {
 	fn drop(&mut self) {
 		assert!(self.cleaned_up, "cleanup() not called");
+		METRICS.chunks.sub(self.chunks.len());
 		log::debug!("Chunk system dropped");
 	}
 }
@@ -78,6 +90,8 @@ impl ChunkSystem {
 		sender: &NotifySender<ChunkSystemSignal>,
 		preview_system: PreviewSystemMutex,
 		autosave_interval_ms: u32,
+		snapshot_retention: Option<SnapshotRetention>,
+		journal: Option<StrokeJournalRef>,
 	) -> Self {
 		Self {
 			chunks: HashMap::new(),
@@ -85,12 +99,17 @@ impl ChunkSystem {
 			cleaned_up: false,
 			autosave_interval_ms,
 			last_autosave_timestamp: get_millis(),
+			snapshot_retention,
+			last_compaction_timestamp: 0, // Compact right after loading
 			preview_system,
 			task_tick: None,
 			task_processor: None,
+			task_compaction: None,
 			notifier: notifier.clone(),
 			signal_garbage_collect: Signal::new(notifier),
 			receiver: sender.subscribe(),
+			journal,
+			journal_needed: false,
 		}
 	}
 
@@ -120,12 +139,10 @@ impl ChunkSystem {
 			return Ok(cell.chunk.clone());
 		}
 
-		let mut compressed_chunk_data: Option<Vec<u8>> = None;
-
 		// Load chunk pixels from the database
-		if let Some(data) = DatabaseFunc::chunk_load_data(&self.database, chunk_pos).await? {
-			compressed_chunk_data = Some(data.data);
-		}
+		let compressed_chunk_data = DatabaseFunc::chunk_load_data(&self.database, chunk_pos)
+			.await?
+			.map(|record| record.data);
 
 		let queue_cache = self.preview_system.lock().await.update_queue_cache.clone();
 
@@ -141,6 +158,7 @@ impl ChunkSystem {
 			queue_cache,
 			self.signal_garbage_collect.clone(),
 			compressed_chunk_data,
+			self.journal.clone(),
 		)));
 
 		self.chunks.insert(
@@ -150,6 +168,7 @@ impl ChunkSystem {
 				refs,
 			},
 		);
+		METRICS.chunks.inc();
 
 		Ok(chunk_mtx)
 	}
@@ -258,21 +277,30 @@ impl ChunkSystem {
 		database: Arc<Mutex<Database>>,
 		data: GarbageCollectData,
 	) {
-		// Save pending chunks
+		// Save pending chunks, the ones which failed stay loaded for the next save to retry
+		let mut unsaved = Vec::new();
 		for chunk in &data.chunks_to_save {
 			if let Some(chunk) = chunk.upgrade() {
 				let chunk = chunk.clone();
 				let mut chunk = chunk.lock().await;
-				Self::save_chunk_wrapper(database.clone(), &mut chunk).await;
+				if !Self::save_chunk_wrapper(database.clone(), &mut chunk).await {
+					unsaved.push(chunk.position);
+				}
 			}
 		}
 
 		let total_loaded = {
 			let mut chunk_system = chunk_system_mtx.lock().await;
+			chunk_system.journal_needed |= !unsaved.is_empty();
 
 			// Free chunks
 			for chunk_pos in &data.chunks_to_free {
-				chunk_system.chunks.remove(chunk_pos);
+				if unsaved.contains(chunk_pos) {
+					continue;
+				}
+				if chunk_system.chunks.remove(chunk_pos).is_some() {
+					METRICS.chunks.dec();
+				}
 			}
 
 			chunk_system.chunks.len()
@@ -316,8 +344,70 @@ impl ChunkSystem {
 		to_autosave
 	}
 
+	// Saves all modified chunks, dropping the journal records they contain if everything got saved.
+	// Returns false if there was nothing to save.
+	async fn save_journaled(chunk_system_mtx: &ChunkSystemMutex) -> bool {
+		let journal = chunk_system_mtx.lock().await.journal.clone();
+
+		// Records written from now on aren't covered by this save
+		if let Some(journal) = &journal {
+			if let Err(e) = journal.rotate().await {
+				log::error!("Failed to rotate stroke journal: {e}");
+			}
+		}
+
+		let (to_save, journal_needed) = {
+			let mut chunk_system = chunk_system_mtx.lock().await;
+			let to_save = chunk_system.get_chunks_to_save();
+			// Chunks the garbage collector failed to save were kept modified, so they are part of this save
+			let journal_needed = if to_save.is_empty() {
+				chunk_system.journal_needed
+			} else {
+				std::mem::take(&mut chunk_system.journal_needed)
+			};
+			(to_save, journal_needed)
+		};
+		if to_save.is_empty() {
+			// Chunks freed by the garbage collector are saved already, the rotated records aren't needed
+			if let Some(journal) = journal.as_ref().filter(|_| !journal_needed) {
+				if let Err(e) = journal.discard_rotated().await {
+					log::error!("Failed to truncate stroke journal: {e}");
+				}
+			}
+			return false;
+		}
+
+		log::info!("Saving {} modified chunks", to_save.len());
+
+		let saved = Self::save_chunks(chunk_system_mtx.clone(), to_save).await;
+
+		// Also set if the garbage collector failed to save a chunk during this save
+		let journal_needed = {
+			let mut chunk_system = chunk_system_mtx.lock().await;
+			chunk_system.journal_needed |= !saved;
+			chunk_system.journal_needed
+		};
+
+		if let Some(journal) = &journal {
+			if journal_needed {
+				log::warn!("Keeping stroke journal, not all chunks were saved");
+			} else if let Err(e) = journal.discard_rotated().await {
+				log::error!("Failed to truncate stroke journal: {e}");
+			}
+		}
+
+		true
+	}
+
 	// Called every 1s
 	async fn tick(chunk_system_mtx: ChunkSystemMutex) {
+		let journal = chunk_system_mtx.lock().await.journal.clone();
+		if let Some(journal) = journal {
+			if let Err(e) = journal.flush().await {
+				log::error!("Failed to write stroke journal: {e}");
+			}
+		}
+
 		let mut chunk_system = chunk_system_mtx.lock().await;
 		let preview_system = chunk_system.preview_system.clone();
 
@@ -325,16 +415,64 @@ impl ChunkSystem {
 		if chunk_system.last_autosave_timestamp + u64::from(chunk_system.autosave_interval_ms) < time_ms
 		{
 			chunk_system.last_autosave_timestamp = time_ms;
+			drop(chunk_system);
 
-			let to_autosave = chunk_system.get_chunks_to_save();
-			if !to_autosave.is_empty() {
-				log::info!("Performing auto-save");
-				drop(chunk_system);
-				Self::save_chunks(chunk_system_mtx, to_autosave).await;
+			let started_at = Instant::now();
+			if Self::save_journaled(&chunk_system_mtx).await {
+				METRICS.autosave.observe(started_at.elapsed());
 				log::info!("Auto-save finished");
 
 				PreviewSystem::process_all(preview_system).await;
 			}
+			return;
+		}
+
+		if let Some(retention) = chunk_system.snapshot_retention.clone() {
+			let compaction_running = chunk_system
+				.task_compaction
+				.as_ref()
+				.is_some_and(|task| !task.is_finished());
+
+			if !compaction_running
+				&& chunk_system
+					.last_compaction_timestamp
+					.saturating_add(retention.compaction_interval_s.saturating_mul(1000))
+					< time_ms
+			{
+				chunk_system.last_compaction_timestamp = time_ms;
+				let database = chunk_system.database.clone();
+				chunk_system.task_compaction = Some(
+					tokio::task::Builder::new()
+						.name("Chunk system compaction task")
+						.spawn(async move {
+							Self::compact(database, retention).await;
+						})
+						.unwrap(),
+				);
+			}
+		}
+	}
+
+	// Prune chunk snapshots according to the retention policy and shrink the database file
+	async fn compact(database: Arc<Mutex<Database>>, retention: SnapshotRetention) {
+		let deleted = match DatabaseFunc::chunk_prune_snapshots(&database, retention).await {
+			Ok(deleted) => deleted,
+			Err(e) => {
+				log::error!("Failed to prune chunk snapshots: {e}");
+				return;
+			}
+		};
+
+		if deleted == 0 {
+			return;
+		}
+
+		match DatabaseFunc::vacuum(&database).await {
+			Ok(reclaimed) => log::info!(
+				"Pruned {deleted} chunk snapshots, reclaimed {} KiB",
+				reclaimed / 1024
+			),
+			Err(e) => log::error!("Failed to vacuum database: {e}"),
 		}
 	}
 
@@ -364,7 +502,7 @@ impl ChunkSystem {
 		PreviewSystem::process_all(preview_system_mtx).await;
 	}
 
-	async fn save_chunk_wrapper(database: Arc<Mutex<Database>>, chunk: &mut ChunkInstance) {
+	async fn save_chunk_wrapper(database: Arc<Mutex<Database>>, chunk: &mut ChunkInstance) -> bool {
 		log::trace!("Saving chunk at {}x{}", chunk.position.x, chunk.position.y);
 		if let Err(e) = Self::save_chunk(database, chunk).await {
 			log::error!(
@@ -373,7 +511,10 @@ impl ChunkSystem {
 				chunk.position.y,
 				e
 			);
+			chunk.mark_unsaved();
+			return false;
 		}
+		true
 	}
 
 	async fn save_chunk(
@@ -393,7 +534,12 @@ impl ChunkSystem {
 		Ok(())
 	}
 
-	async fn save_chunks(chunk_system_mtx: ChunkSystemMutex, mut to_autosave: Vec<ChunkCellWeak>) {
+	// Returns false if any of the chunks failed to save
+	async fn save_chunks(
+		chunk_system_mtx: ChunkSystemMutex,
+		mut to_autosave: Vec<ChunkCellWeak>,
+	) -> bool {
+		let mut saved = true;
 		while let Some(cell) = to_autosave.pop() {
 			if let Some(chunk) = cell.chunk.upgrade() {
 				let mut chunk = chunk.lock().await;
@@ -405,9 +551,48 @@ impl ChunkSystem {
 				let database = chunk_system.database.clone();
 				drop(chunk_system);
 
-				Self::save_chunk_wrapper(database.clone(), &mut chunk).await;
+				saved &= Self::save_chunk_wrapper(database.clone(), &mut chunk).await;
 			}
 		}
+		saved
+	}
+
+	pub async fn save_all(chunk_system_mtx: ChunkSystemMutex) {
+		let to_save = chunk_system_mtx.lock().await.get_chunks_to_save();
+		Self::save_chunks(chunk_system_mtx, to_save).await;
+	}
+
+	// Re-applies strokes drawn since the last autosave before the server stopped unexpectedly
+	pub async fn replay_journal(chunk_system_mtx: ChunkSystemMutex) -> anyhow::Result<()> {
+		let Some(journal) = chunk_system_mtx.lock().await.journal.clone() else {
+			return Ok(());
+		};
+
+		let records = journal.read().await?;
+		if records.is_empty() {
+			return Ok(());
+		}
+
+		log::info!("Replaying {} stroke journal records", records.len());
+		for record in &records {
+			let chunk_mtx = chunk_system_mtx
+				.lock()
+				.await
+				.get_chunk(record.chunk_pos())
+				.await?;
+			chunk_mtx.lock().await.replay_journal_record(record);
+		}
+
+		let to_save = chunk_system_mtx.lock().await.get_chunks_to_save();
+		if !Self::save_chunks(chunk_system_mtx.clone(), to_save).await {
+			anyhow::bail!("Failed to save replayed chunks");
+		}
+
+		journal.clear().await?;
+
+		let preview_system = chunk_system_mtx.lock().await.preview_system.clone();
+		PreviewSystem::process_all(preview_system).await;
+		Ok(())
 	}
 
 	pub async fn cleanup(chunk_system_mtx: ChunkSystemMutex) {
@@ -417,12 +602,13 @@ impl ChunkSystem {
 			task.abort();
 		}
 
+		if let Some(task) = &chunk_system.task_compaction {
+			task.abort();
+		}
+
 		// Save all modified chunks
-		let to_autosave = chunk_system.get_chunks_to_save();
 		drop(chunk_system);
-		if !to_autosave.is_empty() {
-			log::info!("Saving chunks before exit");
-			Self::save_chunks(chunk_system_mtx.clone(), to_autosave).await;
+		if Self::save_journaled(&chunk_system_mtx).await {
 			log::info!("Chunks saved!");
 		}
 
@@ -432,3 +618,73 @@ impl ChunkSystem {
 
 pub type ChunkSystemMutex = Arc<Mutex<ChunkSystem>>;
 pub type ChunkSystemWeak = Weak<Mutex<ChunkSystem>>;
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{chunk::chunk::ChunkPixelRGBA, pixel::ColorRGBA, storage::StorageBackend};
+
+	fn new_chunk_system(database: &Arc<Mutex<Database>>) -> ChunkSystemMutex {
+		let notifier = Arc::new(Notify::new());
+		let sender = NotifySender::<ChunkSystemSignal>::new(notifier.clone(), 16);
+		Arc::new(Mutex::new(ChunkSystem::new(
+			database.clone(),
+			notifier,
+			&sender,
+			Arc::new(Mutex::new(PreviewSystem::new(database.clone()))),
+			1000,
+			None,
+			None,
+		)))
+	}
+
+	#[tokio::test]
+	async fn saved_chunk_loads_back() {
+		let database = Arc::new(Mutex::new(
+			Database::open(String::from(":memory:"), StorageBackend::Memory)
+				.await
+				.unwrap(),
+		));
+		let chunk_pos = IVec2::new(3, -2);
+		let pixel = ChunkPixelRGBA {
+			pos: U8Vec2::new(10, 20),
+			color: ColorRGBA::new(255, 0, 0, 255),
+		};
+
+		let chunk_system_mtx = new_chunk_system(&database);
+		{
+			let chunk_mtx = chunk_system_mtx
+				.lock()
+				.await
+				.get_chunk(chunk_pos)
+				.await
+				.unwrap();
+			let mut chunk = chunk_mtx.lock().await;
+			chunk.allocate_image();
+			chunk.set_pixels(std::slice::from_ref(&pixel), false);
+		}
+		ChunkSystem::cleanup(chunk_system_mtx).await; // Saves modified chunks
+
+		let stats = DatabaseFunc::chunk_list_stats(&database).await.unwrap();
+		assert_eq!(stats.len(), 1);
+		assert_eq!(stats[0].pos, chunk_pos);
+
+		// A new chunk system has nothing cached, the chunk comes from the storage
+		let chunk_system_mtx = new_chunk_system(&database);
+		{
+			let chunk_mtx = chunk_system_mtx
+				.lock()
+				.await
+				.get_chunk(chunk_pos)
+				.await
+				.unwrap();
+			let mut chunk = chunk_mtx.lock().await;
+			chunk.allocate_image();
+			assert!(chunk.get_pixel_main(pixel.pos) == pixel.color);
+			assert!(chunk.get_pixel_main(U8Vec2::new(11, 20)) != pixel.color);
+		}
+		ChunkSystem::cleanup(chunk_system_mtx).await;
+
+		database.lock().await.cleanup().await;
+	}
+}
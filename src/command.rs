@@ -1,9 +1,17 @@
 use std::collections::VecDeque;
 
+use glam::IVec2;
 use tokio::io::AsyncReadExt;
 use tokio_util::sync::CancellationToken;
 
-use crate::server::ServerMutex;
+use crate::{
+	account,
+	ban_system::{BanSystem, BanTarget},
+	export::{self, Region},
+	server::{Server, ServerMutex},
+	session::SessionInstance,
+	util,
+};
 
 #[cfg(feature = "dump")]
 use {std::time::Duration, tokio::runtime::Handle, tokio::time::timeout};
@@ -24,6 +32,154 @@ fn print_help() {
 	log::info!("help - Print this help");
 	log::info!("dump - Show stacktrace of all async tasks (dangerous!)");
 	log::info!("exit - Save everything and exit");
+	log::info!("export <room> <x1> <y1> <x2> <y2> - Export canvas region to PNG");
+	log::info!("account list - List registered accounts");
+	log::info!("account add <name> <password> - Register an account");
+	log::info!("account remove <name> - Remove an account");
+	log::info!("account admin <name> <on|off> - Grant or revoke admin rights of an account");
+	log::info!("account hash <password> - Hash a password for admin_password in settings.json");
+	log::info!("kick <nick> [reason] - Disconnect the user from any room");
+	log::info!("ban <nick|account|ip> [duration] - Ban the user, permanently if no duration (e.g. 30m, 7d) is given");
+	log::info!("unban <account|ip> - Remove the ban");
+	log::info!("bans - List active bans");
+	log::info!("mute|unmute <nick> - Prevent the user from sending chat messages");
+}
+
+async fn command_moderation(
+	command: &str,
+	parts: &VecDeque<&str>,
+	server: &ServerMutex,
+) -> anyhow::Result<()> {
+	let args: Vec<&str> = parts.iter().map(|part| part.trim()).collect();
+	let (bans, sessions) = {
+		let server = server.lock().await;
+		(server.bans.clone(), server.get_all_room_sessions().await)
+	};
+
+	if command == "bans" {
+		for ban in bans.lock().await.list() {
+			log::info!("{ban}");
+		}
+		return Ok(());
+	}
+
+	let Some((target, args)) = args.split_first() else {
+		anyhow::bail!("Usage: {command} <nick>");
+	};
+
+	let matching: Vec<_> = sessions
+		.iter()
+		.filter(|session| session.state.lock().nick_name == *target)
+		.cloned()
+		.collect();
+
+	match command {
+		"ban" => {
+			let duration_s = match args.first() {
+				Some(duration) => {
+					Some(util::parse_duration(duration).ok_or_else(|| anyhow::anyhow!("Invalid duration"))?)
+				}
+				None => None,
+			};
+			for target in BanSystem::ban_user(&bans, &matching, &sessions, target, duration_s).await? {
+				log::info!("Banned {target}");
+			}
+		}
+		"unban" => {
+			let target = BanTarget::parse(target);
+			if BanSystem::remove(&bans, &target).await? {
+				log::info!("Unbanned {target}");
+			} else {
+				log::error!("{target} is not banned");
+			}
+		}
+		"kick" => {
+			let reason = args.join(" ");
+			for session in &matching {
+				SessionInstance::disconnect(
+					session.instance_mtx.clone(),
+					if reason.is_empty() {
+						String::from("Kicked by the server")
+					} else {
+						format!("Kicked by the server: {reason}")
+					},
+				);
+			}
+			log::info!("Kicked {} sessions", matching.len());
+		}
+		_ => {
+			for session in &matching {
+				session.state.lock().muted = command == "mute";
+			}
+			log::info!("{command}: {} sessions", matching.len());
+		}
+	}
+
+	Ok(())
+}
+
+async fn command_account(parts: &VecDeque<&str>, server: &ServerMutex) -> anyhow::Result<()> {
+	let accounts = server.lock().await.accounts.clone();
+	let args: Vec<&str> = parts.iter().map(|part| part.trim()).collect();
+
+	match args.as_slice() {
+		["list"] => {
+			let list = accounts.list().await?;
+			for account in list {
+				log::info!(
+					"{} (created at {}){}",
+					account.name,
+					account.created_at,
+					if account.admin { ", admin" } else { "" }
+				);
+			}
+		}
+		["add", name, password] => {
+			accounts.register(name, String::from(*password)).await?;
+		}
+		["remove", name] => {
+			if accounts.remove(name).await? {
+				log::info!("Account {name} removed");
+			} else {
+				log::error!("Account {name} does not exist");
+			}
+		}
+		["admin", name, state @ ("on" | "off")] => {
+			if accounts.set_admin(name, *state == "on").await? {
+				log::info!("Account {name} admin rights: {state}");
+			} else {
+				log::error!("Account {name} does not exist");
+			}
+		}
+		["hash", password] => {
+			let password = String::from(*password);
+			log::info!(
+				"{}",
+				tokio::task::spawn_blocking(move || account::hash_password(&password)).await??
+			);
+		}
+		_ => log::error!("Usage: account <list|add|remove|admin|hash> [args]"),
+	}
+
+	Ok(())
+}
+
+async fn command_export(parts: &VecDeque<&str>, server: &ServerMutex) {
+	let coords: Vec<i32> = parts
+		.iter()
+		.skip(1)
+		.filter_map(|part| part.trim().parse().ok())
+		.collect();
+
+	let (Some(room_name), [x1, y1, x2, y2]) = (parts.front(), coords.as_slice()) else {
+		log::error!("Usage: export <room> <x1> <y1> <x2> <y2>");
+		return;
+	};
+
+	let region = Region::from_corners(IVec2::new(*x1, *y1), IVec2::new(*x2, *y2));
+	if let Err(e) = export::export_region_png(server, room_name.trim(), region).await {
+		log::error!("Export failed: {e}");
+	}
 }
 
 async fn process_command(line: String, server: &ServerMutex) -> anyhow::Result<()> {
@@ -40,8 +196,19 @@ async fn process_command(line: String, server: &ServerMutex) -> anyhow::Result<(
 				#[cfg(not(feature = "dump"))]
 				log::error!("Feature \"dump\" not enabled");
 			}
+			"export" => command_export(&parts, server).await,
+			"kick" | "ban" | "unban" | "bans" | "mute" | "unmute" => {
+				if let Err(e) = command_moderation(keyword, &parts, server).await {
+					log::error!("{keyword} failed: {e}");
+				}
+			}
+			"account" => {
+				if let Err(e) = command_account(&parts, server).await {
+					log::error!("Account command failed: {e}");
+				}
+			}
 			"exit" => {
-				if let Err(e) = server.lock().await.save_and_exit().await {
+				if let Err(e) = Server::save_and_exit(server).await {
 					log::error!("Cannot exit gracefully: {e}.");
 				}
 			}
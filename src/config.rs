@@ -1,5 +1,9 @@
 #![allow(dead_code)]
 
+use std::net::IpAddr;
+
+use crate::{role_system::Role, storage::StorageBackend};
+
 const SETTINGS_PATH: &str = "settings.json";
 
 #[derive(serde::Deserialize)]
@@ -7,15 +11,123 @@ pub struct PreviewSystem {
 	process_all_at_start: bool,
 }
 
+#[derive(serde::Deserialize, Clone)]
+pub struct SnapshotRetentionTier {
+	pub max_age_s: Option<u64>, // Applies to snapshots younger than this, forever if not set
+	// Keep one snapshot per this period. Snapshots are started at most every 4 hours, so shorter periods keep all of them.
+	pub interval_s: u64,
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct SnapshotRetention {
+	pub tiers: Vec<SnapshotRetentionTier>, // Ordered from the youngest to the oldest
+	pub compaction_interval_s: u64,
+}
+
+#[derive(serde::Deserialize, Clone)]
+#[serde(default)]
+pub struct RateLimit {
+	pub packets_per_s: u32,
+	pub packets_burst: u32,
+	pub pixels_per_s: u32, // Pixels written by drawing tools
+	pub pixels_burst: u32,
+	pub messages_per_min: u32, // Chat messages and commands
+	pub messages_burst: u32,
+	pub mute_after_violations: u32, // Temporarily disallow drawing and chatting
+	pub mute_duration_s: u64,
+	pub kick_after_violations: u32,
+	pub violation_reset_s: u64, // Forget violations after this period without new ones
+}
+
+impl Default for RateLimit {
+	fn default() -> Self {
+		Self {
+			packets_per_s: 200,
+			packets_burst: 400,
+			pixels_per_s: 250_000,
+			pixels_burst: 500_000,
+			messages_per_min: 30,
+			messages_burst: 5,
+			mute_after_violations: 3,
+			mute_duration_s: 30,
+			kick_after_violations: 6,
+			violation_reset_s: 60,
+		}
+	}
+}
+
+#[derive(serde::Deserialize, Clone)]
+#[serde(default)]
+pub struct ConnectionLimits {
+	pub max_sessions: u32, // Session IDs are sent as u16, keep it below 65536
+	pub max_connections_per_ip: u32,
+	pub handshake_timeout_s: u64,
+	pub announce_timeout_s: u64, // Disconnect sessions which don't join a room in time
+	pub trusted_proxies: Vec<IpAddr>, // X-Forwarded-For is honored only for connections from these addresses
+}
+
+impl Default for ConnectionLimits {
+	fn default() -> Self {
+		Self {
+			max_sessions: 4096,
+			max_connections_per_ip: 8,
+			handshake_timeout_s: 10,
+			announce_timeout_s: 15,
+			trusted_proxies: Vec::new(),
+		}
+	}
+}
+
+#[derive(serde::Deserialize, Clone)]
+#[serde(default)]
+pub struct Shutdown {
+	pub message: String,      // Shown to connected users when the server stops
+	pub drain_timeout_s: u64, // Time given to deliver the message, clients which don't receive it by then are dropped
+}
+
+impl Default for Shutdown {
+	fn default() -> Self {
+		Self {
+			message: String::from("Server is shutting down"),
+			drain_timeout_s: 5,
+		}
+	}
+}
+
+// How strokes drawn since the last autosave are written to the room journal
+#[derive(serde::Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum JournalDurability {
+	Off, // No journal, strokes since the last autosave are lost if the server crashes
+	#[default]
+	Flush, // Written every second, survives a crash of the server process
+	Sync, // Written and synced to disk every second, also survives a power loss
+}
+
 #[derive(serde::Deserialize)]
 pub struct Config {
 	pub listen_ip: String,
 	pub listen_port: u16,
+	pub tls_cert_path: Option<String>, // PEM certificate chain, serves wss:// if set along with the key
+	pub tls_key_path: Option<String>,  // PEM private key, reloaded with the certificate on SIGHUP
+	pub metrics_listen: Option<String>, // Address of the Prometheus metrics endpoint (e.g. "127.0.0.1:9100"), disabled if not set
+	pub web_root: Option<String>, // Static files of the web client served over HTTP, "web/dist" if not set
 	pub autosave_interval_ms: u32,
+	pub journal_durability: Option<JournalDurability>, // Flush if not set
+	pub storage_backend: Option<StorageBackend>, // Where chunks of new rooms are stored, sqlite if not set
 	pub plugin_list: Vec<String>,
 	pub preview_system: PreviewSystem,
 	pub admin_password: Option<String>,
 	pub enable_console: Option<bool>,
+	pub history_max_bytes: Option<usize>, // Per-user undo history size limit
+	pub snapshot_retention: Option<SnapshotRetention>, // Keep all chunk snapshots if not set
+	pub guest_role: Option<Role>, // Default role of guests in rooms without an override, artist if not set
+	pub account_role: Option<Role>, // Default role of logged-in users in rooms without an override, artist if not set
+	pub spectator_rooms: Option<Vec<String>>, // Rooms in which guests always join as spectators
+	pub hide_spectators: Option<bool>, // Don't announce spectators to other users, false if not set
+	pub rate_limit: Option<RateLimit>, // Default limits if not set, moderators and owners are exempt
+	pub connection_limits: Option<ConnectionLimits>, // Default limits if not set
+	pub shutdown: Option<Shutdown>, // Used on SIGTERM, SIGINT and the exit console command
 }
 
 pub async fn load() -> anyhow::Result<Config> {
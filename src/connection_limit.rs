@@ -0,0 +1,203 @@
+use std::{collections::HashMap, fmt, net::IpAddr, sync::Arc};
+
+use parking_lot::Mutex as SyncMutex;
+
+use crate::config::ConnectionLimits;
+
+pub enum Rejection {
+	TooManyConnections, // Global session cap reached
+	TooManyConnectionsFromIp,
+}
+
+impl fmt::Display for Rejection {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Self::TooManyConnections => write!(f, "Server is full"),
+			Self::TooManyConnectionsFromIp => write!(f, "Too many connections from your IP address"),
+		}
+	}
+}
+
+#[derive(Default)]
+struct Counters {
+	total: u32,
+	per_ip: HashMap<IpAddr, u32>,
+}
+
+// Counts open connections, globally and per IP address
+pub struct ConnectionLimiter {
+	limits: ConnectionLimits,
+	counters: SyncMutex<Counters>,
+}
+
+// Held for the whole lifetime of a connection, releases its slot when dropped
+pub struct ConnectionPermit {
+	limiter: Arc<ConnectionLimiter>,
+	ip: IpAddr,
+}
+
+impl ConnectionLimiter {
+	pub fn new(limits: ConnectionLimits) -> Arc<Self> {
+		Arc::new(Self {
+			limits,
+			counters: SyncMutex::new(Counters::default()),
+		})
+	}
+
+	pub const fn limits(&self) -> &ConnectionLimits {
+		&self.limits
+	}
+
+	pub fn is_trusted_proxy(&self, ip: IpAddr) -> bool {
+		self.limits.trusted_proxies.contains(&ip)
+	}
+
+	// Returns the address of the client, taken from X-Forwarded-For if the peer is a trusted proxy.
+	// Proxies append the address they received the request from, so the header is read from the right
+	// and the first address which isn't a trusted proxy is the client.
+	pub fn resolve_client_ip(&self, peer_ip: IpAddr, forwarded_for: Option<&str>) -> IpAddr {
+		if !self.is_trusted_proxy(peer_ip) {
+			return peer_ip;
+		}
+
+		let Some(forwarded_for) = forwarded_for else {
+			return peer_ip;
+		};
+
+		let mut client_ip = peer_ip;
+		for entry in forwarded_for.rsplit(',') {
+			let Ok(ip) = entry.trim().parse::<IpAddr>() else {
+				break; // Malformed, don't trust anything before it
+			};
+			client_ip = ip;
+			if !self.is_trusted_proxy(ip) {
+				break;
+			}
+		}
+		client_ip
+	}
+
+	pub fn acquire(self: &Arc<Self>, ip: IpAddr) -> Result<ConnectionPermit, Rejection> {
+		let mut counters = self.counters.lock();
+
+		if counters.total >= self.limits.max_sessions {
+			return Err(Rejection::TooManyConnections);
+		}
+
+		// Trusted proxies carry connections of many clients
+		let count = counters.per_ip.entry(ip).or_default();
+		if *count >= self.limits.max_connections_per_ip && !self.is_trusted_proxy(ip) {
+			return Err(Rejection::TooManyConnectionsFromIp);
+		}
+
+		*count += 1;
+		counters.total += 1;
+
+		Ok(ConnectionPermit {
+			limiter: self.clone(),
+			ip,
+		})
+	}
+
+	fn release(&self, ip: IpAddr) {
+		let mut counters = self.counters.lock();
+		counters.total = counters.total.saturating_sub(1);
+		if let Some(count) = counters.per_ip.get_mut(&ip) {
+			*count -= 1;
+			if *count == 0 {
+				counters.per_ip.remove(&ip);
+			}
+		}
+	}
+}
+
+impl Drop for ConnectionPermit {
+	fn drop(&mut self) {
+		self.limiter.release(self.ip);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn ip(text: &str) -> IpAddr {
+		text.parse().unwrap()
+	}
+
+	fn limiter(max_sessions: u32, max_connections_per_ip: u32) -> Arc<ConnectionLimiter> {
+		ConnectionLimiter::new(ConnectionLimits {
+			max_sessions,
+			max_connections_per_ip,
+			trusted_proxies: vec![ip("10.0.0.1"), ip("10.0.0.2")],
+			..Default::default()
+		})
+	}
+
+	#[test]
+	fn per_ip_and_total_limits() {
+		let limiter = limiter(3, 2);
+		let first = limiter.acquire(ip("1.1.1.1")).ok().unwrap();
+		let _second = limiter.acquire(ip("1.1.1.1")).ok().unwrap();
+		assert!(matches!(
+			limiter.acquire(ip("1.1.1.1")),
+			Err(Rejection::TooManyConnectionsFromIp)
+		));
+
+		// Dropping a permit frees its slot
+		drop(first);
+		let _third = limiter.acquire(ip("1.1.1.1")).ok().unwrap();
+
+		let _other = limiter.acquire(ip("2.2.2.2")).ok().unwrap();
+		assert!(matches!(
+			limiter.acquire(ip("3.3.3.3")),
+			Err(Rejection::TooManyConnections)
+		));
+	}
+
+	#[test]
+	fn trusted_proxies_skip_per_ip_limit() {
+		let limiter = limiter(10, 1);
+		let _permits: Vec<_> = (0..3)
+			.map(|_| limiter.acquire(ip("10.0.0.1")).ok().unwrap())
+			.collect();
+	}
+
+	#[test]
+	fn forwarded_for_is_ignored_from_untrusted_peers() {
+		let limiter = limiter(10, 10);
+		assert_eq!(
+			limiter.resolve_client_ip(ip("1.1.1.1"), Some("2.2.2.2")),
+			ip("1.1.1.1")
+		);
+		assert_eq!(
+			limiter.resolve_client_ip(ip("10.0.0.1"), None),
+			ip("10.0.0.1")
+		);
+	}
+
+	#[test]
+	fn forwarded_for_is_read_from_the_right() {
+		let limiter = limiter(10, 10);
+
+		// A client can prepend a fake address, the trusted proxy appends the real one
+		assert_eq!(
+			limiter.resolve_client_ip(ip("10.0.0.1"), Some("6.6.6.6, 2.2.2.2")),
+			ip("2.2.2.2")
+		);
+		// Chained trusted proxies are skipped
+		assert_eq!(
+			limiter.resolve_client_ip(ip("10.0.0.1"), Some("2.2.2.2,10.0.0.2")),
+			ip("2.2.2.2")
+		);
+		// Nothing before a malformed entry is trusted
+		assert_eq!(
+			limiter.resolve_client_ip(ip("10.0.0.1"), Some("2.2.2.2, garbage, 10.0.0.2")),
+			ip("10.0.0.2")
+		);
+		assert_eq!(
+			limiter.resolve_client_ip(ip("10.0.0.1"), Some("::1")),
+			ip("::1")
+		);
+	}
+}
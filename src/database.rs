@@ -1,16 +1,22 @@
-use glam::{IVec2, U8Vec2};
+use glam::{I64Vec2, IVec2};
 use num_enum::TryFromPrimitive;
-use rusqlite::params;
-use std::sync::Arc;
-use tokio::sync::Mutex;
-
-use crate::chunk::layer::LayerRGBA;
-use crate::compression::decompress_lz4;
-use crate::pixel::ColorRGBA;
-
-const SECONDS_BETWEEN_SNAPSHOTS: u32 = 14400;
-
-fn get_unix_timestamp() -> u64 {
+use rusqlite::{params, OpenFlags, OptionalExtension};
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex as StdMutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use tokio::sync::{oneshot, Mutex};
+
+use crate::config::SnapshotRetention;
+use crate::metrics::METRICS;
+use crate::migration::{self, MigrationProgress, DATABASE_VERSION};
+use crate::region_system::ProtectedRegion;
+use crate::storage::{self, ChunkStorage, StorageBackend};
+
+// A room database can be read by HTTP requests while the room writes to it, wait for the lock instead of failing with SQLITE_BUSY
+pub const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub fn get_unix_timestamp() -> u64 {
 	std::time::SystemTime::now()
 		.duration_since(std::time::SystemTime::UNIX_EPOCH)
 		.map_or(0, |n| n.as_secs())
@@ -22,10 +28,80 @@ pub enum CompressionType {
 	Lz4 = 1,
 }
 
+// Owned by the database thread
+struct DatabaseState {
+	conn: rusqlite::Connection,
+	storage: Box<dyn ChunkStorage>,
+}
+
+type DatabaseJob = Box<dyn FnOnce(&mut DatabaseState) + Send>;
+
+#[derive(Clone, Copy)]
+enum Priority {
+	Interactive, // Chunk loads and saves, metadata
+	Background,  // Preview generation, snapshot pruning and vacuum
+}
+
+#[derive(Default)]
+struct JobQueueState {
+	interactive: VecDeque<DatabaseJob>,
+	background: VecDeque<DatabaseJob>,
+	closed: bool,
+}
+
+// Background jobs run only while no interactive job is waiting, so a backlog of previews doesn't delay chunk loads.
+// A job which already runs (e.g. a vacuum) is not interrupted.
+#[derive(Default)]
+struct JobQueue {
+	state: StdMutex<JobQueueState>,
+	available: Condvar,
+}
+
+impl JobQueue {
+	fn push(&self, priority: Priority, job: DatabaseJob) -> bool {
+		let mut state = self.state.lock().unwrap();
+		if state.closed {
+			return false;
+		}
+		match priority {
+			Priority::Interactive => state.interactive.push_back(job),
+			Priority::Background => state.background.push_back(job),
+		}
+		self.available.notify_one();
+		true
+	}
+
+	// Blocks until a job is available, None once the queue is closed and empty
+	fn pop(&self) -> Option<DatabaseJob> {
+		let mut state = self.state.lock().unwrap();
+		loop {
+			if let Some(job) = state
+				.interactive
+				.pop_front()
+				.or_else(|| state.background.pop_front())
+			{
+				return Some(job);
+			}
+			if state.closed {
+				return None;
+			}
+			state = self.available.wait(state).unwrap();
+		}
+	}
+
+	fn close(&self) {
+		self.state.lock().unwrap().closed = true;
+		self.available.notify_one();
+	}
+}
+
+// Queries run one by one on a dedicated thread owning the connection, so they never block the async runtime
 pub struct Database {
-	pub conn: rusqlite::Connection,
+	jobs: Option<Arc<JobQueue>>,
+	thread: Option<JoinHandle<()>>,
 	cleaned_up: bool,
 	pub migrated_from_version: u32,
+	pub storage_backend: StorageBackend,
 }
 
 #[allow(dead_code)]
@@ -36,154 +112,130 @@ pub struct ChunkDatabaseRecord {
 	pub data: Vec<u8>,
 }
 
-pub struct PreviewDatabaseRecord {
-	pub data: Vec<u8>,
+pub struct ChunkStats {
+	pub pos: IVec2,
+	pub snapshot_count: u32,
+	pub modified_at: u64, // unix timestamp of the newest snapshot
 }
 
-const DATABASE_VERSION: u32 = 1;
-
-fn get_version(conn: &rusqlite::Connection) -> rusqlite::Result<u32> {
-	let mut stmt = conn.prepare("PRAGMA user_version")?;
-	let mut res = stmt.query([])?;
-
-	let Some(row) = res.next()? else {
-		return Ok(0);
-	};
-
-	let val: u32 = row.get(0)?;
-
-	Ok(val)
+pub struct PreviewDatabaseRecord {
+	pub data: Vec<u8>,
 }
 
-fn set_version(conn: &rusqlite::Connection, version: u32) -> rusqlite::Result<()> {
-	conn.execute(&format!("PRAGMA user_version = {version}"), [])?;
-	Ok(())
+pub struct HistoryDatabaseRecord {
+	pub redo: bool,
+	pub raw_size: u32,
+	pub data: Vec<u8>, // LZ4-compressed
 }
 
-fn migrate_to_version_1(conn: &rusqlite::Connection) -> rusqlite::Result<()> {
-	struct ChunkDataRow {
-		x: i32,
-		y: i32,
-		data: Vec<u8>,
-		modified: i64,
-		created: i64,
-		compression: i64,
-	}
-
-	log::info!("Migrating to version 1");
-
-	log::info!("removing previews");
-	conn.execute("DELETE FROM previews", [])?;
+const PROPERTY_STORAGE_BACKEND: &str = "storage_backend";
 
-	log::info!("loading all compressed rgb chunks into memory");
+impl Database {
+	// `new_room_backend` is used only if the database doesn't exist yet, existing rooms keep their storage backend
+	pub fn new(path: &str, new_room_backend: StorageBackend) -> anyhow::Result<Self> {
+		log::trace!("Opening database at path {path}");
+		let db_exists = std::fs::exists(path).unwrap_or(false);
+		let newly_created = !db_exists;
+		let mut conn = rusqlite::Connection::open(path)?;
+		conn.busy_timeout(BUSY_TIMEOUT)?;
 
-	let old_data: Vec<_> = {
-		let mut stmt =
-			conn.prepare("SELECT x, y, data, modified, created, compression FROM chunk_data")?;
-		let res: Vec<_> = stmt
-			.query_map([], |row| {
-				Ok(ChunkDataRow {
-					x: row.get(0)?,
-					y: row.get(1)?,
-					data: row.get(2)?,
-					modified: row.get(3)?,
-					created: row.get(4)?,
-					compression: row.get(5)?,
-				})
-			})?
-			.flatten()
-			.collect();
-		res
-	};
-
-	log::info!("loaded {} chunks", old_data.len());
-	log::info!("converting chunks from rgb to rgba");
-
-	let old_data_len = old_data.len();
-
-	let mut i = 0;
-	for old_cell in old_data {
-		i += 1;
-		log::info!(
-			"updating chunk at {}x{} {}%",
-			old_cell.x,
-			old_cell.y,
-			((i as f32 / old_data_len as f32) * 100.0).round()
-		);
+		Self::run_empty_query(&conn, "PRAGMA SYNCHRONOUS=OFF")?;
 
-		let Some(rgb) = decompress_lz4(&old_cell.data, 256 * 256 * 3) else {
-			log::error!("failed to decompress");
-			continue;
+		let migrated_from_version = if newly_created {
+			migration::initialize(&mut conn)?;
+			0
+		} else {
+			let mut output = |message: String| log::info!("{message}");
+			migration::migrate(
+				&mut conn,
+				path,
+				false,
+				&mut MigrationProgress::new(&mut output),
+			)?
+			.from_version
 		};
 
-		let mut layer = LayerRGBA::new();
-		layer.alloc_transparent_black();
-
-		for y in 0..256 {
-			for x in 0..256 {
-				let offset = y * 256 * 3 + x * 3;
-
-				let red = rgb[offset];
-				let green = rgb[offset + 1];
-				let blue = rgb[offset + 2];
-
-				layer.set_pixel(
-					U8Vec2::new(x as u8, y as u8),
-					ColorRGBA {
-						r: red,
-						g: green,
-						b: blue,
-						a: 255,
-					},
-				);
-			}
-		}
-
-		let compressed = layer.compress_lz4();
-
-		conn.execute(
-			"UPDATE chunk_data SET data=? WHERE x=? AND y=? AND modified=? AND created=? AND compression=?",
-			params![compressed, old_cell.x, old_cell.y, old_cell.modified, old_cell.created, old_cell.compression],
-		)?;
+		let storage_backend =
+			if let Some(backend) = Self::property_load(&conn, PROPERTY_STORAGE_BACKEND)? {
+				backend.parse()?
+			} else {
+				// Rooms created before storage backends were introduced keep their chunks in the database
+				let backend = if newly_created {
+					new_room_backend
+				} else {
+					StorageBackend::Sqlite
+				};
+				Self::property_save(&conn, PROPERTY_STORAGE_BACKEND, backend.as_str())?;
+				backend
+			};
+		let storage = storage::open(storage_backend, path, false)?;
+
+		Self::spawn(conn, storage, path, migrated_from_version, storage_backend)
 	}
 
-	Ok(())
-}
+	// Opens an existing database without modifying it, for readers which don't load the room.
+	// Outdated databases are refused, they are migrated once the room is loaded.
+	pub fn new_read_only(path: &str) -> anyhow::Result<Self> {
+		log::trace!("Opening database at path {path} (read-only)");
+		let conn = rusqlite::Connection::open_with_flags(
+			path,
+			OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+		)?;
+		conn.busy_timeout(BUSY_TIMEOUT)?;
 
-impl Database {
-	pub fn new(path: &str) -> rusqlite::Result<Self> {
-		log::trace!("Opening database at path {path}");
-		let db_exists = std::fs::exists(path).unwrap_or(false);
-		let newly_created = !db_exists;
-		let conn = rusqlite::Connection::open(path)?;
+		let version = migration::get_version(&conn)?;
+		if version != DATABASE_VERSION {
+			anyhow::bail!(
+				"Database {path} is at version {version}, expected version {DATABASE_VERSION}, load the room to migrate it"
+			);
+		}
 
-		let mut db = Self {
-			conn,
-			cleaned_up: false,
-			migrated_from_version: 0,
+		let Some(backend) = Self::property_load(&conn, PROPERTY_STORAGE_BACKEND)? else {
+			anyhow::bail!("Database {path} has no storage backend set");
 		};
+		let storage_backend = backend.parse()?;
+		let storage = storage::open(storage_backend, path, true)?;
 
-		Self::run_empty_query(&db.conn, "PRAGMA SYNCHRONOUS=OFF")?;
-
-		if !newly_created {
-			let db_version = get_version(&db.conn)?;
-			db.migrated_from_version = db_version;
+		Self::spawn(conn, storage, path, version, storage_backend)
+	}
 
-			if db_version != DATABASE_VERSION {
-				log::info!("Updating database from version {db_version} to version {DATABASE_VERSION}");
-				if db_version == 0 {
-					migrate_to_version_1(&db.conn)?;
+	fn spawn(
+		conn: rusqlite::Connection,
+		storage: Box<dyn ChunkStorage>,
+		path: &str,
+		migrated_from_version: u32,
+		storage_backend: StorageBackend,
+	) -> anyhow::Result<Self> {
+		let jobs = Arc::new(JobQueue::default());
+		let mut state = DatabaseState { conn, storage };
+		let thread_jobs = jobs.clone();
+		let thread = std::thread::Builder::new()
+			.name(String::from("mp-database"))
+			.spawn(move || {
+				// Ends once the database is closed and all queued jobs are done
+				while let Some(job) = thread_jobs.pop() {
+					job(&mut state);
 				}
-			}
-		}
-		set_version(&db.conn, DATABASE_VERSION)?;
+			})?;
+
+		log::info!("Database at path {path} loaded ({storage_backend} storage)");
 
-		Self::init_table_chunk_data(&db.conn)?;
-		Self::init_table_previews(&db.conn)?;
+		Ok(Self {
+			jobs: Some(jobs),
+			thread: Some(thread),
+			cleaned_up: false,
+			migrated_from_version,
+			storage_backend,
+		})
+	}
 
-		log::info!("Database at path {path} loaded");
+	// Opens the database from async code, migrating it may take a while
+	pub async fn open(path: String, new_room_backend: StorageBackend) -> anyhow::Result<Self> {
+		tokio::task::spawn_blocking(move || Self::new(&path, new_room_backend)).await?
+	}
 
-		Ok(db)
+	pub async fn open_read_only(path: String) -> anyhow::Result<Self> {
+		tokio::task::spawn_blocking(move || Self::new_read_only(&path)).await?
 	}
 
 	fn run_empty_query(conn: &rusqlite::Connection, query: &'static str) -> rusqlite::Result<()> {
@@ -192,205 +244,146 @@ impl Database {
 		Ok(())
 	}
 
-	fn init_table_chunk_data(conn: &rusqlite::Connection) -> rusqlite::Result<()> {
-		Self::run_empty_query(conn, "CREATE TABLE IF NOT EXISTS chunk_data(x INT NOT NULL, y INT NOT NULL, data BLOB, modified INT64 NOT NULL, created INT64 NOT NULL, compression INT);")?;
-		Self::run_empty_query(conn, "CREATE INDEX IF NOT EXISTS index_x on chunk_data(x)")?;
-		Self::run_empty_query(conn, "CREATE INDEX IF NOT EXISTS index_y on chunk_data(y)")?;
-		Ok(())
+	fn property_load(conn: &rusqlite::Connection, key: &str) -> rusqlite::Result<Option<String>> {
+		conn
+			.query_row(
+				"SELECT value FROM properties WHERE key=?",
+				params![key],
+				|row| row.get(0),
+			)
+			.optional()
 	}
 
-	fn init_table_previews(conn: &rusqlite::Connection) -> rusqlite::Result<()> {
-		Self::run_empty_query(conn, "CREATE TABLE IF NOT EXISTS previews(x INT NOT NULL, y INT NOT NULL, zoom INT NOT NULL, data BLOB)")?;
-		Self::run_empty_query(
-			conn,
-			"CREATE INDEX IF NOT EXISTS previews_index_x on previews(x)",
-		)?;
-		Self::run_empty_query(
-			conn,
-			"CREATE INDEX IF NOT EXISTS previews_index_y on previews(y)",
+	fn property_save(conn: &rusqlite::Connection, key: &str, value: &str) -> rusqlite::Result<()> {
+		conn.execute(
+			"INSERT OR REPLACE INTO properties (key, value) VALUES (?,?)",
+			params![key, value],
 		)?;
 		Ok(())
 	}
 
-	fn chunk_insert(
+	fn history_load(
 		conn: &rusqlite::Connection,
-		pos: IVec2,
-		data: &[u8],
-		compression_type: CompressionType,
-	) -> rusqlite::Result<()> {
-		conn.execute(
-			"INSERT INTO chunk_data (x,y,data,modified,created,compression) VALUES(?,?,?,?,?,?)",
-			params![
-				pos.x,
-				pos.y,
-				data,
-				get_unix_timestamp(),
-				get_unix_timestamp(),
-				compression_type as i32,
-			],
+		identity: &str,
+	) -> rusqlite::Result<Vec<HistoryDatabaseRecord>> {
+		let mut stmt = conn.prepare(
+			"SELECT redo, raw_size, data FROM history WHERE identity=? ORDER BY redo ASC, idx ASC",
 		)?;
-		Ok(())
+		let res: Vec<_> = stmt
+			.query_map(params![identity], |row| {
+				Ok(HistoryDatabaseRecord {
+					redo: row.get(0)?,
+					raw_size: row.get(1)?,
+					data: row.get(2)?,
+				})
+			})?
+			.flatten()
+			.collect();
+		Ok(res)
 	}
 
-	fn chunk_save_data(
+	fn history_save(
 		conn: &rusqlite::Connection,
-		pos: IVec2,
-		data: &[u8],
-		compression_type: CompressionType,
+		identity: &str,
+		records: &[HistoryDatabaseRecord],
 	) -> rusqlite::Result<()> {
-		struct Row {
-			timestamp: i64,
-			chunk_id: i64,
-		}
-
-		if let Ok(row) = conn.query_row(
-			"SELECT created, rowid FROM chunk_data WHERE x = ? AND y = ? ORDER BY created DESC",
-			params![pos.x, pos.y],
-			|row| {
-				Ok(Row {
-					timestamp: row.get(0)?,
-					chunk_id: row.get(1)?,
-				})
-			},
-		) {
-			//Chunk already exists, update chunk
-			log::trace!("Updating chunk");
-			if get_unix_timestamp() as i64 - row.timestamp > i64::from(SECONDS_BETWEEN_SNAPSHOTS) {
-				//Insert a new chunk in its place
-				Self::chunk_insert(conn, pos, data, compression_type)?;
-			} else {
-				//Replace chunk
-				conn.execute(
-					"UPDATE chunk_data SET modified = ?, data = ?, compression = ? WHERE rowid = ?",
-					params![
-						get_unix_timestamp(),
-						data,
-						compression_type as i32,
-						row.chunk_id
-					],
-				)?;
-			}
-		} else {
-			// Chunk doesn't exist, create chunk
-			log::trace!("Creating chunk");
-			Self::chunk_insert(conn, pos, data, compression_type)?;
+		let transaction = conn.unchecked_transaction()?;
+		transaction.execute("DELETE FROM history WHERE identity=?", params![identity])?;
+		for (idx, record) in records.iter().enumerate() {
+			transaction.execute(
+				"INSERT INTO history (identity,redo,idx,raw_size,data) VALUES (?,?,?,?,?)",
+				params![identity, record.redo, idx, record.raw_size, record.data],
+			)?;
 		}
-
-		Ok(())
+		transaction.commit()
 	}
 
-	fn chunk_list_all(conn: &rusqlite::Connection) -> rusqlite::Result<Vec<IVec2>> {
-		struct Row {
-			x: i32,
-			y: i32,
-		}
-
-		let mut res = Vec::<IVec2>::new();
-
-		let mut stmt = conn.prepare("SELECT x, y FROM chunk_data GROUP BY x, y")?;
-		let iter = stmt.query_map([], |row| {
-			Ok(Row {
-				x: row.get(0)?,
-				y: row.get(1)?,
-			})
-		})?;
-
-		for row in iter.flatten() {
-			res.push(IVec2::new(row.x, row.y));
-		}
-
+	fn region_load_all(conn: &rusqlite::Connection) -> rusqlite::Result<Vec<ProtectedRegion>> {
+		let mut stmt =
+			conn.prepare("SELECT name, owner, x1, y1, x2, y2, read_only, editors FROM regions")?;
+		let res: Vec<_> = stmt
+			.query_map([], |row| {
+				let editors: String = row.get(7)?;
+				Ok(ProtectedRegion {
+					name: row.get(0)?,
+					owner: row.get(1)?,
+					start: IVec2::new(row.get(2)?, row.get(3)?),
+					end: IVec2::new(row.get(4)?, row.get(5)?),
+					read_only: row.get(6)?,
+					editors: editors
+						.split(',')
+						.filter(|editor| !editor.is_empty())
+						.map(String::from)
+						.collect(),
+				})
+			})?
+			.flatten()
+			.collect();
 		Ok(res)
 	}
 
-	fn chunk_load_data(conn: &rusqlite::Connection, pos: IVec2) -> Option<ChunkDatabaseRecord> {
-		struct Row {
-			data: Vec<u8>,
-			compression: u8,
-			modified: i64,
-			created: i64,
-		}
-
-		if let Ok(row) = conn
-			.query_row(
-				"SELECT data, compression, modified, created FROM chunk_data WHERE x=? AND y=? ORDER BY modified DESC",
-				params![pos.x, pos.y],
-				|row| {
-					Ok(Row {
-						data: row.get(0)?,
-						compression: row.get(1)?,
-						modified: row.get(2)?,
-						created: row.get(3)?,
-					})
-				},
-			) {
-				return Some(ChunkDatabaseRecord{
-					compression_type: CompressionType::try_from(row.compression).unwrap_or(CompressionType::Lz4),
-					created_at: row.created as u64,
-					modified_at: row.modified as u64,
-					data: row.data,
-				});
-			}
-
-		None
+	fn region_save(conn: &rusqlite::Connection, region: &ProtectedRegion) -> rusqlite::Result<()> {
+		conn.execute(
+			"INSERT OR REPLACE INTO regions (name,owner,x1,y1,x2,y2,read_only,editors) VALUES (?,?,?,?,?,?,?,?)",
+			params![
+				region.name,
+				region.owner,
+				region.start.x,
+				region.start.y,
+				region.end.x,
+				region.end.y,
+				region.read_only,
+				region.editors.join(","),
+			],
+		)?;
+		Ok(())
 	}
 
-	fn preview_clear_all(conn: &rusqlite::Connection) -> rusqlite::Result<()> {
-		conn.execute("DELETE FROM previews", [])?;
+	fn region_remove(conn: &rusqlite::Connection, name: &str) -> rusqlite::Result<()> {
+		conn.execute("DELETE FROM regions WHERE name=?", params![name])?;
 		Ok(())
 	}
 
-	fn preview_load_data(
-		conn: &rusqlite::Connection,
-		pos: IVec2,
-		zoom: u8,
-	) -> Option<PreviewDatabaseRecord> {
-		struct Row {
-			data: Vec<u8>,
-		}
-
-		if let Ok(row) = conn.query_row(
-			"SELECT data FROM previews WHERE x=? AND y=? AND zoom=?",
-			params![pos.x, pos.y, zoom],
-			|row| Ok(Row { data: row.get(0)? }),
-		) {
-			return Some(PreviewDatabaseRecord { data: row.data });
-		}
-
-		None
+	fn role_load_all(conn: &rusqlite::Connection) -> rusqlite::Result<Vec<(String, String)>> {
+		let mut stmt = conn.prepare("SELECT identity, role FROM roles")?;
+		let res: Vec<_> = stmt
+			.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+			.flatten()
+			.collect();
+		Ok(res)
 	}
 
-	fn preview_save_data(
+	fn role_save(
 		conn: &rusqlite::Connection,
-		pos: IVec2,
-		zoom: u8,
-		data: &[u8],
+		identity: &str,
+		role: Option<&str>,
 	) -> rusqlite::Result<()> {
-		if let Ok(rowid) = conn.query_row(
-			"SELECT rowid FROM previews WHERE x=? AND y=? AND zoom=?",
-			params![pos.x, pos.y, zoom],
-			|row| {
-				let res: u32 = row.get(0)?;
-				Ok(res)
-			},
-		) {
-			// Update preview data
+		if let Some(role) = role {
 			conn.execute(
-				"UPDATE previews SET x=?, y=?, zoom=?, data=? WHERE rowid=?",
-				params![pos.x, pos.y, zoom, data, rowid],
+				"INSERT OR REPLACE INTO roles (identity, role) VALUES (?,?)",
+				params![identity, role],
 			)?;
 		} else {
-			// Insert new preview data
-			conn.execute(
-				"INSERT INTO previews (x,y,zoom,data) VALUES (?,?,?,?)",
-				params![pos.x, pos.y, zoom, data],
-			)?;
+			conn.execute("DELETE FROM roles WHERE identity=?", params![identity])?;
 		}
-
 		Ok(())
 	}
 
-	pub fn cleanup(&mut self) {
+	// Lets the thread finish queued jobs and close the connection, further queries fail
+	pub async fn cleanup(&mut self) {
 		log::trace!("Cleaning-up database");
+		if let Some(jobs) = self.jobs.take() {
+			jobs.close();
+		}
+		if let Some(thread) = self.thread.take() {
+			// Joining blocks, keep it off the async runtime
+			if !matches!(
+				tokio::task::spawn_blocking(move || thread.join()).await,
+				Ok(Ok(()))
+			) {
+				log::error!("Database thread panicked");
+			}
+		}
 		self.cleaned_up = true;
 	}
 
@@ -402,15 +395,83 @@ impl Database {
 		for<'a> F: FnOnce(&'a rusqlite::Connection) -> rusqlite::Result<ResultType> + Send + 'static,
 		ResultType: std::marker::Send + 'static,
 	{
-		let db = database.lock().await;
-		let res = callback(&db.conn)?;
-		Ok(res)
+		Self::run(database, Priority::Interactive, move |state| {
+			Ok(callback(&state.conn)?)
+		})
+		.await
+	}
+
+	pub async fn get_storage<F, ResultType>(
+		database: &Arc<Mutex<Self>>,
+		callback: F,
+	) -> anyhow::Result<ResultType>
+	where
+		for<'a> F: FnOnce(&'a mut dyn ChunkStorage) -> anyhow::Result<ResultType> + Send + 'static,
+		ResultType: std::marker::Send + 'static,
+	{
+		Self::run(database, Priority::Interactive, move |state| {
+			callback(state.storage.as_mut())
+		})
+		.await
+	}
+
+	async fn get_storage_background<F, ResultType>(
+		database: &Arc<Mutex<Self>>,
+		callback: F,
+	) -> anyhow::Result<ResultType>
+	where
+		for<'a> F: FnOnce(&'a mut dyn ChunkStorage) -> anyhow::Result<ResultType> + Send + 'static,
+		ResultType: std::marker::Send + 'static,
+	{
+		Self::run(database, Priority::Background, move |state| {
+			callback(state.storage.as_mut())
+		})
+		.await
+	}
+
+	async fn run<F, ResultType>(
+		database: &Arc<Mutex<Self>>,
+		priority: Priority,
+		callback: F,
+	) -> anyhow::Result<ResultType>
+	where
+		F: FnOnce(&mut DatabaseState) -> anyhow::Result<ResultType> + Send + 'static,
+		ResultType: std::marker::Send + 'static,
+	{
+		let queued_at = Instant::now();
+		let jobs = database
+			.lock()
+			.await
+			.jobs
+			.clone()
+			.ok_or_else(|| anyhow::anyhow!("Database closed"))?;
+
+		let (result_sender, result_receiver) = oneshot::channel();
+		let queued = jobs.push(
+			priority,
+			Box::new(move |state| {
+				let started_at = Instant::now();
+				METRICS.database_lock_wait.observe(started_at - queued_at);
+
+				let res = callback(state);
+				METRICS.database_query.observe(started_at.elapsed());
+				let _ = result_sender.send(res);
+			}),
+		);
+		if !queued {
+			anyhow::bail!("Database thread stopped");
+		}
+
+		result_receiver.await?
 	}
 }
 
 impl Drop for Database {
 	fn drop(&mut self) {
 		assert!(self.cleaned_up, "cleanup() not called");
+		if let Some(jobs) = self.jobs.take() {
+			jobs.close(); // Lets the thread exit on its own
+		}
 		log::trace!("Database freed");
 	}
 }
@@ -419,15 +480,40 @@ pub struct DatabaseFunc {}
 
 impl DatabaseFunc {
 	pub async fn chunk_list_all(database: &Arc<Mutex<Database>>) -> anyhow::Result<Vec<IVec2>> {
-		Database::get_conn(database, Database::chunk_list_all).await
+		Database::get_storage(database, |storage| storage.chunk_list_all()).await
+	}
+
+	pub async fn chunk_list_stats(
+		database: &Arc<Mutex<Database>>,
+	) -> anyhow::Result<Vec<ChunkStats>> {
+		Database::get_storage(database, |storage| storage.chunk_list_stats()).await
+	}
+
+	pub async fn chunk_modified_in_area(
+		database: &Arc<Mutex<Database>>,
+		from: I64Vec2,
+		to: I64Vec2,
+	) -> anyhow::Result<Option<u64>> {
+		Database::get_storage(database, move |storage| {
+			storage.chunk_modified_in_area(from, to)
+		})
+		.await
 	}
 
 	pub async fn chunk_load_data(
 		database: &Arc<Mutex<Database>>,
 		chunk_pos: IVec2,
 	) -> anyhow::Result<Option<ChunkDatabaseRecord>> {
-		Database::get_conn(database, move |conn| {
-			Ok(Database::chunk_load_data(conn, chunk_pos))
+		Database::get_storage(database, move |storage| storage.chunk_load_data(chunk_pos)).await
+	}
+
+	pub async fn chunk_load_data_at(
+		database: &Arc<Mutex<Database>>,
+		chunk_pos: IVec2,
+		timestamp: u64,
+	) -> anyhow::Result<Option<ChunkDatabaseRecord>> {
+		Database::get_storage(database, move |storage| {
+			storage.chunk_load_data_at(chunk_pos, timestamp)
 		})
 		.await
 	}
@@ -437,15 +523,85 @@ impl DatabaseFunc {
 		pos: IVec2,
 		data: Arc<Vec<u8>>,
 		compression_type: CompressionType,
+	) -> anyhow::Result<()> {
+		Database::get_storage(database, move |storage| {
+			storage.chunk_save_data(pos, &data, compression_type)
+		})
+		.await
+	}
+
+	pub async fn chunk_prune_snapshots(
+		database: &Arc<Mutex<Database>>,
+		retention: SnapshotRetention,
+	) -> anyhow::Result<usize> {
+		Database::get_storage_background(database, move |storage| {
+			storage.chunk_prune_snapshots(&retention, get_unix_timestamp())
+		})
+		.await
+	}
+
+	pub async fn vacuum(database: &Arc<Mutex<Database>>) -> anyhow::Result<u64> {
+		Database::get_storage_background(database, |storage| storage.vacuum()).await
+	}
+
+	pub async fn role_load_all(
+		database: &Arc<Mutex<Database>>,
+	) -> anyhow::Result<Vec<(String, String)>> {
+		Database::get_conn(database, Database::role_load_all).await
+	}
+
+	// Removes the assignment if role is None
+	pub async fn role_save(
+		database: &Arc<Mutex<Database>>,
+		identity: String,
+		role: Option<String>,
+	) -> anyhow::Result<()> {
+		Database::get_conn(database, move |conn| {
+			Database::role_save(conn, &identity, role.as_deref())
+		})
+		.await
+	}
+
+	pub async fn region_load_all(
+		database: &Arc<Mutex<Database>>,
+	) -> anyhow::Result<Vec<ProtectedRegion>> {
+		Database::get_conn(database, Database::region_load_all).await
+	}
+
+	pub async fn region_save(
+		database: &Arc<Mutex<Database>>,
+		region: ProtectedRegion,
+	) -> anyhow::Result<()> {
+		Database::get_conn(database, move |conn| Database::region_save(conn, &region)).await
+	}
+
+	pub async fn region_remove(database: &Arc<Mutex<Database>>, name: String) -> anyhow::Result<()> {
+		Database::get_conn(database, move |conn| Database::region_remove(conn, &name)).await
+	}
+
+	pub async fn history_load(
+		database: &Arc<Mutex<Database>>,
+		identity: String,
+	) -> anyhow::Result<Vec<HistoryDatabaseRecord>> {
+		Database::get_conn(database, move |conn| {
+			Database::history_load(conn, &identity)
+		})
+		.await
+	}
+
+	pub async fn history_save(
+		database: &Arc<Mutex<Database>>,
+		identity: String,
+		records: Vec<HistoryDatabaseRecord>,
 	) -> anyhow::Result<()> {
 		Database::get_conn(database, move |conn| {
-			Database::chunk_save_data(conn, pos, &data, compression_type)
+			Database::history_save(conn, &identity, &records)
 		})
 		.await
 	}
 
 	pub async fn preview_clear_all(database: &Arc<Mutex<Database>>) -> anyhow::Result<()> {
-		Database::get_conn(database, Database::preview_clear_all).await
+		Database::get_storage_background(database, |storage| storage.preview_clear_all()).await
 	}
 
 	pub async fn preview_load_data(
@@ -453,8 +609,26 @@ impl DatabaseFunc {
 		pos: IVec2,
 		zoom: u8,
 	) -> anyhow::Result<Option<PreviewDatabaseRecord>> {
-		Database::get_conn(database, move |conn| {
-			Ok(Database::preview_load_data(conn, pos, zoom))
+		Database::get_storage(database, move |storage| {
+			storage.preview_load_data(pos, zoom)
+		})
+		.await
+	}
+
+	// Inputs of preview generation, a chunk (zoom 0) or a preview of the layer below
+	pub async fn preview_source_load(
+		database: &Arc<Mutex<Database>>,
+		pos: IVec2,
+		zoom: u8,
+	) -> anyhow::Result<Option<Vec<u8>>> {
+		Database::get_storage_background(database, move |storage| {
+			Ok(if zoom == 0 {
+				storage.chunk_load_data(pos)?.map(|record| record.data)
+			} else {
+				storage
+					.preview_load_data(pos, zoom)?
+					.map(|record| record.data)
+			})
 		})
 		.await
 	}
@@ -465,9 +639,40 @@ impl DatabaseFunc {
 		zoom: u8,
 		data: Vec<u8>,
 	) -> anyhow::Result<()> {
-		Database::get_conn(database, move |conn| {
-			Database::preview_save_data(conn, pos, zoom, &data)
+		Database::get_storage_background(database, move |storage| {
+			storage.preview_save_data(pos, zoom, &data)
 		})
 		.await
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn record_job(order: &Arc<StdMutex<Vec<&'static str>>>, name: &'static str) -> DatabaseJob {
+		let order = order.clone();
+		Box::new(move |_| order.lock().unwrap().push(name))
+	}
+
+	#[test]
+	fn interactive_jobs_run_before_background_jobs() {
+		let mut state = DatabaseState {
+			conn: rusqlite::Connection::open_in_memory().unwrap(),
+			storage: storage::open(StorageBackend::Memory, "", false).unwrap(),
+		};
+		let order = Arc::new(StdMutex::new(Vec::new()));
+
+		let queue = JobQueue::default();
+		assert!(queue.push(Priority::Background, record_job(&order, "preview")));
+		assert!(queue.push(Priority::Background, record_job(&order, "vacuum")));
+		assert!(queue.push(Priority::Interactive, record_job(&order, "load")));
+		queue.close();
+		assert!(!queue.push(Priority::Interactive, record_job(&order, "closed")));
+
+		while let Some(job) = queue.pop() {
+			job(&mut state);
+		}
+		assert_eq!(*order.lock().unwrap(), ["load", "preview", "vacuum"]);
+	}
+}
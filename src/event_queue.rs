@@ -6,9 +6,12 @@ use parking_lot::Mutex as SyncMutex;
 
 use tokio::sync::{broadcast, Notify};
 
+use crate::metrics::{Gauge, GaugeSet};
+
 struct Data<DataType> {
 	queue: VecDeque<DataType>,
 	pub notifier: Arc<Notify>,
+	depth_gauge: Option<Arc<Gauge>>, // Tracks the number of queued events, unregistered with the queue
 }
 
 #[derive(Clone)]
@@ -22,23 +25,39 @@ impl<DataType> EventQueue<DataType> {
 			data: Arc::new(SyncMutex::new(Data {
 				notifier,
 				queue: VecDeque::default(),
+				depth_gauge: None,
 			})),
 		}
 	}
 
+	pub fn with_depth_gauge(self, gauges: &GaugeSet) -> Self {
+		self.data.lock().depth_gauge = Some(gauges.register());
+		self
+	}
+
 	pub fn send(&self, message: DataType) {
 		let mut data = self.data.lock();
 		data.queue.push_back(message);
+		if let Some(gauge) = &data.depth_gauge {
+			gauge.inc();
+		}
 		data.notifier.notify_waiters();
 	}
 
 	pub fn read(&self) -> Option<DataType> {
 		let mut data = self.data.lock();
-		data.queue.pop_front()
+		let message = data.queue.pop_front()?;
+		if let Some(gauge) = &data.depth_gauge {
+			gauge.dec();
+		}
+		Some(message)
 	}
 
 	pub fn read_all(&self) -> Vec<DataType> {
 		let mut data = self.data.lock();
+		if let Some(gauge) = &data.depth_gauge {
+			gauge.sub(data.queue.len());
+		}
 		data.queue.drain(..).collect()
 	}
 }
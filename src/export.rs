@@ -0,0 +1,176 @@
+use std::{
+	io::{BufWriter, Write},
+	sync::Arc,
+};
+
+use glam::IVec2;
+use tokio::sync::Mutex;
+
+use crate::{
+	chunk::system::ChunkSystem,
+	compression::decompress_lz4,
+	database::{get_unix_timestamp, Database, DatabaseFunc},
+	limits::{self, CHUNK_IMAGE_SIZE_BYTES_RGBA, CHUNK_SIZE_PX},
+	room,
+	server::ServerMutex,
+};
+
+const EXPORT_DIR: &str = "exports";
+
+// Global pixel rectangle, both corners inclusive
+#[derive(Clone, Copy)]
+pub struct Region {
+	pub start: IVec2,
+	pub end: IVec2,
+}
+
+impl Region {
+	pub fn from_corners(a: IVec2, b: IVec2) -> Self {
+		Self {
+			start: a.min(b),
+			end: a.max(b),
+		}
+	}
+
+	pub const fn size(&self) -> IVec2 {
+		IVec2::new(self.end.x - self.start.x + 1, self.end.y - self.start.y + 1)
+	}
+
+	// Computed in 64 bits, safe to call before validating the region
+	pub const fn area(&self) -> u64 {
+		let width = self.end.x as i64 - self.start.x as i64 + 1;
+		let height = self.end.y as i64 - self.start.y as i64 + 1;
+		width as u64 * height as u64
+	}
+}
+
+// Stitch all chunks covered by the region into a single RGBA image.
+// Chunks missing in the database are left transparent.
+pub async fn render_region(
+	database: &Arc<Mutex<Database>>,
+	region: Region,
+) -> anyhow::Result<Vec<u8>> {
+	let size = region.size();
+	let mut image = vec![0u8; size.x as usize * size.y as usize * 4];
+
+	let chunk_start = ChunkSystem::global_pixel_pos_to_chunk_pos(region.start);
+	let chunk_end = ChunkSystem::global_pixel_pos_to_chunk_pos(region.end);
+
+	for chunk_y in chunk_start.y..=chunk_end.y {
+		for chunk_x in chunk_start.x..=chunk_end.x {
+			let chunk_pos = IVec2::new(chunk_x, chunk_y);
+
+			let Some(record) = DatabaseFunc::chunk_load_data(database, chunk_pos).await? else {
+				continue;
+			};
+
+			let Some(raw) = decompress_lz4(&record.data, CHUNK_IMAGE_SIZE_BYTES_RGBA) else {
+				log::error!("Failed to decompress chunk at {chunk_pos}, skipping");
+				continue;
+			};
+
+			let chunk_origin = chunk_pos * CHUNK_SIZE_PX as i32;
+			let from = region.start.max(chunk_origin);
+			let to = region
+				.end
+				.min(chunk_origin + IVec2::splat(CHUNK_SIZE_PX as i32 - 1));
+			let row_bytes = (to.x - from.x + 1) as usize * 4;
+
+			// Copy row by row
+			for y in from.y..=to.y {
+				let src_offset = ((y - chunk_origin.y) as usize * CHUNK_SIZE_PX as usize
+					+ (from.x - chunk_origin.x) as usize)
+					* 4;
+				let dst_offset = ((y - region.start.y) as usize * size.x as usize
+					+ (from.x - region.start.x) as usize)
+					* 4;
+
+				image[dst_offset..dst_offset + row_bytes]
+					.copy_from_slice(&raw[src_offset..src_offset + row_bytes]);
+			}
+		}
+	}
+
+	Ok(image)
+}
+
+pub fn write_png(path: &str, size: IVec2, rgba: &[u8]) -> anyhow::Result<()> {
+	let file = std::fs::File::create(path)?;
+	write_png_to(BufWriter::new(file), size, rgba)
+}
+
+pub fn encode_png(size: IVec2, rgba: &[u8]) -> anyhow::Result<Vec<u8>> {
+	let mut out = Vec::new();
+	write_png_to(&mut out, size, rgba)?;
+	Ok(out)
+}
+
+fn write_png_to<W: Write>(out: W, size: IVec2, rgba: &[u8]) -> anyhow::Result<()> {
+	let mut encoder = png::Encoder::new(out, size.x as u32, size.y as u32);
+	encoder.set_color(png::ColorType::Rgba);
+	encoder.set_depth(png::BitDepth::Eight);
+	let mut writer = encoder.write_header()?;
+	writer.write_image_data(rgba)?;
+	writer.finish()?;
+	Ok(())
+}
+
+// Export the region of a room into the exports directory. Returns the path of the written file.
+pub async fn export_region_png(
+	server_mtx: &ServerMutex,
+	room_name: &str,
+	region: Region,
+) -> anyhow::Result<String> {
+	if room_name.is_empty() || !room_name.chars().all(|ch| ch.is_ascii_alphanumeric()) {
+		anyhow::bail!("Invalid room name");
+	}
+
+	if region.area() > u64::from(limits::EXPORT_AREA_MAX_PX) {
+		anyhow::bail!(
+			"Region too large (max {} pixels)",
+			limits::EXPORT_AREA_MAX_PX
+		);
+	}
+
+	let room_mtx = server_mtx.lock().await.rooms.get(room_name).cloned();
+
+	let rgba = if let Some(room_mtx) = room_mtx {
+		let room = room_mtx.lock().await;
+		let database = room.database.clone();
+		let chunk_system_mtx = room.chunk_system.clone();
+		drop(room);
+
+		// Include changes not auto-saved yet
+		ChunkSystem::save_all(chunk_system_mtx).await;
+		render_region(&database, region).await?
+	} else {
+		// Room not loaded, read its database directly
+		let db_path = room::get_database_path(room_name);
+		if !std::fs::exists(&db_path).unwrap_or(false) {
+			anyhow::bail!("Room {room_name} does not exist");
+		}
+
+		// Read-only, so neither a migration nor a room loading meanwhile can be affected
+		let database = Arc::new(Mutex::new(Database::open_read_only(db_path).await?));
+		let res = render_region(&database, region).await;
+		database.lock().await.cleanup().await;
+		res?
+	};
+
+	tokio::fs::create_dir_all(EXPORT_DIR).await?;
+	let path = format!(
+		"{EXPORT_DIR}/{room_name}_{}_{}_{}_{}_{}.png",
+		region.start.x,
+		region.start.y,
+		region.end.x,
+		region.end.y,
+		get_unix_timestamp()
+	);
+
+	let size = region.size();
+	let path_write = path.clone();
+	tokio::task::spawn_blocking(move || write_png(&path_write, size, &rgba)).await??;
+
+	log::info!("Exported room {room_name} region to {path}");
+	Ok(path)
+}
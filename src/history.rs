@@ -0,0 +1,340 @@
+use std::{collections::HashMap, mem::size_of};
+
+use bytes::{Buf, BufMut, BytesMut};
+use glam::IVec2;
+
+use crate::{
+	compression::{compress_lz4, decompress_lz4},
+	database::HistoryDatabaseRecord,
+	pixel::ColorRGBA,
+};
+
+const ENCODING_VERSION: u8 = 1;
+
+// x, y, previous rgba, written rgba
+const ENCODED_PIXEL_SIZE: usize = size_of::<i32>() * 2 + 4 + 4;
+
+#[derive(Clone)]
+pub struct HistoryPixel {
+	pub pos: IVec2,
+	pub previous: ColorRGBA, // Color to restore
+	pub written: ColorRGBA, // Color expected to be on the canvas, otherwise someone else has modified it
+}
+
+fn put_color(buf: &mut BytesMut, color: ColorRGBA) {
+	buf.put_u8(color.r);
+	buf.put_u8(color.g);
+	buf.put_u8(color.b);
+	buf.put_u8(color.a);
+}
+
+fn get_color(buf: &mut &[u8]) -> ColorRGBA {
+	ColorRGBA::new(buf.get_u8(), buf.get_u8(), buf.get_u8(), buf.get_u8())
+}
+
+pub struct HistoryCell {
+	pub pixels: Vec<HistoryPixel>,
+}
+
+impl HistoryCell {
+	const fn size_bytes(&self) -> usize {
+		self.pixels.len() * size_of::<HistoryPixel>()
+	}
+
+	fn encode(&self, redo: bool) -> HistoryDatabaseRecord {
+		let mut buf = BytesMut::with_capacity(1 + self.pixels.len() * ENCODED_PIXEL_SIZE);
+		buf.put_u8(ENCODING_VERSION);
+		for pixel in &self.pixels {
+			buf.put_i32(pixel.pos.x);
+			buf.put_i32(pixel.pos.y);
+			put_color(&mut buf, pixel.previous);
+			put_color(&mut buf, pixel.written);
+		}
+
+		HistoryDatabaseRecord {
+			redo,
+			raw_size: buf.len() as u32,
+			data: compress_lz4(&buf),
+		}
+	}
+
+	fn decode(record: &HistoryDatabaseRecord) -> Option<Self> {
+		let raw = decompress_lz4(&record.data, record.raw_size as usize)?;
+		let (&version, mut buf) = raw.split_first()?;
+
+		if version != ENCODING_VERSION || buf.len() % ENCODED_PIXEL_SIZE != 0 {
+			log::error!(
+				"Invalid history cell (version {version}, size {})",
+				buf.len()
+			);
+			return None;
+		}
+
+		let mut pixels = Vec::with_capacity(buf.len() / ENCODED_PIXEL_SIZE);
+		while buf.has_remaining() {
+			pixels.push(HistoryPixel {
+				pos: IVec2::new(buf.get_i32(), buf.get_i32()),
+				previous: get_color(&mut buf),
+				written: get_color(&mut buf),
+			});
+		}
+
+		Some(Self { pixels })
+	}
+}
+
+#[derive(Default)]
+pub struct History {
+	cells: Vec<HistoryCell>,
+	redo_cells: Vec<HistoryCell>,
+	modified_pixel_coords: HashMap<IVec2, usize /* index in the last cell */>,
+	size_bytes: usize,
+	max_size_bytes: usize,
+	modified: bool,  // Changed since it was loaded or last saved
+	oversized: bool, // The current stroke exceeded the budget on its own and is not recorded
+}
+
+impl History {
+	pub fn new(max_size_bytes: usize) -> Self {
+		Self {
+			max_size_bytes,
+			..Default::default()
+		}
+	}
+
+	// A single stroke above the budget (e.g. a large import or rollback) would evict the whole history, drop it instead
+	fn drop_oversized_stroke(&mut self) {
+		if self
+			.cells
+			.last()
+			.is_none_or(|cell| cell.size_bytes() <= self.max_size_bytes)
+		{
+			return;
+		}
+
+		if let Some(dropped) = self.cells.pop() {
+			log::warn!(
+				"History cell of {} pixels exceeds the history limit, it can't be undone",
+				dropped.pixels.len()
+			);
+			self.size_bytes -= dropped.size_bytes();
+		}
+		self.modified_pixel_coords.clear();
+		self.oversized = true;
+	}
+
+	// Drop the oldest cells until the history fits in the configured budget.
+	// Runs between strokes only, otherwise a growing stroke could evict the older cells and still turn out too large.
+	// Until the next stroke starts the history may take up to twice the budget.
+	fn enforce_limit(&mut self) {
+		self.drop_oversized_stroke();
+
+		while self.size_bytes > self.max_size_bytes {
+			let removed = if self.cells.len() > 1 {
+				self.cells.remove(0)
+			} else if !self.redo_cells.is_empty() {
+				self.redo_cells.remove(0)
+			} else {
+				break; // Keep the current stroke
+			};
+			self.size_bytes -= removed.size_bytes();
+		}
+	}
+
+	fn push_cell(&mut self, redo: bool, cell: HistoryCell) {
+		self.modified = true;
+		self.size_bytes += cell.size_bytes();
+		if redo {
+			self.redo_cells.push(cell);
+		} else {
+			self.cells.push(cell);
+		}
+		self.enforce_limit();
+	}
+
+	pub fn create_snapshot(&mut self) {
+		self.modified_pixel_coords.clear();
+		self.oversized = false;
+
+		self.push_cell(
+			false,
+			HistoryCell {
+				pixels: Vec::default(),
+			},
+		);
+	}
+
+	pub fn add_pixel(&mut self, pixel: HistoryPixel) {
+		self.modified = true;
+
+		// New stroke, the redo stack is no longer valid
+		if !self.redo_cells.is_empty() {
+			for cell in self.redo_cells.drain(..) {
+				self.size_bytes -= cell.size_bytes();
+			}
+		}
+
+		// The rest of a dropped stroke isn't recorded either, it would be undoable on its own
+		if self.oversized {
+			return;
+		}
+
+		if self.cells.is_empty() {
+			self.create_snapshot();
+		}
+
+		if let Some(last) = self.cells.last_mut() {
+			if let Some(idx) = self.modified_pixel_coords.get(&pixel.pos) {
+				// Already modified in this stroke, keep the oldest color to restore
+				last.pixels[*idx].written = pixel.written;
+			} else {
+				self
+					.modified_pixel_coords
+					.insert(pixel.pos, last.pixels.len());
+				last.pixels.push(pixel);
+				self.size_bytes += size_of::<HistoryPixel>();
+				self.drop_oversized_stroke();
+			}
+		}
+	}
+
+	fn pop_non_empty(&mut self, redo: bool) -> Option<HistoryCell> {
+		let cells = if redo {
+			&mut self.redo_cells
+		} else {
+			&mut self.cells
+		};
+
+		while let Some(cell) = cells.pop() {
+			self.modified = true;
+			self.size_bytes -= cell.size_bytes();
+			if !cell.pixels.is_empty() {
+				return Some(cell);
+			}
+		}
+		None
+	}
+
+	pub fn undo(&mut self) -> Option<HistoryCell> {
+		self.pop_non_empty(false)
+	}
+
+	pub fn redo(&mut self) -> Option<HistoryCell> {
+		self.pop_non_empty(true)
+	}
+
+	// Store pixels overwritten by undo
+	pub fn push_redo(&mut self, pixels: Vec<HistoryPixel>) {
+		self.push_cell(true, HistoryCell { pixels });
+	}
+
+	// Store pixels overwritten by redo, keeping the redo stack intact
+	pub fn push_undo(&mut self, pixels: Vec<HistoryPixel>) {
+		self.modified_pixel_coords.clear();
+		self.oversized = false;
+		self.push_cell(false, HistoryCell { pixels });
+	}
+
+	pub fn encode(&self) -> Vec<HistoryDatabaseRecord> {
+		let undo = self
+			.cells
+			.iter()
+			.filter(|cell| !cell.pixels.is_empty())
+			.map(|cell| cell.encode(false));
+
+		let redo = self
+			.redo_cells
+			.iter()
+			.filter(|cell| !cell.pixels.is_empty())
+			.map(|cell| cell.encode(true));
+
+		undo.chain(redo).collect()
+	}
+
+	// Records are expected to be ordered from the oldest to the newest
+	pub fn load(&mut self, records: &[HistoryDatabaseRecord]) {
+		self.cells.clear();
+		self.redo_cells.clear();
+		self.modified_pixel_coords.clear();
+		self.size_bytes = 0;
+
+		for record in records {
+			if let Some(cell) = HistoryCell::decode(record) {
+				self.push_cell(record.redo, cell);
+			}
+		}
+		self.modified = false;
+	}
+
+	pub const fn is_modified(&self) -> bool {
+		self.modified
+	}
+
+	// The current stroke was too large to be recorded
+	pub const fn is_oversized(&self) -> bool {
+		self.oversized
+	}
+
+	pub const fn mark_saved(&mut self) {
+		self.modified = false;
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn history_for_pixels(count: usize) -> History {
+		History::new(count * size_of::<HistoryPixel>())
+	}
+
+	fn draw(history: &mut History, from: i32, count: i32) {
+		history.create_snapshot();
+		for x in from..from + count {
+			history.add_pixel(HistoryPixel {
+				pos: IVec2::new(x, 0),
+				previous: ColorRGBA::new(0, 0, 0, 0),
+				written: ColorRGBA::new(255, 255, 255, 255),
+			});
+		}
+	}
+
+	#[test]
+	fn oversized_stroke_keeps_earlier_history() {
+		let mut history = history_for_pixels(10);
+		draw(&mut history, 0, 3);
+		draw(&mut history, 100, 4);
+		draw(&mut history, 200, 25);
+		assert!(history.is_oversized());
+
+		assert_eq!(history.undo().unwrap().pixels.len(), 4);
+		assert_eq!(history.undo().unwrap().pixels.len(), 3);
+		assert!(history.undo().is_none());
+	}
+
+	#[test]
+	fn oversized_stroke_is_not_recorded_partially() {
+		let mut history = history_for_pixels(10);
+		draw(&mut history, 0, 25);
+		assert!(history.is_oversized());
+		assert!(history.undo().is_none());
+
+		// The next stroke is recorded again
+		draw(&mut history, 100, 2);
+		assert!(!history.is_oversized());
+		assert_eq!(history.undo().unwrap().pixels.len(), 2);
+	}
+
+	#[test]
+	fn older_strokes_are_evicted_once_the_next_one_starts() {
+		let mut history = history_for_pixels(10);
+		draw(&mut history, 0, 6);
+		draw(&mut history, 100, 6);
+		assert!(!history.is_oversized());
+
+		draw(&mut history, 200, 1);
+		assert_eq!(history.undo().unwrap().pixels.len(), 1);
+		assert_eq!(history.undo().unwrap().pixels.len(), 6);
+		assert!(history.undo().is_none());
+	}
+}
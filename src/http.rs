@@ -0,0 +1,557 @@
+use std::{
+	fmt::Write as _,
+	io,
+	path::{Path, PathBuf},
+	pin::Pin,
+	sync::Arc,
+	task::{Context, Poll},
+};
+
+use tokio::{
+	io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+	sync::Mutex,
+};
+
+use crate::{
+	database::Database,
+	limits, room,
+	server::ServerMutex,
+	tile_server::{self, TileLayer},
+};
+
+// Plain HTTP requests arriving on the WebSocket port: static files of the web client and a small JSON API.
+// Every connection serves a single request.
+
+const REQUEST_HEAD_SIZE_MAX: usize = 16 * 1024;
+const REQUEST_HEADER_COUNT_MAX: usize = 64;
+const WEB_ROOT_DEFAULT: &str = "web/dist";
+
+pub struct Request {
+	pub method: String,
+	pub path: String, // Without the query string
+	headers: Vec<(String, String)>,
+}
+
+impl Request {
+	fn parse(head: &[u8]) -> anyhow::Result<Self> {
+		let mut headers = [httparse::EMPTY_HEADER; REQUEST_HEADER_COUNT_MAX];
+		let mut request = httparse::Request::new(&mut headers);
+		if !request.parse(head)?.is_complete() {
+			anyhow::bail!("Incomplete request");
+		}
+
+		let (Some(method), Some(target)) = (request.method, request.path) else {
+			anyhow::bail!("Invalid request line");
+		};
+
+		Ok(Self {
+			method: String::from(method),
+			path: String::from(target.split('?').next().unwrap_or_default()),
+			headers: request
+				.headers
+				.iter()
+				.map(|header| {
+					(
+						header.name.to_ascii_lowercase(),
+						String::from_utf8_lossy(header.value).into_owned(),
+					)
+				})
+				.collect(),
+		})
+	}
+
+	pub fn header(&self, name: &str) -> Option<&str> {
+		self
+			.headers
+			.iter()
+			.find(|(header_name, _)| header_name.eq_ignore_ascii_case(name))
+			.map(|(_, value)| value.as_str())
+	}
+
+	pub fn is_websocket_upgrade(&self) -> bool {
+		self
+			.header("upgrade")
+			.is_some_and(|upgrade| upgrade.eq_ignore_ascii_case("websocket"))
+	}
+}
+
+// Reads the request line and headers, leaving the body (if any) in the stream
+pub async fn read_request<S: AsyncRead + Unpin>(
+	stream: &mut S,
+) -> anyhow::Result<(Request, Vec<u8>)> {
+	let mut head = Vec::new();
+	let mut buf = [0u8; 1024];
+
+	loop {
+		let count = stream.read(&mut buf).await?;
+		if count == 0 {
+			anyhow::bail!("Connection closed before the request was complete");
+		}
+		head.extend_from_slice(&buf[..count]);
+
+		if let Some(end) = head.windows(4).position(|window| window == b"\r\n\r\n") {
+			let request = Request::parse(&head[..end + 4])?;
+			return Ok((request, head));
+		}
+
+		if head.len() > REQUEST_HEAD_SIZE_MAX {
+			anyhow::bail!("Request head too large");
+		}
+	}
+}
+
+// Replays already read bytes before reading from the stream again, so the WebSocket handshake can parse the request head
+pub struct Rewind<S> {
+	prefix: Vec<u8>,
+	prefix_pos: usize,
+	inner: S,
+}
+
+impl<S> Rewind<S> {
+	pub const fn new(prefix: Vec<u8>, inner: S) -> Self {
+		Self {
+			prefix,
+			prefix_pos: 0,
+			inner,
+		}
+	}
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Rewind<S> {
+	fn poll_read(
+		self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &mut ReadBuf<'_>,
+	) -> Poll<io::Result<()>> {
+		let this = self.get_mut();
+		if this.prefix_pos < this.prefix.len() {
+			let remaining = &this.prefix[this.prefix_pos..];
+			let count = remaining.len().min(buf.remaining());
+			buf.put_slice(&remaining[..count]);
+			this.prefix_pos += count;
+			return Poll::Ready(Ok(()));
+		}
+		Pin::new(&mut this.inner).poll_read(cx, buf)
+	}
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Rewind<S> {
+	fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+		Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+	}
+
+	fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+	}
+
+	fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+	}
+}
+
+pub struct Response {
+	status: u16,
+	content_type: &'static str,
+	headers: Vec<(&'static str, String)>,
+	body: Vec<u8>,
+}
+
+impl Response {
+	pub const fn new(status: u16, content_type: &'static str, body: Vec<u8>) -> Self {
+		Self {
+			status,
+			content_type,
+			headers: Vec::new(),
+			body,
+		}
+	}
+
+	pub fn json<T: serde::Serialize>(value: &T) -> Self {
+		match serde_json::to_vec(value) {
+			Ok(body) => Self::new(200, "application/json", body),
+			Err(e) => Self::error(500, &e.to_string()),
+		}
+	}
+
+	pub fn error(status: u16, message: &str) -> Self {
+		Self::new(
+			status,
+			"text/plain; charset=utf-8",
+			Vec::from(message.as_bytes()),
+		)
+	}
+
+	pub fn with_header(mut self, name: &'static str, value: String) -> Self {
+		self.headers.push((name, value));
+		self
+	}
+
+	const fn reason(&self) -> &'static str {
+		match self.status {
+			200 => "OK",
+			304 => "Not Modified",
+			400 => "Bad Request",
+			404 => "Not Found",
+			405 => "Method Not Allowed",
+			503 => "Service Unavailable",
+			_ => "Internal Server Error",
+		}
+	}
+
+	pub async fn write<S: AsyncWrite + Unpin>(
+		&self,
+		stream: &mut S,
+		with_body: bool,
+	) -> io::Result<()> {
+		let mut head = format!(
+			"HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+			self.status,
+			self.reason(),
+			self.content_type,
+			self.body.len()
+		);
+		for (name, value) in &self.headers {
+			let _ = write!(head, "{name}: {value}\r\n");
+		}
+		head.push_str("\r\n");
+
+		stream.write_all(head.as_bytes()).await?;
+		if with_body {
+			stream.write_all(&self.body).await?;
+		}
+		stream.shutdown().await
+	}
+}
+
+// Used when the connection is rejected before the request is handled
+pub async fn respond_error<S: AsyncWrite + Unpin>(
+	stream: &mut S,
+	status: u16,
+	message: &str,
+) -> io::Result<()> {
+	Response::error(status, message).write(stream, true).await
+}
+
+pub async fn serve<S: AsyncWrite + Unpin>(
+	stream: &mut S,
+	request: &Request,
+	server_mtx: &ServerMutex,
+) -> anyhow::Result<()> {
+	let response = match request.method.as_str() {
+		"GET" | "HEAD" => handle_request(request, server_mtx).await,
+		_ => Response::error(405, "Method not allowed"),
+	};
+
+	log::trace!(
+		"HTTP {} {} -> {}",
+		request.method,
+		request.path,
+		response.status
+	);
+	response.write(stream, request.method != "HEAD").await?;
+	Ok(())
+}
+
+// What a request path points to
+#[derive(Debug, PartialEq, Eq)]
+enum Route<'a> {
+	Rooms,
+	Room(&'a str),
+	Tile {
+		room_name: &'a str,
+		layer: Option<TileLayer>, // None if the zoom level is out of range
+		x: &'a str,
+		y: &'a str,
+	},
+	UnknownApi,
+	Static, // File of the web client
+}
+
+fn route<'a>(segments: &[&'a str]) -> Route<'a> {
+	match *segments {
+		["api", "rooms"] => Route::Rooms,
+		["api", "rooms", room_name] => Route::Room(room_name),
+		["api", "rooms", room_name, "previews", zoom, x, y] => Route::Tile {
+			room_name,
+			layer: zoom.parse().ok().and_then(TileLayer::from_preview_zoom),
+			x,
+			y,
+		},
+		["api", ..] => Route::UnknownApi,
+		[room_name, zoom, x, y]
+			if zoom.parse::<u8>().is_ok() && tile_server::parse_tile_pos(x, y).is_some() =>
+		{
+			Route::Tile {
+				room_name,
+				layer: zoom.parse().ok().and_then(TileLayer::from_zoom),
+				x,
+				y,
+			}
+		}
+		_ => Route::Static,
+	}
+}
+
+async fn handle_request(request: &Request, server_mtx: &ServerMutex) -> Response {
+	let segments: Vec<&str> = request
+		.path
+		.split('/')
+		.filter(|segment| !segment.is_empty())
+		.collect();
+
+	let res = match route(&segments) {
+		Route::Rooms => api_rooms(server_mtx).await,
+		Route::Room(room_name) => api_room(server_mtx, room_name).await,
+		Route::Tile {
+			room_name,
+			layer,
+			x,
+			y,
+		} => tile(server_mtx, request, room_name, layer, x, y).await,
+		Route::UnknownApi => Ok(Response::error(404, "Unknown endpoint")),
+		Route::Static => {
+			let web_root = server_mtx
+				.lock()
+				.await
+				.config
+				.web_root
+				.clone()
+				.unwrap_or_else(|| String::from(WEB_ROOT_DEFAULT));
+			serve_static(&web_root, &segments).await
+		}
+	};
+
+	res.unwrap_or_else(|e| {
+		log::error!("HTTP request {} failed: {e}", request.path);
+		Response::error(500, "Internal server error")
+	})
+}
+
+#[derive(serde::Serialize)]
+struct RoomInfo {
+	name: String,
+	online: usize, // Sessions in the room, including hidden spectators
+}
+
+fn is_valid_room_name(room_name: &str) -> bool {
+	room_name.len() >= limits::ROOM_NAME_LEN_MIN as usize
+		&& room_name.len() <= limits::ROOM_NAME_LEN_MAX as usize
+		&& room_name.chars().all(|ch| ch.is_ascii_alphanumeric())
+}
+
+fn room_exists(room_name: &str) -> bool {
+	std::fs::exists(room::get_database_path(room_name)).unwrap_or(false)
+}
+
+async fn online_count(server_mtx: &ServerMutex, room_name: &str) -> usize {
+	let room_mtx = server_mtx.lock().await.rooms.get(room_name).cloned();
+	match room_mtx {
+		Some(room_mtx) => room_mtx.lock().await.get_all_sessions(None).len(),
+		None => 0,
+	}
+}
+
+// Every room stored on disk, whether loaded or not
+async fn api_rooms(server_mtx: &ServerMutex) -> anyhow::Result<Response> {
+	let mut names = Vec::new();
+	let mut dir = match tokio::fs::read_dir("rooms").await {
+		Ok(dir) => dir,
+		Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Response::json(&names)),
+		Err(e) => return Err(e.into()),
+	};
+	while let Some(entry) = dir.next_entry().await? {
+		let file_name = entry.file_name();
+		if let Some(name) = file_name.to_str().and_then(|name| name.strip_suffix(".db")) {
+			if is_valid_room_name(name) {
+				names.push(String::from(name));
+			}
+		}
+	}
+	names.sort_unstable();
+
+	let mut rooms = Vec::with_capacity(names.len());
+	for name in names {
+		let online = online_count(server_mtx, &name).await;
+		rooms.push(RoomInfo { name, online });
+	}
+
+	Ok(Response::json(&rooms))
+}
+
+async fn api_room(server_mtx: &ServerMutex, room_name: &str) -> anyhow::Result<Response> {
+	if !is_valid_room_name(room_name) || !room_exists(room_name) {
+		return Ok(Response::error(404, "Room not found"));
+	}
+
+	Ok(Response::json(&RoomInfo {
+		name: String::from(room_name),
+		online: online_count(server_mtx, room_name).await,
+	}))
+}
+
+// Database of a loaded room, or a temporarily opened one. Doesn't load the room, so HTTP requests cannot keep rooms alive.
+pub enum RoomDatabase {
+	Loaded(Arc<Mutex<Database>>),
+	Opened(Arc<Mutex<Database>>),
+}
+
+impl RoomDatabase {
+	pub async fn open(server_mtx: &ServerMutex, room_name: &str) -> anyhow::Result<Option<Self>> {
+		let room_mtx = server_mtx.lock().await.rooms.get(room_name).cloned();
+		if let Some(room_mtx) = room_mtx {
+			return Ok(Some(Self::Loaded(room_mtx.lock().await.database.clone())));
+		}
+
+		if !room_exists(room_name) {
+			return Ok(None);
+		}
+
+		// Opened without the server lock. If the room loads meanwhile, its writes wait for our reads (and the other
+		// way around) thanks to the busy timeout, and a database not migrated yet is refused.
+		let database = Database::open_read_only(room::get_database_path(room_name)).await?;
+		Ok(Some(Self::Opened(Arc::new(Mutex::new(database)))))
+	}
+
+	pub const fn get(&self) -> &Arc<Mutex<Database>> {
+		match self {
+			Self::Loaded(database) | Self::Opened(database) => database,
+		}
+	}
+
+	pub async fn close(self) {
+		if let Self::Opened(database) = self {
+			database.lock().await.cleanup().await;
+		}
+	}
+}
+
+async fn tile(
+	server_mtx: &ServerMutex,
+	request: &Request,
+	room_name: &str,
+	layer: Option<TileLayer>,
+	x: &str,
+	y: &str,
+) -> anyhow::Result<Response> {
+	let (Some(layer), Some(pos)) = (layer, tile_server::parse_tile_pos(x, y)) else {
+		return Ok(Response::error(404, "Tile not found"));
+	};
+
+	if !is_valid_room_name(room_name) {
+		return Ok(Response::error(404, "Room not found"));
+	}
+
+	tile_server::serve_tile(server_mtx, request, room_name, layer, pos).await
+}
+
+fn content_type(path: &Path) -> &'static str {
+	match path.extension().and_then(|ext| ext.to_str()) {
+		Some("html") => "text/html; charset=utf-8",
+		Some("js" | "mjs") => "text/javascript; charset=utf-8",
+		Some("css") => "text/css; charset=utf-8",
+		Some("json" | "map") => "application/json",
+		Some("svg") => "image/svg+xml",
+		Some("png") => "image/png",
+		Some("jpg" | "jpeg") => "image/jpeg",
+		Some("ico") => "image/x-icon",
+		Some("wasm") => "application/wasm",
+		Some("woff2") => "font/woff2",
+		Some("txt") => "text/plain; charset=utf-8",
+		_ => "application/octet-stream",
+	}
+}
+
+async fn serve_static(web_root: &str, segments: &[&str]) -> anyhow::Result<Response> {
+	// Don't let requests escape the web root
+	if segments
+		.iter()
+		.any(|segment| segment.starts_with('.') || segment.contains('\\'))
+	{
+		return Ok(Response::error(404, "Not found"));
+	}
+
+	let mut path = PathBuf::from(web_root);
+	path.extend(segments);
+	if segments.is_empty()
+		|| tokio::fs::metadata(&path)
+			.await
+			.is_ok_and(|meta| meta.is_dir())
+	{
+		path.push("index.html");
+	}
+
+	match tokio::fs::read(&path).await {
+		Ok(data) => Ok(Response::new(200, content_type(&path), data)),
+		Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Response::error(404, "Not found")),
+		Err(e) => Err(e.into()),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn route_path(path: &str) -> Route<'_> {
+		let segments: Vec<&str> = path
+			.split('/')
+			.filter(|segment| !segment.is_empty())
+			.collect();
+		route(&segments)
+	}
+
+	#[test]
+	fn request_path_drops_query_string() {
+		let request =
+			Request::parse(b"GET /jour/10/1/2.png?v=3 HTTP/1.1\r\nIf-None-Match: \"5\"\r\n\r\n").unwrap();
+		assert_eq!(request.path, "/jour/10/1/2.png");
+		assert_eq!(request.header("if-none-match"), Some("\"5\""));
+	}
+
+	#[test]
+	fn api_routes() {
+		assert_eq!(route_path("/api/rooms"), Route::Rooms);
+		assert_eq!(route_path("/api/rooms/jour/"), Route::Room("jour"));
+		assert_eq!(route_path("/api/unknown"), Route::UnknownApi);
+	}
+
+	#[test]
+	fn tile_routes() {
+		assert_eq!(
+			route_path("/jour/10/-1/2.png"),
+			Route::Tile {
+				room_name: "jour",
+				layer: TileLayer::from_zoom(10),
+				x: "-1",
+				y: "2.png",
+			}
+		);
+		assert_eq!(
+			route_path("/api/rooms/jour/previews/3/0/0.png"),
+			Route::Tile {
+				room_name: "jour",
+				layer: TileLayer::from_preview_zoom(3),
+				x: "0",
+				y: "0.png",
+			}
+		);
+
+		// Out of range zoom levels are routed as tiles, and answered with 404
+		assert!(matches!(
+			route_path("/jour/11/0/0.png"),
+			Route::Tile { layer: None, .. }
+		));
+		assert!(matches!(
+			route_path("/api/rooms/jour/previews/0/0/0.png"),
+			Route::Tile { layer: None, .. }
+		));
+	}
+
+	#[test]
+	fn other_paths_are_static_files() {
+		assert_eq!(route_path("/"), Route::Static);
+		assert_eq!(route_path("/index.html"), Route::Static);
+		assert_eq!(route_path("/jour/10/0/0"), Route::Static);
+		assert_eq!(route_path("/jour/zoom/0/0.png"), Route::Static);
+		assert_eq!(route_path("/assets/js/0/app.png"), Route::Static);
+	}
+}
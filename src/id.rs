@@ -102,18 +102,21 @@ macro_rules! gen_id {
 				handle
 			}
 
-			pub fn remove(&mut self, handle: &$handle_name) {
+			// Returns false if the handle was removed already
+			pub fn remove(&mut self, handle: &$handle_name) -> bool {
 				// Out of bounds, ignore
 				if handle.idx as usize >= self.vec.len() {
-					return;
+					return false;
 				}
 
 				// Remove only if the generation matches
 				if let Some(cell) = &self.vec[handle.idx as usize] {
 					if cell.generation == handle.generation {
 						self.vec[handle.idx as usize] = None;
+						return true;
 					}
 				}
+				false
 			}
 
 			pub fn get(&self, handle: &$handle_name) -> Option<&$instance_name> {
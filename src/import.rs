@@ -0,0 +1,71 @@
+use std::{fs::File, io::BufReader};
+
+use glam::IVec2;
+
+use crate::limits;
+
+const IMPORT_DIR: &str = "imports";
+
+pub struct ImportedImage {
+	pub size: IVec2,
+	pub rgba: Vec<u8>,
+}
+
+fn to_rgba(color_type: png::ColorType, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+	let rgba = match color_type {
+		png::ColorType::Rgba => data.to_vec(),
+		png::ColorType::Rgb => data
+			.chunks_exact(3)
+			.flat_map(|p| [p[0], p[1], p[2], 255])
+			.collect(),
+		png::ColorType::GrayscaleAlpha => data
+			.chunks_exact(2)
+			.flat_map(|p| [p[0], p[0], p[0], p[1]])
+			.collect(),
+		png::ColorType::Grayscale => data.iter().flat_map(|v| [*v, *v, *v, 255]).collect(),
+		png::ColorType::Indexed => anyhow::bail!("Unexpected indexed color type"), // Expanded by the decoder
+	};
+	Ok(rgba)
+}
+
+// Files are only read from the imports directory, path separators are not allowed
+fn get_path(file_name: &str) -> anyhow::Result<String> {
+	if file_name.is_empty()
+		|| file_name.starts_with('.')
+		|| !file_name
+			.chars()
+			.all(|ch| ch.is_ascii_alphanumeric() || ch == '_' || ch == '-' || ch == '.')
+	{
+		anyhow::bail!("Invalid file name");
+	}
+	Ok(format!("{IMPORT_DIR}/{file_name}"))
+}
+
+fn load_png(path: &str) -> anyhow::Result<ImportedImage> {
+	let mut decoder = png::Decoder::new(BufReader::new(File::open(path)?));
+	decoder.set_transformations(png::Transformations::normalize_to_color8());
+	let mut reader = decoder.read_info()?;
+
+	// Check before decoding anything
+	let (width, height) = (reader.info().width, reader.info().height);
+	if u64::from(width) * u64::from(height) > u64::from(limits::IMPORT_AREA_MAX_PX) {
+		anyhow::bail!(
+			"Image too large (max {} pixels)",
+			limits::IMPORT_AREA_MAX_PX
+		);
+	}
+
+	let mut buf = vec![0; reader.output_buffer_size()];
+	let info = reader.next_frame(&mut buf)?;
+
+	Ok(ImportedImage {
+		size: IVec2::new(info.width as i32, info.height as i32),
+		rgba: to_rgba(info.color_type, &buf[..info.buffer_size()])?,
+	})
+}
+
+// Load a PNG image from the imports directory, converted to 8-bit RGBA
+pub async fn load_image(file_name: &str) -> anyhow::Result<ImportedImage> {
+	let path = get_path(file_name)?;
+	tokio::task::spawn_blocking(move || load_png(&path)).await?
+}
@@ -12,6 +12,9 @@ pub const ROOM_NAME_LEN_MAX: u8 = 24;
 pub const NICK_NAME_LEN_MIN: u8 = 3;
 pub const NICK_NAME_LEN_MAX: u8 = 24;
 
+pub const PASSWORD_LEN_MIN: u8 = 8;
+pub const PASSWORD_LEN_MAX: u8 = 128;
+
 pub const BOUNDARY_ZOOM_MIN: f32 = 0.45;
 
 pub const CHUNK_SIZE_PX: u32 = 256;
@@ -21,4 +24,12 @@ pub const PREVIEW_SYSTEM_LAYER_COUNT: u8 = 10;
 
 pub const FLOODFILL_MAX_DISTANCE: u32 = 300;
 
+pub const EXPORT_AREA_MAX_PX: u32 = 4096 * 4096;
+
+pub const IMPORT_AREA_MAX_PX: u32 = 2048 * 2048;
+
+pub const ROLLBACK_AREA_MAX_PX: u32 = 1024 * 1024;
+
+pub const HISTORY_MAX_BYTES_DEFAULT: usize = 4 * 1024 * 1024;
+
 pub const MIN_ZOOM: f32 = 0.45;
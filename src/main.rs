@@ -9,56 +9,83 @@
 #![allow(clippy::cast_precision_loss)]
 #![allow(clippy::cast_lossless)]
 
-use std::{collections::VecDeque, sync::Arc};
+use std::{collections::VecDeque, net::IpAddr, sync::Arc, time::Duration};
 
 use futures_util::{
 	stream::{SplitSink, SplitStream},
-	StreamExt,
+	SinkExt, StreamExt,
 };
 
 use server::ServerMutex;
 use session::{SessionHandle, SessionInstanceMutex};
 use tokio::{
 	net::{TcpListener, TcpStream},
+	signal::unix::{signal as unix_signal, Signal as UnixSignal, SignalKind},
 	sync::Mutex,
 };
 
 use tokio_util::sync::CancellationToken;
 use tokio_websockets::{Message, ServerBuilder, WebSocketStream};
 
-use crate::{server::Server, session::SessionInstance};
+use crate::{
+	account::AccountSystem,
+	ban_system::BanSystem,
+	connection_limit::{ConnectionLimiter, ConnectionPermit},
+	http::Rewind,
+	server::Server,
+	session::SessionInstance,
+	tls::{ServerStream, TlsState},
+};
 
 extern crate pretty_env_logger;
 
+mod account;
+mod ban_system;
 mod canvas_cache;
 mod chunk;
 mod command;
 mod compression;
 mod config;
+mod connection_limit;
 mod database;
 mod event_queue;
+mod export;
+mod history;
+mod http;
 mod id;
+mod import;
 mod limits;
+mod metrics;
+mod migration;
 mod packet_client;
 mod packet_server;
 mod pixel;
+mod plugin_system;
 mod preview_system;
+mod rate_limit;
+mod region_system;
+mod role_system;
 mod room;
+mod room_tool;
 mod serial_generator;
 mod server;
 mod session;
 mod signal;
+mod storage;
+mod tile_server;
 mod time;
+mod tls;
 mod tool;
 mod util;
 
-pub type Connection = WebSocketStream<TcpStream>;
-pub type ConnectionWriter = SplitSink<WebSocketStream<TcpStream>, Message>;
-pub type ConnectionReader = SplitStream<WebSocketStream<TcpStream>>;
+pub type ConnectionStream = Rewind<ServerStream>;
+pub type Connection = WebSocketStream<ConnectionStream>;
+pub type ConnectionWriter = SplitSink<Connection, Message>;
+pub type ConnectionReader = SplitStream<Connection>;
 pub type ConnectionMutex = Arc<Mutex<Connection>>;
 
 async fn connection_read_packet(
-	reader: &mut SplitStream<WebSocketStream<TcpStream>>,
+	reader: &mut ConnectionReader,
 	session_handle: &SessionHandle,
 	server_mtx: &ServerMutex,
 	session_mtx: &SessionInstanceMutex,
@@ -91,16 +118,168 @@ async fn connection_read_packet(
 	Ok(())
 }
 
-async fn task_connection(tcp_conn: TcpStream, server_mtx: ServerMutex) -> anyhow::Result<()> {
-	let connection = ServerBuilder::new().accept(tcp_conn).await?;
-	let (writer, mut reader) = connection.1.split();
+// Kick the client right after the handshake, before a session is created
+async fn reject_connection(writer: &mut ConnectionWriter, cause: &str) -> anyhow::Result<()> {
+	let packet = packet_server::prepare_packet_kick(cause);
+	writer.send(Message::binary(packet.data)).await?;
+	writer.close().await?;
+	Ok(())
+}
+
+enum AcceptedConnection {
+	WebSocket {
+		writer: ConnectionWriter,
+		reader: ConnectionReader,
+		ip: IpAddr, // Client address, not the address of a trusted proxy
+		permit: ConnectionPermit,
+	},
+	// Plain HTTP request for the web client or the API
+	Http {
+		stream: ServerStream,
+		request: http::Request,
+		permit: ConnectionPermit,
+	},
+}
+
+enum Handshake {
+	WebSocket(Connection),
+	Http(ServerStream, http::Request),
+}
+
+// Terminates TLS if enabled
+async fn accept_stream(
+	tcp_conn: TcpStream,
+	tls: Option<&TlsState>,
+) -> anyhow::Result<ServerStream> {
+	Ok(match tls {
+		Some(tls) => tls.accept(tcp_conn).await?,
+		None => ServerStream::Plain(tcp_conn),
+	})
+}
+
+// Reads the request head and upgrades to a WebSocket if requested, otherwise leaves the request to the HTTP server.
+// Also returns the X-Forwarded-For header of the request.
+async fn handshake(
+	tcp_conn: TcpStream,
+	tls: Option<&TlsState>,
+) -> anyhow::Result<(Handshake, Option<String>)> {
+	let mut stream = accept_stream(tcp_conn, tls).await?;
+	let (request, head) = http::read_request(&mut stream).await?;
+	let forwarded_for = request.header("x-forwarded-for").map(String::from);
+
+	if !request.is_websocket_upgrade() {
+		return Ok((Handshake::Http(stream, request), forwarded_for));
+	}
+
+	let (_, connection) = ServerBuilder::new()
+		.accept(Rewind::new(head, stream))
+		.await?;
+	Ok((Handshake::WebSocket(connection), forwarded_for))
+}
+
+// Performs the TLS and WebSocket handshakes within the connection limits. Returns None if the connection was rejected.
+async fn accept_connection(
+	tcp_conn: TcpStream,
+	peer_ip: IpAddr,
+	limiter: &Arc<ConnectionLimiter>,
+	tls: Option<&TlsState>,
+) -> anyhow::Result<Option<AcceptedConnection>> {
+	// Connections of trusted proxies are counted after the handshake, once the client address is known
+	let mut permit: Option<ConnectionPermit> = None;
+	if !limiter.is_trusted_proxy(peer_ip) {
+		match limiter.acquire(peer_ip) {
+			Ok(acquired) => permit = Some(acquired),
+			Err(rejection) => {
+				log::info!("Rejecting connection from {peer_ip}: {rejection}");
+				return Ok(None);
+			}
+		}
+	}
+
+	let Ok(handshake) = tokio::time::timeout(
+		Duration::from_secs(limiter.limits().handshake_timeout_s),
+		handshake(tcp_conn, tls),
+	)
+	.await
+	else {
+		log::info!("Handshake with {peer_ip} timed out");
+		return Ok(None);
+	};
+
+	let (handshake, forwarded_for) = handshake?;
+	let ip = limiter.resolve_client_ip(peer_ip, forwarded_for.as_deref());
+
+	let permit = permit.map_or_else(|| limiter.acquire(ip), Ok);
+
+	match handshake {
+		Handshake::WebSocket(connection) => {
+			let (mut writer, reader) = connection.split();
+			match permit {
+				Ok(permit) => Ok(Some(AcceptedConnection::WebSocket {
+					writer,
+					reader,
+					ip,
+					permit,
+				})),
+				Err(rejection) => {
+					log::info!("Rejecting connection from {ip}: {rejection}");
+					reject_connection(&mut writer, &rejection.to_string()).await?;
+					Ok(None)
+				}
+			}
+		}
+		Handshake::Http(mut stream, request) => match permit {
+			Ok(permit) => Ok(Some(AcceptedConnection::Http {
+				stream,
+				request,
+				permit,
+			})),
+			Err(rejection) => {
+				log::info!("Rejecting HTTP request from {ip}: {rejection}");
+				http::respond_error(&mut stream, 503, &rejection.to_string()).await?;
+				Ok(None)
+			}
+		},
+	}
+}
+
+async fn task_connection(
+	tcp_conn: TcpStream,
+	peer_ip: IpAddr,
+	server_mtx: ServerMutex,
+	limiter: Arc<ConnectionLimiter>,
+	tls: Option<Arc<TlsState>>,
+) -> anyhow::Result<()> {
+	let (mut writer, mut reader, ip, _permit) =
+		match accept_connection(tcp_conn, peer_ip, &limiter, tls.as_deref()).await? {
+			None => return Ok(()),
+			Some(AcceptedConnection::Http {
+				mut stream,
+				request,
+				permit: _permit,
+			}) => return http::serve(&mut stream, &request, &server_mtx).await,
+			Some(AcceptedConnection::WebSocket {
+				writer,
+				reader,
+				ip,
+				permit, // Released when the connection ends
+			}) => (writer, reader, ip, permit),
+		};
+
+	let bans = server_mtx.lock().await.bans.clone();
+	let ban = bans.lock().await.check(Some(ip), None);
+	if let Some(ban) = ban {
+		log::info!("Rejecting connection from banned IP {ip}");
+		return reject_connection(&mut writer, &ban.describe()).await;
+	}
 
-	let mut server = server_mtx.lock().await;
 	let cancel_token = CancellationToken::new();
 
 	log::trace!("Creating new session");
-	let (session_handle, session_mtx) = server.create_session(cancel_token.clone());
-	drop(server);
+	let (session_handle, session_mtx) = server_mtx
+		.lock()
+		.await
+		.create_session(cancel_token.clone(), ip);
 	log::info!("Created session with ID {}", session_handle.id());
 
 	{
@@ -122,12 +301,26 @@ async fn task_connection(tcp_conn: TcpStream, server_mtx: ServerMutex) -> anyhow
 		);
 	}
 
+	// Sessions which don't join a room in time are disconnected, so they can't hold a slot forever
+	let announce_deadline =
+		tokio::time::sleep(Duration::from_secs(limiter.limits().announce_timeout_s));
+	tokio::pin!(announce_deadline);
+	let mut announce_checked = false;
+
 	loop {
 		tokio::select! {
 			() = cancel_token.cancelled() => {
 				log::info!("Got cancel token, freeing session");
 				break;
 			}
+			() = &mut announce_deadline, if !announce_checked => {
+				announce_checked = true;
+				let announced = session_mtx.lock().await.is_announced();
+				if !announced {
+					log::info!("Session {} didn't announce in time", session_handle.id());
+					SessionInstance::disconnect(Arc::downgrade(&session_mtx), String::from("Announce timeout"));
+				}
+			}
 			res = connection_read_packet(&mut reader, &session_handle, &server_mtx, &session_mtx) => {
 				// exit loop on error
 				if let Err(e) = res {
@@ -144,9 +337,11 @@ async fn task_connection(tcp_conn: TcpStream, server_mtx: ServerMutex) -> anyhow
 
 	let weak_session = Arc::downgrade(&session_mtx);
 
-	server = server_mtx.lock().await;
-	server.remove_session(&session_handle).await;
-	drop(server);
+	server_mtx
+		.lock()
+		.await
+		.remove_session(&session_handle)
+		.await;
 	drop(session_mtx);
 
 	//for debugging purposes
@@ -160,13 +355,20 @@ async fn task_connection(tcp_conn: TcpStream, server_mtx: ServerMutex) -> anyhow
 	Ok(())
 }
 
-async fn server_listener_loop(listener: TcpListener, server: ServerMutex) -> anyhow::Result<()> {
-	while let Ok((tcp_conn, _)) = listener.accept().await {
+async fn server_listener_loop(
+	listener: TcpListener,
+	server: ServerMutex,
+	limiter: Arc<ConnectionLimiter>,
+	tls: Option<Arc<TlsState>>,
+) -> anyhow::Result<()> {
+	while let Ok((tcp_conn, addr)) = listener.accept().await {
 		let s = server.clone();
+		let limiter = limiter.clone();
+		let tls = tls.clone();
 		tokio::task::Builder::new()
 			.name("Listener task")
 			.spawn(async move {
-				if let Err(e) = task_connection(tcp_conn, s).await {
+				if let Err(e) = task_connection(tcp_conn, addr.ip(), s, limiter, tls).await {
 					log::error!("task_processor: {e}");
 				}
 			})
@@ -176,23 +378,65 @@ async fn server_listener_loop(listener: TcpListener, server: ServerMutex) -> any
 	Ok(())
 }
 
+// Resolves on SIGTERM (systemd, Docker) or SIGINT (Ctrl+C)
+async fn shutdown_signal(terminate: &mut UnixSignal, interrupt: &mut UnixSignal) -> &'static str {
+	tokio::select! {
+		_ = terminate.recv() => "SIGTERM",
+		_ = interrupt.recv() => "SIGINT",
+	}
+}
+
 async fn task_listener(listener: TcpListener, config: config::Config) -> anyhow::Result<()> {
 	let cancel_token = CancellationToken::new();
 
 	let enable_console = config.enable_console.unwrap_or(false);
+	let limiter = ConnectionLimiter::new(config.connection_limits.clone().unwrap_or_default());
 
-	let server = Server::new(config, cancel_token.clone());
+	let tls = match (&config.tls_cert_path, &config.tls_key_path) {
+		(Some(cert_path), Some(key_path)) => {
+			let tls = TlsState::new(cert_path.clone(), key_path.clone())?;
+			tls.clone().spawn_reload_on_sighup()?;
+			Some(tls)
+		}
+		(None, None) => None,
+		_ => anyhow::bail!("Both tls_cert_path and tls_key_path must be set to enable TLS"),
+	};
+
+	if let Some(metrics_listen) = &config.metrics_listen {
+		metrics::spawn_listener(metrics_listen).await?;
+	}
+
+	let accounts = Arc::new(AccountSystem::new()?);
+	let bans = Arc::new(Mutex::new(BanSystem::new()?));
+	let server = Server::new(config, accounts, bans, cancel_token.clone());
 
 	let cancel_token_command = CancellationToken::new();
 	if enable_console {
 		command::start(server.clone(), cancel_token_command.clone());
 	}
 
+	let mut terminate = unix_signal(SignalKind::terminate())?;
+	let mut interrupt = unix_signal(SignalKind::interrupt())?;
+
 	tokio::select! {
 		() = cancel_token.cancelled() => {
 			log::info!("Got cancel token, stopping server");
 		}
-		res = server_listener_loop(listener, server.clone()) => {
+		name = shutdown_signal(&mut terminate, &mut interrupt) => {
+			log::info!("Got {name}, saving and stopping server");
+
+			// Don't make the user wait for a stuck shutdown
+			tokio::spawn(async move {
+				let name = shutdown_signal(&mut terminate, &mut interrupt).await;
+				log::warn!("Got {name} again, exiting without saving");
+				std::process::exit(1);
+			});
+
+			if let Err(e) = Server::save_and_exit(&server).await {
+				log::error!("Cannot exit gracefully: {e}.");
+			}
+		}
+		res = server_listener_loop(listener, server.clone(), limiter, tls) => {
 			// exit loop on error
 			if let Err(e) = res {
 				log::error!("Listener error: {e}");
@@ -234,6 +478,20 @@ fn main() {
 		let mut args: VecDeque<String> = std::env::args().collect();
 		args.pop_front(); // Ignore program path
 
+		// Offline room tooling, doesn't start the server
+		if args.front().map(String::as_str) == Some("room") {
+			args.pop_front();
+			if std::env::var_os("RUST_LOG").is_none() {
+				std::env::set_var("RUST_LOG", "warn");
+			}
+			pretty_env_logger::init();
+			if let Err(e) = runtime.block_on(room_tool::run(args)) {
+				log::error!("{e}");
+				std::process::exit(1);
+			}
+			return;
+		}
+
 		while let Some(arg) = args.pop_front() {
 			match arg.as_str() {
 				"--console-subscriber" => {
@@ -245,7 +503,7 @@ fn main() {
 			}
 		}
 
-		std::env::set_var("RUST_LOG", "trace");
+		std::env::set_var("RUST_LOG", "trace,rustls=info"); // rustls traces every handshake
 		pretty_env_logger::init_timed();
 
 		if let Err(e) = runtime.block_on(run()) {
@@ -0,0 +1,367 @@
+use std::{
+	fmt::Write as _,
+	sync::{
+		atomic::{AtomicI64, AtomicU64, Ordering},
+		Arc, Weak,
+	},
+	time::Duration,
+};
+
+use parking_lot::Mutex as SyncMutex;
+
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::{
+	http::{self, Response},
+	limits,
+};
+
+// Server-wide counters and gauges, exposed in the Prometheus text exposition format.
+// Updated lock-free from anywhere, so scraping never waits for a stalled room.
+
+pub struct Counter(AtomicU64);
+
+impl Counter {
+	const fn new() -> Self {
+		Self(AtomicU64::new(0))
+	}
+
+	pub fn add(&self, value: u64) {
+		self.0.fetch_add(value, Ordering::Relaxed);
+	}
+
+	fn get(&self) -> u64 {
+		self.0.load(Ordering::Relaxed)
+	}
+}
+
+pub struct Gauge(AtomicI64);
+
+impl Gauge {
+	const fn new() -> Self {
+		Self(AtomicI64::new(0))
+	}
+
+	pub fn inc(&self) {
+		self.add(1);
+	}
+
+	pub fn dec(&self) {
+		self.sub(1);
+	}
+
+	pub fn add(&self, value: usize) {
+		self.0.fetch_add(value as i64, Ordering::Relaxed);
+	}
+
+	pub fn sub(&self, value: usize) {
+		self.0.fetch_sub(value as i64, Ordering::Relaxed);
+	}
+
+	fn get(&self) -> i64 {
+		self.0.load(Ordering::Relaxed)
+	}
+}
+
+// One gauge per instance of something (e.g. the send queue of every session), exported as a sum, a maximum and a count,
+// so a single stalled instance doesn't hide in the total
+pub struct GaugeSet {
+	gauges: SyncMutex<Vec<Weak<Gauge>>>,
+}
+
+struct GaugeSetStats {
+	count: usize,
+	sum: i64,
+	max: i64,
+}
+
+impl GaugeSet {
+	const fn new() -> Self {
+		Self {
+			gauges: SyncMutex::new(Vec::new()),
+		}
+	}
+
+	// The gauge is removed from the set once dropped
+	pub fn register(&self) -> Arc<Gauge> {
+		let gauge = Arc::new(Gauge::new());
+		let mut gauges = self.gauges.lock();
+		gauges.retain(|gauge| gauge.strong_count() > 0);
+		gauges.push(Arc::downgrade(&gauge));
+		gauge
+	}
+
+	fn stats(&self) -> GaugeSetStats {
+		let mut gauges = self.gauges.lock();
+		gauges.retain(|gauge| gauge.strong_count() > 0);
+
+		let values: Vec<i64> = gauges
+			.iter()
+			.filter_map(Weak::upgrade)
+			.map(|gauge| gauge.get())
+			.collect();
+		GaugeSetStats {
+			count: values.len(),
+			sum: values.iter().sum(),
+			max: values.iter().copied().max().unwrap_or(0),
+		}
+	}
+}
+
+// Count and total duration of an operation
+pub struct Summary {
+	count: AtomicU64,
+	sum_us: AtomicU64,
+}
+
+impl Summary {
+	const fn new() -> Self {
+		Self {
+			count: AtomicU64::new(0),
+			sum_us: AtomicU64::new(0),
+		}
+	}
+
+	pub fn observe(&self, duration: Duration) {
+		self.count.fetch_add(1, Ordering::Relaxed);
+		self
+			.sum_us
+			.fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+	}
+}
+
+pub struct Metrics {
+	pub sessions: Gauge,
+	pub rooms: Gauge,
+	pub chunks: Gauge,
+	pub pixels_written: Counter,
+	pub preview_queue: [Gauge; limits::PREVIEW_SYSTEM_LAYER_COUNT as usize], // Indexed by zoom - 1
+	pub autosave: Summary,
+	pub database_lock_wait: Summary,
+	pub database_query: Summary,
+	pub session_send_queue: GaugeSet,
+	pub preview_update_queue: GaugeSet,
+}
+
+pub static METRICS: Metrics = Metrics {
+	sessions: Gauge::new(),
+	rooms: Gauge::new(),
+	chunks: Gauge::new(),
+	pixels_written: Counter::new(),
+	preview_queue: [const { Gauge::new() }; limits::PREVIEW_SYSTEM_LAYER_COUNT as usize],
+	autosave: Summary::new(),
+	database_lock_wait: Summary::new(),
+	database_query: Summary::new(),
+	session_send_queue: GaugeSet::new(),
+	preview_update_queue: GaugeSet::new(),
+};
+
+fn write_header(out: &mut String, name: &str, kind: &str, help: &str) {
+	let _ = writeln!(out, "# HELP {name} {help}");
+	let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+fn write_gauge(out: &mut String, name: &str, help: &str, gauge: &Gauge) {
+	write_header(out, name, "gauge", help);
+	let _ = writeln!(out, "{name} {}", gauge.get());
+}
+
+fn write_summary(out: &mut String, name: &str, help: &str, summary: &Summary) {
+	write_header(out, name, "summary", help);
+	let sum_s = summary.sum_us.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+	let _ = writeln!(out, "{name}_sum {sum_s}");
+	let _ = writeln!(
+		out,
+		"{name}_count {}",
+		summary.count.load(Ordering::Relaxed)
+	);
+}
+
+fn write_queue_depths(out: &mut String, queues: &[(&str, GaugeSetStats)]) {
+	write_header(
+		out,
+		"multipixel_event_queue_depth",
+		"gauge",
+		"Events waiting in all queues of a kind",
+	);
+	for (queue, stats) in queues {
+		let _ = writeln!(
+			out,
+			"multipixel_event_queue_depth{{queue=\"{queue}\"}} {}",
+			stats.sum
+		);
+	}
+
+	write_header(
+		out,
+		"multipixel_event_queue_depth_max",
+		"gauge",
+		"Events waiting in the fullest queue of a kind",
+	);
+	for (queue, stats) in queues {
+		let _ = writeln!(
+			out,
+			"multipixel_event_queue_depth_max{{queue=\"{queue}\"}} {}",
+			stats.max
+		);
+	}
+
+	write_header(
+		out,
+		"multipixel_event_queues",
+		"gauge",
+		"Existing queues of a kind",
+	);
+	for (queue, stats) in queues {
+		let _ = writeln!(
+			out,
+			"multipixel_event_queues{{queue=\"{queue}\"}} {}",
+			stats.count
+		);
+	}
+}
+
+impl Metrics {
+	pub fn render(&self) -> String {
+		let mut out = String::new();
+
+		write_gauge(
+			&mut out,
+			"multipixel_sessions",
+			"Connected sessions",
+			&self.sessions,
+		);
+		write_gauge(
+			&mut out,
+			"multipixel_rooms_loaded",
+			"Loaded rooms",
+			&self.rooms,
+		);
+		write_gauge(
+			&mut out,
+			"multipixel_chunks_loaded",
+			"Chunks loaded in all rooms",
+			&self.chunks,
+		);
+
+		write_header(
+			&mut out,
+			"multipixel_pixels_written_total",
+			"counter",
+			"Pixels written to chunks",
+		);
+		let _ = writeln!(
+			out,
+			"multipixel_pixels_written_total {}",
+			self.pixels_written.get()
+		);
+
+		write_header(
+			&mut out,
+			"multipixel_preview_queue_length",
+			"gauge",
+			"Previews waiting to be regenerated",
+		);
+		for (idx, gauge) in self.preview_queue.iter().enumerate() {
+			let _ = writeln!(
+				out,
+				"multipixel_preview_queue_length{{zoom=\"{}\"}} {}",
+				idx + 1,
+				gauge.get()
+			);
+		}
+
+		write_summary(
+			&mut out,
+			"multipixel_autosave_duration_seconds",
+			"Time spent saving modified chunks",
+			&self.autosave,
+		);
+		write_summary(
+			&mut out,
+			"multipixel_database_lock_wait_seconds",
+			"Time spent waiting for a room database",
+			&self.database_lock_wait,
+		);
+		write_summary(
+			&mut out,
+			"multipixel_database_query_duration_seconds",
+			"Time spent in room database queries",
+			&self.database_query,
+		);
+
+		write_queue_depths(
+			&mut out,
+			&[
+				("session_send", self.session_send_queue.stats()),
+				("preview_update", self.preview_update_queue.stats()),
+			],
+		);
+
+		out
+	}
+}
+
+async fn serve_connection(mut stream: TcpStream) -> anyhow::Result<()> {
+	let (request, _) =
+		tokio::time::timeout(Duration::from_secs(10), http::read_request(&mut stream)).await??;
+
+	let response = if request.path == "/metrics" {
+		Response::new(
+			200,
+			"text/plain; version=0.0.4; charset=utf-8",
+			METRICS.render().into_bytes(),
+		)
+	} else {
+		Response::error(404, "Not found")
+	};
+
+	response
+		.write(&mut stream, request.method != "HEAD")
+		.await?;
+	Ok(())
+}
+
+// Serves /metrics on a separate address, so it doesn't need to be exposed publicly
+pub async fn spawn_listener(listen_address: &str) -> anyhow::Result<()> {
+	let listener = TcpListener::bind(listen_address).await?;
+	log::info!("Serving metrics at http://{listen_address}/metrics");
+
+	tokio::task::Builder::new()
+		.name("Metrics listener task")
+		.spawn(async move {
+			while let Ok((tcp_conn, _)) = listener.accept().await {
+				tokio::task::Builder::new()
+					.name("Metrics connection task")
+					.spawn(async move {
+						if let Err(e) = serve_connection(tcp_conn).await {
+							log::debug!("Metrics request failed: {e}");
+						}
+					})
+					.unwrap();
+			}
+		})?;
+
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn gauge_set_reports_each_live_gauge() {
+		let set = GaugeSet::new();
+		let first = set.register();
+		let second = set.register();
+		first.add(3);
+		second.add(5);
+
+		let stats = set.stats();
+		assert_eq!((stats.count, stats.sum, stats.max), (2, 8, 5));
+
+		drop(second);
+		let stats = set.stats();
+		assert_eq!((stats.count, stats.sum, stats.max), (1, 3, 3));
+	}
+}
@@ -0,0 +1,433 @@
+use glam::U8Vec2;
+use rusqlite::params;
+
+use crate::{chunk::layer::LayerRGBA, compression::decompress_lz4, pixel::ColorRGBA};
+
+// Schema migrations of room databases, applied in order when a room is opened.
+//
+// Each migration runs in its own transaction together with the version bump, so an interrupted
+// upgrade leaves the database at the last completed version. The database file is copied
+// to `{path}.v{version}.bak` before the first migration runs.
+//
+// New databases start with the base schema and run all migrations, so they always end up with the same schema
+// as upgraded ones. To change the schema, append a migration to MIGRATIONS, never modify or reorder existing ones.
+
+pub struct Migration {
+	pub version: u32, // Database version after this migration
+	pub description: &'static str,
+	run: fn(&rusqlite::Connection, &mut MigrationProgress) -> rusqlite::Result<()>,
+}
+
+const MIGRATIONS: &[Migration] = &[
+	Migration {
+		version: 1,
+		description: "convert chunks from RGB to RGBA",
+		run: migrate_to_version_1,
+	},
+	Migration {
+		version: 2,
+		description: "add undo history table",
+		run: migrate_to_version_2,
+	},
+	Migration {
+		version: 3,
+		description: "add protected regions table",
+		run: migrate_to_version_3,
+	},
+	Migration {
+		version: 4,
+		description: "add roles table",
+		run: migrate_to_version_4,
+	},
+	Migration {
+		version: 5,
+		description: "add properties table",
+		run: migrate_to_version_5,
+	},
+];
+
+pub const DATABASE_VERSION: u32 = MIGRATIONS[MIGRATIONS.len() - 1].version;
+
+// Forwards progress messages to the log (server) or stdout (room tool)
+pub struct MigrationProgress<'a> {
+	output: &'a mut dyn FnMut(String),
+	last_percent: Option<u32>,
+}
+
+impl<'a> MigrationProgress<'a> {
+	pub fn new(output: &'a mut dyn FnMut(String)) -> Self {
+		Self {
+			output,
+			last_percent: None,
+		}
+	}
+
+	pub fn message(&mut self, message: String) {
+		(self.output)(message);
+	}
+
+	// Reports every 10 percent of the current migration
+	pub fn step(&mut self, done: usize, total: usize) {
+		let percent = (done * 100 / total.max(1)) as u32 / 10 * 10;
+		if self.last_percent != Some(percent) {
+			self.last_percent = Some(percent);
+			self.message(format!("  {percent}% ({done}/{total})"));
+		}
+	}
+}
+
+pub struct MigrationReport {
+	pub from_version: u32,
+	pub applied: Vec<&'static Migration>,
+}
+
+pub fn get_version(conn: &rusqlite::Connection) -> rusqlite::Result<u32> {
+	conn.query_row("PRAGMA user_version", [], |row| row.get(0))
+}
+
+pub fn set_version(conn: &rusqlite::Connection, version: u32) -> rusqlite::Result<()> {
+	conn.execute_batch(&format!("PRAGMA user_version = {version}"))
+}
+
+fn get_backup_path(path: &str, version: u32) -> String {
+	format!("{path}.v{version}.bak")
+}
+
+// Consistent copy of the database, taken with the connection open
+fn backup(conn: &rusqlite::Connection, backup_path: &str) -> rusqlite::Result<()> {
+	if std::fs::exists(backup_path).unwrap_or(false) {
+		// Left by an earlier attempt which failed, the database is still at the same version
+		let _ = std::fs::remove_file(backup_path);
+	}
+	conn.execute("VACUUM INTO ?", params![backup_path])?;
+	Ok(())
+}
+
+fn announce(progress: &mut MigrationProgress, idx: usize, count: usize, migration: &Migration) {
+	progress.message(format!(
+		"[{}/{count}] Version {}: {}",
+		idx + 1,
+		migration.version,
+		migration.description
+	));
+}
+
+fn apply(
+	conn: &rusqlite::Connection,
+	migration: &Migration,
+	progress: &mut MigrationProgress,
+) -> rusqlite::Result<()> {
+	progress.last_percent = None;
+	(migration.run)(conn, progress)?;
+	set_version(conn, migration.version)
+}
+
+// Chunks and previews, as created before databases were versioned
+fn create_base_schema(conn: &rusqlite::Connection) -> rusqlite::Result<()> {
+	conn.execute_batch(
+		"CREATE TABLE chunk_data(x INT NOT NULL, y INT NOT NULL, data BLOB, modified INT64 NOT NULL, created INT64 NOT NULL, compression INT);
+		CREATE INDEX index_x on chunk_data(x);
+		CREATE INDEX index_y on chunk_data(y);
+		CREATE TABLE previews(x INT NOT NULL, y INT NOT NULL, zoom INT NOT NULL, data BLOB);
+		CREATE INDEX previews_index_x on previews(x);
+		CREATE INDEX previews_index_y on previews(y);",
+	)
+}
+
+// Sets up a new, empty database at DATABASE_VERSION. There is nothing to back up or report.
+pub fn initialize(conn: &mut rusqlite::Connection) -> rusqlite::Result<()> {
+	let mut output = |_message: String| {};
+	let mut progress = MigrationProgress::new(&mut output);
+
+	let transaction = conn.transaction()?;
+	create_base_schema(&transaction)?;
+	for migration in MIGRATIONS {
+		apply(&transaction, migration, &mut progress)?;
+	}
+	transaction.commit()
+}
+
+// Brings the database at `path` to DATABASE_VERSION. Databases created by a newer server are refused.
+// With `dry_run` all migrations run in a transaction which is rolled back afterwards, and no backup is made.
+pub fn migrate(
+	conn: &mut rusqlite::Connection,
+	path: &str,
+	dry_run: bool,
+	progress: &mut MigrationProgress,
+) -> anyhow::Result<MigrationReport> {
+	let from_version = get_version(conn)?;
+	if from_version > DATABASE_VERSION {
+		anyhow::bail!(
+			"Database {path} is at version {from_version}, this server supports up to version {DATABASE_VERSION}"
+		);
+	}
+
+	let pending: Vec<_> = MIGRATIONS
+		.iter()
+		.filter(|migration| migration.version > from_version)
+		.collect();
+
+	if pending.is_empty() {
+		return Ok(MigrationReport {
+			from_version,
+			applied: pending,
+		});
+	}
+
+	progress.message(format!(
+		"Updating database {path} from version {from_version} to version {DATABASE_VERSION}{}",
+		if dry_run { " (dry run)" } else { "" }
+	));
+
+	if dry_run {
+		// Later migrations depend on earlier ones, so they run in savepoints of a single transaction
+		let mut transaction = conn.transaction()?;
+		for (idx, migration) in pending.iter().enumerate() {
+			announce(progress, idx, pending.len(), migration);
+			let savepoint = transaction.savepoint()?;
+			apply(&savepoint, migration, progress)?;
+			savepoint.commit()?;
+		}
+		transaction.rollback()?;
+	} else {
+		let backup_path = get_backup_path(path, from_version);
+		backup(conn, &backup_path)?;
+		progress.message(format!("Backup saved to {backup_path}"));
+
+		for (idx, migration) in pending.iter().enumerate() {
+			announce(progress, idx, pending.len(), migration);
+			let transaction = conn.transaction()?;
+			apply(&transaction, migration, progress)?;
+			transaction.commit()?;
+		}
+	}
+
+	Ok(MigrationReport {
+		from_version,
+		applied: pending,
+	})
+}
+
+fn migrate_to_version_1(
+	conn: &rusqlite::Connection,
+	progress: &mut MigrationProgress,
+) -> rusqlite::Result<()> {
+	struct ChunkDataRow {
+		x: i32,
+		y: i32,
+		data: Vec<u8>,
+		modified: i64,
+		created: i64,
+		compression: i64,
+	}
+
+	progress.message(String::from("  removing previews"));
+	conn.execute("DELETE FROM previews", [])?;
+
+	progress.message(String::from(
+		"  loading all compressed rgb chunks into memory",
+	));
+
+	let old_data: Vec<_> = {
+		let mut stmt =
+			conn.prepare("SELECT x, y, data, modified, created, compression FROM chunk_data")?;
+		let res: Vec<_> = stmt
+			.query_map([], |row| {
+				Ok(ChunkDataRow {
+					x: row.get(0)?,
+					y: row.get(1)?,
+					data: row.get(2)?,
+					modified: row.get(3)?,
+					created: row.get(4)?,
+					compression: row.get(5)?,
+				})
+			})?
+			.flatten()
+			.collect();
+		res
+	};
+
+	progress.message(format!(
+		"  converting {} chunks from rgb to rgba",
+		old_data.len()
+	));
+
+	let old_data_len = old_data.len();
+
+	for (i, old_cell) in old_data.into_iter().enumerate() {
+		progress.step(i, old_data_len);
+
+		let Some(rgb) =
+			decompress_lz4(&old_cell.data, 256 * 256 * 3).filter(|rgb| rgb.len() >= 256 * 256 * 3)
+		else {
+			log::error!(
+				"failed to decompress chunk at {}x{}",
+				old_cell.x,
+				old_cell.y
+			);
+			continue;
+		};
+
+		let mut layer = LayerRGBA::new();
+		layer.alloc_transparent_black();
+
+		for y in 0..256 {
+			for x in 0..256 {
+				let offset = y * 256 * 3 + x * 3;
+
+				let red = rgb[offset];
+				let green = rgb[offset + 1];
+				let blue = rgb[offset + 2];
+
+				layer.set_pixel(
+					U8Vec2::new(x as u8, y as u8),
+					ColorRGBA {
+						r: red,
+						g: green,
+						b: blue,
+						a: 255,
+					},
+				);
+			}
+		}
+
+		let compressed = layer.compress_lz4();
+
+		conn.execute(
+			"UPDATE chunk_data SET data=? WHERE x=? AND y=? AND modified=? AND created=? AND compression=?",
+			params![compressed, old_cell.x, old_cell.y, old_cell.modified, old_cell.created, old_cell.compression],
+		)?;
+	}
+	progress.step(old_data_len, old_data_len);
+
+	Ok(())
+}
+
+// Tables of versions 2 to 5 were created on open before they were versioned, so databases
+// at version 1 may already have them
+
+fn migrate_to_version_2(
+	conn: &rusqlite::Connection,
+	_progress: &mut MigrationProgress,
+) -> rusqlite::Result<()> {
+	conn.execute_batch(
+		"CREATE TABLE IF NOT EXISTS history(identity TEXT NOT NULL, redo INT NOT NULL, idx INT NOT NULL, raw_size INT NOT NULL, data BLOB);
+		CREATE INDEX IF NOT EXISTS history_index_identity on history(identity);",
+	)
+}
+
+fn migrate_to_version_3(
+	conn: &rusqlite::Connection,
+	_progress: &mut MigrationProgress,
+) -> rusqlite::Result<()> {
+	conn.execute_batch(
+		"CREATE TABLE IF NOT EXISTS regions(name TEXT NOT NULL UNIQUE, owner TEXT NOT NULL, x1 INT NOT NULL, y1 INT NOT NULL, x2 INT NOT NULL, y2 INT NOT NULL, read_only INT NOT NULL, editors TEXT NOT NULL)",
+	)
+}
+
+fn migrate_to_version_4(
+	conn: &rusqlite::Connection,
+	_progress: &mut MigrationProgress,
+) -> rusqlite::Result<()> {
+	conn.execute_batch(
+		"CREATE TABLE IF NOT EXISTS roles(identity TEXT NOT NULL UNIQUE, role TEXT NOT NULL)",
+	)
+}
+
+fn migrate_to_version_5(
+	conn: &rusqlite::Connection,
+	_progress: &mut MigrationProgress,
+) -> rusqlite::Result<()> {
+	conn.execute_batch(
+		"CREATE TABLE IF NOT EXISTS properties(key TEXT NOT NULL UNIQUE, value TEXT NOT NULL)",
+	)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn schema(conn: &rusqlite::Connection) -> Vec<(String, String, Option<String>)> {
+		let mut stmt = conn
+			.prepare("SELECT type, name, sql FROM sqlite_master ORDER BY type, name")
+			.unwrap();
+		stmt
+			.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+			.unwrap()
+			.map(Result::unwrap)
+			.collect()
+	}
+
+	fn migrate_quietly(
+		conn: &mut rusqlite::Connection,
+		path: &str,
+	) -> anyhow::Result<MigrationReport> {
+		let mut output = |_message: String| {};
+		migrate(conn, path, false, &mut MigrationProgress::new(&mut output))
+	}
+
+	#[test]
+	fn new_and_migrated_databases_match() {
+		let dir = tempfile::tempdir().unwrap();
+
+		let mut created = rusqlite::Connection::open_in_memory().unwrap();
+		initialize(&mut created).unwrap();
+		assert_eq!(get_version(&created).unwrap(), DATABASE_VERSION);
+
+		let path = dir.path().join("old.db").to_string_lossy().into_owned();
+		let mut migrated = rusqlite::Connection::open(&path).unwrap();
+		create_base_schema(&migrated).unwrap();
+		let report = migrate_quietly(&mut migrated, &path).unwrap();
+		assert_eq!(report.from_version, 0);
+		assert_eq!(report.applied.len(), MIGRATIONS.len());
+		assert!(std::fs::exists(get_backup_path(&path, 0)).unwrap());
+
+		assert_eq!(schema(&created), schema(&migrated));
+	}
+
+	#[test]
+	fn unversioned_tables_are_kept() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("room.db").to_string_lossy().into_owned();
+
+		// Version 1 database with the roles table created on open, before it was versioned
+		let mut conn = rusqlite::Connection::open(&path).unwrap();
+		create_base_schema(&conn).unwrap();
+		set_version(&conn, 1).unwrap();
+		conn
+			.execute_batch(
+				"CREATE TABLE roles(identity TEXT NOT NULL UNIQUE, role TEXT NOT NULL);
+				INSERT INTO roles VALUES ('alice', 'moderator');",
+			)
+			.unwrap();
+
+		let report = migrate_quietly(&mut conn, &path).unwrap();
+		assert_eq!(report.from_version, 1);
+		assert_eq!(get_version(&conn).unwrap(), DATABASE_VERSION);
+		let role: String = conn
+			.query_row("SELECT role FROM roles WHERE identity='alice'", [], |row| {
+				row.get(0)
+			})
+			.unwrap();
+		assert_eq!(role, "moderator");
+
+		// Nothing left to do
+		assert!(migrate_quietly(&mut conn, &path)
+			.unwrap()
+			.applied
+			.is_empty());
+	}
+
+	#[test]
+	fn newer_database_is_refused() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("room.db").to_string_lossy().into_owned();
+
+		let mut conn = rusqlite::Connection::open(&path).unwrap();
+		initialize(&mut conn).unwrap();
+		set_version(&conn, DATABASE_VERSION + 1).unwrap();
+
+		assert!(migrate_quietly(&mut conn, &path).is_err());
+		assert_eq!(get_version(&conn).unwrap(), DATABASE_VERSION + 1);
+	}
+}
@@ -4,6 +4,8 @@ use binary_reader::BinaryReader;
 use glam::IVec2;
 use num_enum::TryFromPrimitive;
 
+use crate::account::Credentials;
+
 #[derive(Debug, Eq, PartialEq, TryFromPrimitive)]
 #[repr(u8)]
 pub enum ToolType {
@@ -22,7 +24,7 @@ pub enum ToolType {
 #[repr(u16)]
 pub enum ClientCmd {
 	Message = 1,  // u16 text_size, utf-8 text
-	Announce = 2, // u8 room_name_size, utf-8 room_name, u8 nickname_size, utf-8 nickname
+	Announce = 2, // u8 room_name_size, utf-8 room_name, u8 nickname_size, utf-8 nickname, optional: u8 auth_type (0 - guest, 1 - password, 2 - token), u8 secret_size, utf-8 secret, optional: u8 flags (see ANNOUNCE_FLAG_*)
 	Ping = 4,
 	CursorPos = 100, // s32 x, s32 y
 	CursorDown = 101,
@@ -30,11 +32,24 @@ pub enum ClientCmd {
 	Boundary = 103,
 	ChunksReceived = 104,
 	PreviewRequest = 105, // s32 previewX, s32 previewY, u8 zoom
+	ViewTimestamp = 106,  // u64 unix timestamp, 0 for live canvas
 	ToolType = 200,       // u8 type
 	ToolColor = 201,      // u8 red, u8 green, u8 blue
 	ToolSize = 202,       // u8 size,
 	ToolFlow = 203,       // u32 flow
 	Undo = 300,
+	Redo = 301,
+}
+
+impl ClientCmd {
+	// Drawing and chat, dropped when the session exceeds its packet rate limit.
+	// Other commands keep the session in sync with the client and are never dropped.
+	pub const fn is_throttleable(&self) -> bool {
+		matches!(
+			self,
+			Self::Message | Self::CursorPos | Self::CursorDown | Self::Undo | Self::Redo
+		)
+	}
 }
 
 pub fn read_string_u8(reader: &mut BinaryReader) -> anyhow::Result<String> {
@@ -49,19 +64,38 @@ pub fn read_string_u16(reader: &mut BinaryReader) -> anyhow::Result<String> {
 	Ok(String::from(std::str::from_utf8(str_data)?))
 }
 
+// Join as a spectator, which cannot draw
+pub const ANNOUNCE_FLAG_SPECTATOR: u8 = 1 << 0;
+
 pub struct PacketAnnounce {
 	pub room_name: String,
 	pub nick_name: String,
+	pub credentials: Credentials,
+	pub spectator: bool,
 }
 
 impl PacketAnnounce {
 	pub fn read(reader: &mut BinaryReader) -> anyhow::Result<Self> {
 		let room_name_raw = read_string_u8(reader)?;
 		let room_name = room_name_raw.to_lowercase();
+		let nick_name = read_string_u8(reader)?;
+
+		// Older clients don't send credentials at all
+		let credentials = match reader.read_u8() {
+			Err(_) | Ok(0) => Credentials::None,
+			Ok(1) => Credentials::Password(read_string_u8(reader)?),
+			Ok(2) => Credentials::Token(read_string_u8(reader)?),
+			Ok(auth_type) => anyhow::bail!("Invalid auth type {auth_type}"),
+		};
+
+		// Flags are optional as well
+		let flags = reader.read_u8().unwrap_or(0);
 
 		Ok(Self {
 			room_name,
-			nick_name: read_string_u8(reader)?,
+			nick_name,
+			credentials,
+			spectator: flags & ANNOUNCE_FLAG_SPECTATOR != 0,
 		})
 	}
 }
@@ -0,0 +1,449 @@
+use std::sync::Arc;
+
+use glam::IVec2;
+use mlua::{Function, IntoLuaMulti, Lua, Table, Variadic};
+use parking_lot::Mutex as SyncMutex;
+use tokio::{sync::Mutex, task::JoinHandle};
+
+use crate::{
+	chunk::{
+		cache::ChunkCache, chunk::ChunkPixelRGBA, system::ChunkSystemMutex, writer::ChunkWriterRGBA,
+	},
+	limits::CHUNK_SIZE_PX,
+	packet_server,
+	pixel::{ColorRGBA, GlobalPixelRGBA},
+	region_system::{RegionActor, RegionSystemMutex},
+	room::{RoomInstanceWeak, RoomRefs, RoomSessionData},
+};
+
+const PLUGINS_DIR: &str = "plugins";
+const EVENTS_REGISTRY_KEY: &str = "multipixel_events";
+const TICK_INTERVAL_MS: u64 = 50;
+
+#[derive(Clone)]
+pub enum PluginEvent {
+	Message(u32 /* session id */, String),
+	Command(u32 /* session id */, String),
+	UserJoin(u32 /* session id */),
+	UserLeave(u32 /* session id */),
+	UserMouseDown(u32 /* session id */),
+	Tick,
+}
+
+impl PluginEvent {
+	const fn name(&self) -> &'static str {
+		match self {
+			Self::Message(..) => "message",
+			Self::Command(..) => "command",
+			Self::UserJoin(_) => "user_join",
+			Self::UserLeave(_) => "user_leave",
+			Self::UserMouseDown(_) => "user_mouse_down",
+			Self::Tick => "tick",
+		}
+	}
+}
+
+// Data accessible from the Lua side during a single event dispatch
+#[derive(Default)]
+struct PluginContext {
+	sessions: Vec<RoomSessionData>,
+	queued_pixels: Vec<GlobalPixelRGBA>,
+}
+
+impl PluginContext {
+	fn get_session(&self, session_id: u32) -> Option<&RoomSessionData> {
+		self
+			.sessions
+			.iter()
+			.find(|session| session.handle.id() == session_id)
+	}
+}
+
+struct Plugin {
+	name: String,
+	lua: Lua,
+}
+
+impl Plugin {
+	fn load(name: &str, context: &Arc<SyncMutex<PluginContext>>) -> anyhow::Result<Self> {
+		let path = format!("{PLUGINS_DIR}/{name}/init.lua");
+		let code = std::fs::read_to_string(&path)?;
+
+		let lua = Lua::new();
+		lua.set_named_registry_value(EVENTS_REGISTRY_KEY, lua.create_table()?)?;
+		register_api(&lua, name, context)?;
+
+		lua.load(&code).set_name(path).exec()?;
+
+		let plugin = Self {
+			name: String::from(name),
+			lua,
+		};
+
+		plugin.call_global("onLoad")?;
+
+		Ok(plugin)
+	}
+
+	fn call_global(&self, function_name: &str) -> mlua::Result<()> {
+		if let Some(func) = self
+			.lua
+			.globals()
+			.get::<_, Option<Function>>(function_name)?
+		{
+			func.call::<_, ()>(())?;
+		}
+		Ok(())
+	}
+
+	fn has_listeners(&self, event_name: &str) -> bool {
+		let Ok(events) = self.lua.named_registry_value::<Table>(EVENTS_REGISTRY_KEY) else {
+			return false;
+		};
+
+		events
+			.get::<_, Option<Table>>(event_name)
+			.ok()
+			.flatten()
+			.is_some_and(|listeners| listeners.raw_len() > 0)
+	}
+
+	// Returns true if at least one listener has been called
+	fn call_event<'lua>(
+		&'lua self,
+		event_name: &str,
+		args: &(impl IntoLuaMulti<'lua> + Clone),
+	) -> mlua::Result<bool> {
+		let events: Table = self.lua.named_registry_value(EVENTS_REGISTRY_KEY)?;
+		let Some(listeners) = events.get::<_, Option<Table>>(event_name)? else {
+			return Ok(false);
+		};
+
+		let mut called = false;
+		for listener in listeners.sequence_values::<Function>() {
+			listener?.call::<_, ()>(args.clone())?;
+			called = true;
+		}
+
+		Ok(called)
+	}
+
+	fn dispatch(&self, event: &PluginEvent) -> mlua::Result<bool> {
+		let name = event.name();
+		match event {
+			PluginEvent::Message(session_id, text) | PluginEvent::Command(session_id, text) => {
+				self.call_event(name, &(*session_id, text.as_str()))
+			}
+			PluginEvent::UserJoin(session_id)
+			| PluginEvent::UserLeave(session_id)
+			| PluginEvent::UserMouseDown(session_id) => self.call_event(name, session_id),
+			PluginEvent::Tick => self.call_event(name, &()),
+		}
+	}
+}
+
+fn register_api(
+	lua: &Lua,
+	plugin_name: &str,
+	context: &Arc<SyncMutex<PluginContext>>,
+) -> mlua::Result<()> {
+	let server = lua.create_table()?;
+
+	server.set(
+		"addEvent",
+		lua.create_function(|lua, (event_name, func): (String, Function)| {
+			let events: Table = lua.named_registry_value(EVENTS_REGISTRY_KEY)?;
+			let listeners =
+				if let Some(listeners) = events.get::<_, Option<Table>>(event_name.as_str())? {
+					listeners
+				} else {
+					let listeners = lua.create_table()?;
+					events.set(event_name, listeners.clone())?;
+					listeners
+				};
+			listeners.raw_push(func)?;
+			Ok(())
+		})?,
+	)?;
+
+	let ctx = context.clone();
+	server.set(
+		"chatBroadcast",
+		lua.create_function(move |_, text: String| {
+			let packet = packet_server::prepare_packet_message(
+				packet_server::MessageType::PlainText,
+				"[PLUGIN]",
+				&text,
+			);
+			for session in &ctx.lock().sessions {
+				session.queue_send.send(packet.clone());
+			}
+			Ok(())
+		})?,
+	)?;
+
+	let ctx = context.clone();
+	server.set(
+		"userSendMessage",
+		lua.create_function(move |_, (session_id, text): (u32, String)| {
+			if let Some(session) = ctx.lock().get_session(session_id) {
+				session
+					.queue_send
+					.send(packet_server::prepare_packet_message(
+						packet_server::MessageType::PlainText,
+						"[PLUGIN]",
+						&text,
+					));
+			}
+			Ok(())
+		})?,
+	)?;
+
+	let ctx = context.clone();
+	server.set(
+		"userGetName",
+		lua.create_function(move |_, session_id: u32| {
+			Ok(
+				ctx
+					.lock()
+					.get_session(session_id)
+					.map(|session| session.state.lock().nick_name.clone()),
+			)
+		})?,
+	)?;
+
+	let ctx = context.clone();
+	server.set(
+		"userGetPosition",
+		lua.create_function(move |_, session_id: u32| {
+			Ok(
+				ctx
+					.lock()
+					.get_session(session_id)
+					.map_or((0, 0), |session| {
+						let state = session.state.lock();
+						(state.cursor.pos.x, state.cursor.pos.y)
+					}),
+			)
+		})?,
+	)?;
+
+	let ctx = context.clone();
+	server.set(
+		"mapSetPixel",
+		lua.create_function(move |_, (x, y, r, g, b): (i32, i32, u8, u8, u8)| {
+			ctx.lock().queued_pixels.push(GlobalPixelRGBA {
+				pos: IVec2::new(x, y),
+				color: ColorRGBA::new(r, g, b, 255),
+			});
+			Ok(())
+		})?,
+	)?;
+
+	lua.globals().set("server", server)?;
+
+	// Redirect print() to the server log
+	let name = String::from(plugin_name);
+	lua.globals().set(
+		"print",
+		lua.create_function(move |_, args: Variadic<String>| {
+			log::info!("[plugin {name}] {}", args.join("\t"));
+			Ok(())
+		})?,
+	)?;
+
+	Ok(())
+}
+
+pub struct PluginSystem {
+	plugins: Vec<Plugin>,
+	context: Arc<SyncMutex<PluginContext>>,
+	task_tick: Option<JoinHandle<()>>,
+	cleaned_up: bool,
+}
+
+impl PluginSystem {
+	pub fn new(plugin_list: &[String]) -> Self {
+		let context = Arc::new(SyncMutex::new(PluginContext::default()));
+		let mut plugins = Vec::new();
+
+		for name in plugin_list {
+			log::info!("Loading plugin {name}");
+			match Plugin::load(name, &context) {
+				Ok(plugin) => plugins.push(plugin),
+				Err(e) => log::error!("Failed to load plugin {name}: {e}"),
+			}
+		}
+
+		Self {
+			plugins,
+			context,
+			task_tick: None,
+			cleaned_up: false,
+		}
+	}
+
+	fn has_listeners(&self, event: &PluginEvent) -> bool {
+		self
+			.plugins
+			.iter()
+			.any(|plugin| plugin.has_listeners(event.name()))
+	}
+
+	fn dispatch_sync(&self, sessions: Vec<RoomSessionData>, event: &PluginEvent) -> bool {
+		self.context.lock().sessions = sessions;
+
+		let mut handled = false;
+		for plugin in &self.plugins {
+			match plugin.dispatch(event) {
+				Ok(called) => handled |= called,
+				Err(e) => log::error!(
+					"Plugin {} failed to handle event {}: {e}",
+					plugin.name,
+					event.name()
+				),
+			}
+		}
+
+		self.context.lock().sessions.clear();
+		handled
+	}
+
+	// Returns true if any plugin listens to this event
+	pub async fn dispatch(refs: &RoomRefs, event: PluginEvent) -> bool {
+		Self::dispatch_raw(
+			&refs.plugin_system_mtx,
+			&refs.chunk_system_mtx,
+			&refs.region_system_mtx,
+			|| async { refs.room_mtx.lock().await.get_all_sessions(None) },
+			event,
+		)
+		.await
+	}
+
+	async fn dispatch_raw<F, Fut>(
+		plugin_system_mtx: &PluginSystemMutex,
+		chunk_system_mtx: &ChunkSystemMutex,
+		region_system_mtx: &RegionSystemMutex,
+		get_sessions: F,
+		event: PluginEvent,
+	) -> bool
+	where
+		F: FnOnce() -> Fut,
+		Fut: std::future::Future<Output = Vec<RoomSessionData>>,
+	{
+		let plugin_system = plugin_system_mtx.lock().await;
+		if !plugin_system.has_listeners(&event) {
+			return false;
+		}
+		drop(plugin_system);
+
+		let sessions = get_sessions().await;
+
+		let plugin_system = plugin_system_mtx.lock().await;
+		let handled = plugin_system.dispatch_sync(sessions, &event);
+		let pixels = std::mem::take(&mut plugin_system.context.lock().queued_pixels);
+		drop(plugin_system);
+
+		if !pixels.is_empty() {
+			// Plugins draw with the permissions of a guest, protected regions are left untouched
+			let actor = RegionActor {
+				account: None,
+				manager: false,
+			};
+			let allowed = region_system_mtx
+				.lock()
+				.await
+				.filter_writable(&pixels, &actor)
+				.0
+				.into_owned();
+			Self::write_pixels(chunk_system_mtx, &allowed).await;
+		}
+
+		handled
+	}
+
+	async fn write_pixels(chunk_system_mtx: &ChunkSystemMutex, pixels: &[GlobalPixelRGBA]) {
+		let mut chunk_cache = ChunkCache::default();
+		let mut writer = ChunkWriterRGBA::new();
+
+		writer
+			.generate_affected(pixels, &mut chunk_cache, chunk_system_mtx)
+			.await;
+
+		for cell in &writer.affected_chunks {
+			if cell.queued_pixels.is_empty() {
+				continue;
+			}
+
+			let queued_pixels: Vec<ChunkPixelRGBA> =
+				cell.queued_pixels.iter().map(|c| c.0.clone()).collect();
+
+			let mut chunk = cell.chunk.lock().await;
+			chunk.allocate_image();
+
+			let threshold = CHUNK_SIZE_PX * (CHUNK_SIZE_PX / 5); // over 1/5th of chunk modified
+			chunk.set_pixels(&queued_pixels, queued_pixels.len() > threshold as usize);
+		}
+	}
+
+	pub fn launch_task_tick(plugin_system: &mut Self, room_weak: RoomInstanceWeak) {
+		if !plugin_system.has_listeners(&PluginEvent::Tick) {
+			return; // Nobody is interested
+		}
+
+		plugin_system.task_tick = Some(
+			tokio::task::Builder::new()
+				.name("Plugin system tick task")
+				.spawn(async move {
+					while let Some(room_mtx) = room_weak.upgrade() {
+						let room = room_mtx.lock().await;
+						let plugin_system_mtx = room.plugin_system.clone();
+						let chunk_system_mtx = room.chunk_system.clone();
+						let region_system_mtx = room.region_system.clone();
+						let sessions = room.get_all_sessions(None);
+						drop(room);
+						drop(room_mtx);
+
+						Self::dispatch_raw(
+							&plugin_system_mtx,
+							&chunk_system_mtx,
+							&region_system_mtx,
+							|| async { sessions },
+							PluginEvent::Tick,
+						)
+						.await;
+
+						tokio::time::sleep(std::time::Duration::from_millis(TICK_INTERVAL_MS)).await;
+					}
+					log::trace!("Plugin system tick task ended");
+				})
+				.unwrap(),
+		);
+	}
+
+	pub fn cleanup(&mut self) {
+		log::trace!("Cleaning-up plugin system");
+		if let Some(task) = &self.task_tick {
+			task.abort();
+		}
+
+		for plugin in &self.plugins {
+			if let Err(e) = plugin.call_global("onUnload") {
+				log::error!("Plugin {} failed to unload: {e}", plugin.name);
+			}
+		}
+
+		self.plugins.clear();
+		self.cleaned_up = true;
+	}
+}
+
+impl Drop for PluginSystem {
+	fn drop(&mut self) {
+		assert!(self.cleaned_up, "cleanup() not called");
+		log::trace!("Plugin system freed");
+	}
+}
+
+pub type PluginSystemMutex = Arc<Mutex<PluginSystem>>;
@@ -8,33 +8,17 @@ use tokio::sync::{Mutex, Notify};
 
 use crate::{
 	compression,
-	database::{ChunkDatabaseRecord, Database, DatabaseFunc, PreviewDatabaseRecord},
+	database::{Database, DatabaseFunc},
 	event_queue::EventQueue,
 	limits::{self, CHUNK_SIZE_PX},
+	metrics::{Gauge, METRICS},
 };
 
-#[derive(Default, Clone)]
 pub struct PreviewSystemLayer {
 	zoom: u8,
 	update_queue: Vec<IVec2>,
 }
 
-fn extract_chunk_record(record: Option<ChunkDatabaseRecord>) -> Vec<u8> {
-	if let Some(record) = record {
-		record.data
-	} else {
-		Vec::new()
-	}
-}
-
-fn extract_preview_record(record: Option<PreviewDatabaseRecord>) -> Vec<u8> {
-	if let Some(record) = record {
-		record.data
-	} else {
-		Vec::new()
-	}
-}
-
 fn decompress_vec_lz4(compressed: &[u8]) -> anyhow::Result<Vec<u8>> {
 	if compressed.is_empty() {
 		return Ok(Vec::new());
@@ -86,6 +70,10 @@ fn decompress_and_blit(
 	Ok(())
 }
 
+fn queue_gauge(zoom: u8) -> &'static Gauge {
+	&METRICS.preview_queue[usize::from(zoom - 1)]
+}
+
 impl PreviewSystemLayer {
 	pub const fn new(zoom: u8) -> Self {
 		Self {
@@ -103,6 +91,7 @@ impl PreviewSystemLayer {
 		}
 
 		self.update_queue.push(coords);
+		queue_gauge(self.zoom).inc();
 	}
 
 	#[allow(clippy::too_many_lines)]
@@ -125,6 +114,7 @@ impl PreviewSystemLayer {
 				upper_pos: IVec2::ZERO,
 			});
 		};
+		queue_gauge(zoom).dec();
 
 		// Fuse 2x2 chunks into one preview image
 		let topleft = IVec2::new(position.x * 2, position.y * 2);
@@ -132,34 +122,19 @@ impl PreviewSystemLayer {
 		let bottomleft = IVec2::new(position.x * 2, position.y * 2 + 1);
 		let bottomright = IVec2::new(position.x * 2 + 1, position.y * 2 + 1);
 
-		let compressed = if zoom == 1 {
-			// Load real chunks data underneath
-			Quad {
-				topleft: extract_chunk_record(DatabaseFunc::chunk_load_data(database, topleft).await?),
-				topright: extract_chunk_record(DatabaseFunc::chunk_load_data(database, topright).await?),
-				bottomleft: extract_chunk_record(
-					DatabaseFunc::chunk_load_data(database, bottomleft).await?,
-				),
-				bottomright: extract_chunk_record(
-					DatabaseFunc::chunk_load_data(database, bottomright).await?,
-				),
-			}
-		} else {
-			// Load preview system layer chunks
-			Quad {
-				topleft: extract_preview_record(
-					DatabaseFunc::preview_load_data(database, topleft, zoom - 1).await?,
-				),
-				topright: extract_preview_record(
-					DatabaseFunc::preview_load_data(database, topright, zoom - 1).await?,
-				),
-				bottomleft: extract_preview_record(
-					DatabaseFunc::preview_load_data(database, bottomleft, zoom - 1).await?,
-				),
-				bottomright: extract_preview_record(
-					DatabaseFunc::preview_load_data(database, bottomright, zoom - 1).await?,
-				),
-			}
+		// Load real chunks data (zoom 1) or the preview layer underneath
+		let source = |pos| async move {
+			Ok::<_, anyhow::Error>(
+				DatabaseFunc::preview_source_load(database, pos, zoom - 1)
+					.await?
+					.unwrap_or_default(),
+			)
+		};
+		let compressed = Quad {
+			topleft: source(topleft).await?,
+			topright: source(topright).await?,
+			bottomleft: source(bottomleft).await?,
+			bottomright: source(bottomright).await?,
 		};
 
 		// Allocate preview chunk data
@@ -267,7 +242,8 @@ impl PreviewSystem {
 		Self {
 			database,
 			notifier: notifier.clone(),
-			update_queue_cache: PreviewSystemQueuedChunks::new(notifier),
+			update_queue_cache: PreviewSystemQueuedChunks::new(notifier)
+				.with_depth_gauge(&METRICS.preview_update_queue),
 			layers: init_layers_vec(),
 		}
 	}
@@ -340,6 +316,12 @@ impl PreviewSystem {
 	}
 }
 
+impl Drop for PreviewSystemLayer {
+	fn drop(&mut self) {
+		queue_gauge(self.zoom).sub(self.update_queue.len());
+	}
+}
+
 impl Drop for PreviewSystem {
 	fn drop(&mut self) {
 		log::trace!("Preview system freed");
@@ -0,0 +1,215 @@
+use std::time::{Duration, Instant};
+
+use crate::config::RateLimit;
+
+// Refills continuously up to `capacity`. Taking more than is available is allowed while the bucket is full,
+// so single large operations (flood fill) still work, but the bucket goes into debt and must refill first.
+struct TokenBucket {
+	capacity: f64,
+	refill_per_s: f64,
+	tokens: f64,
+	updated_at: Instant,
+}
+
+impl TokenBucket {
+	fn new(refill_per_s: f64, capacity: u32) -> Self {
+		Self {
+			capacity: f64::from(capacity),
+			refill_per_s,
+			tokens: f64::from(capacity),
+			updated_at: Instant::now(),
+		}
+	}
+
+	fn try_take(&mut self, amount: f64) -> bool {
+		let now = Instant::now();
+		let elapsed_s = now.duration_since(self.updated_at).as_secs_f64();
+		self.tokens = elapsed_s
+			.mul_add(self.refill_per_s, self.tokens)
+			.min(self.capacity);
+		self.updated_at = now;
+
+		if self.tokens < amount.min(self.capacity) {
+			return false;
+		}
+		self.tokens -= amount;
+		true
+	}
+}
+
+#[derive(Clone, Copy)]
+pub enum Verdict {
+	Allowed,
+	Denied,
+	Throttled, // Denied again shortly after the previous violation, counted as the same violation
+	Muted(Duration), // Remaining mute time
+	Kick,
+}
+
+// Per-session limits of packets, drawn pixels and chat messages.
+// Repeated violations escalate to a temporary mute (no drawing or chatting) and then to a kick.
+pub struct RateLimiter {
+	config: RateLimit,
+	packets: TokenBucket,
+	pixels: TokenBucket,
+	messages: TokenBucket,
+	violations: u32,
+	last_violation: Option<Instant>,
+	muted_until: Option<Instant>,
+}
+
+impl RateLimiter {
+	pub fn new(config: RateLimit) -> Self {
+		Self {
+			packets: TokenBucket::new(f64::from(config.packets_per_s), config.packets_burst),
+			pixels: TokenBucket::new(f64::from(config.pixels_per_s), config.pixels_burst),
+			messages: TokenBucket::new(
+				f64::from(config.messages_per_min) / 60.0,
+				config.messages_burst,
+			),
+			config,
+			violations: 0,
+			last_violation: None,
+			muted_until: None,
+		}
+	}
+
+	pub fn check_packet(&mut self) -> Verdict {
+		if self.packets.try_take(1.0) {
+			Verdict::Allowed
+		} else {
+			self.violation()
+		}
+	}
+
+	pub fn check_pixels(&mut self, count: usize) -> Verdict {
+		if let Some(remaining) = self.mute_remaining() {
+			return Verdict::Muted(remaining);
+		}
+
+		if self.pixels.try_take(count as f64) {
+			Verdict::Allowed
+		} else {
+			self.violation()
+		}
+	}
+
+	pub fn check_message(&mut self) -> Verdict {
+		if let Some(remaining) = self.mute_remaining() {
+			return Verdict::Muted(remaining);
+		}
+
+		if self.messages.try_take(1.0) {
+			Verdict::Allowed
+		} else {
+			self.violation()
+		}
+	}
+
+	fn mute_remaining(&self) -> Option<Duration> {
+		self
+			.muted_until
+			.and_then(|muted_until| muted_until.checked_duration_since(Instant::now()))
+	}
+
+	fn violation(&mut self) -> Verdict {
+		let now = Instant::now();
+
+		if let Some(last_violation) = self.last_violation {
+			let elapsed = now.duration_since(last_violation);
+			if elapsed > Duration::from_secs(self.config.violation_reset_s) {
+				self.violations = 0;
+			} else if elapsed < Duration::from_secs(1) {
+				// A single burst of denied requests counts as one violation
+				return Verdict::Throttled;
+			}
+		}
+
+		self.last_violation = Some(now);
+		self.violations += 1;
+
+		if self.violations >= self.config.kick_after_violations {
+			Verdict::Kick
+		} else if self.violations == self.config.mute_after_violations {
+			let duration = Duration::from_secs(self.config.mute_duration_s);
+			self.muted_until = Some(now + duration);
+			Verdict::Muted(duration)
+		} else {
+			Verdict::Denied
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	// Moves the last violation out of the 1 s throttle window, as if the client kept flooding
+	fn next_violation(limiter: &mut RateLimiter) -> Verdict {
+		if let Some(last_violation) = &mut limiter.last_violation {
+			*last_violation -= Duration::from_secs(2);
+		}
+		limiter.violation()
+	}
+
+	#[test]
+	fn bucket_allows_burst_then_denies() {
+		let mut bucket = TokenBucket::new(1.0, 3);
+		assert!(bucket.try_take(1.0));
+		assert!(bucket.try_take(2.0));
+		assert!(!bucket.try_take(1.0));
+	}
+
+	#[test]
+	fn bucket_refills_over_time() {
+		let mut bucket = TokenBucket::new(10.0, 5);
+		assert!(bucket.try_take(5.0));
+		assert!(!bucket.try_take(1.0));
+
+		bucket.updated_at -= Duration::from_millis(500);
+		assert!(bucket.try_take(5.0));
+	}
+
+	#[test]
+	fn large_operation_goes_into_debt() {
+		let mut bucket = TokenBucket::new(10.0, 5);
+		assert!(bucket.try_take(50.0));
+		assert!(!bucket.try_take(1.0));
+
+		// Refilling to full takes longer than one bucket capacity
+		bucket.updated_at -= Duration::from_secs(1);
+		assert!(!bucket.try_take(1.0));
+		bucket.updated_at -= Duration::from_secs(5);
+		assert!(bucket.try_take(1.0));
+	}
+
+	#[test]
+	fn violations_escalate_to_mute_then_kick() {
+		let mut limiter = RateLimiter::new(RateLimit::default());
+
+		assert!(matches!(limiter.violation(), Verdict::Denied));
+		assert!(matches!(limiter.violation(), Verdict::Throttled));
+		assert!(matches!(next_violation(&mut limiter), Verdict::Denied));
+		assert!(matches!(next_violation(&mut limiter), Verdict::Muted(_)));
+		assert!(matches!(limiter.check_message(), Verdict::Muted(_)));
+		assert!(matches!(limiter.check_pixels(1), Verdict::Muted(_)));
+		assert!(matches!(limiter.check_packet(), Verdict::Allowed));
+
+		assert!(matches!(next_violation(&mut limiter), Verdict::Denied));
+		assert!(matches!(next_violation(&mut limiter), Verdict::Denied));
+		assert!(matches!(next_violation(&mut limiter), Verdict::Kick));
+	}
+
+	#[test]
+	fn violations_are_forgotten_after_reset_period() {
+		let mut limiter = RateLimiter::new(RateLimit::default());
+		assert!(matches!(limiter.violation(), Verdict::Denied));
+		assert!(matches!(next_violation(&mut limiter), Verdict::Denied));
+
+		if let Some(last_violation) = &mut limiter.last_violation {
+			*last_violation -= Duration::from_secs(limiter.config.violation_reset_s + 1);
+		}
+		assert!(matches!(limiter.violation(), Verdict::Denied));
+		assert_eq!(limiter.violations, 1);
+	}
+}
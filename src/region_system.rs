@@ -0,0 +1,211 @@
+use std::{borrow::Cow, sync::Arc};
+
+use glam::IVec2;
+use tokio::sync::Mutex;
+
+use crate::{
+	database::{Database, DatabaseFunc},
+	pixel::GlobalPixelRGBA,
+};
+
+// Rectangle of the canvas which only its owner and editors can draw in
+#[derive(Clone)]
+pub struct ProtectedRegion {
+	pub name: String,
+	pub owner: String,        // Account name of the owner
+	pub start: IVec2,         // Inclusive
+	pub end: IVec2,           // Inclusive
+	pub read_only: bool,      // Nobody except region managers can draw, including the owner
+	pub editors: Vec<String>, // Accounts allowed to draw besides the owner
+}
+
+impl ProtectedRegion {
+	pub const fn contains(&self, pos: IVec2) -> bool {
+		pos.x >= self.start.x && pos.y >= self.start.y && pos.x <= self.end.x && pos.y <= self.end.y
+	}
+
+	pub fn is_owner(&self, actor: &RegionActor) -> bool {
+		actor.manager
+			|| actor
+				.account
+				.as_ref()
+				.is_some_and(|account| account.eq_ignore_ascii_case(&self.owner))
+	}
+
+	fn can_write(&self, actor: &RegionActor) -> bool {
+		if actor.manager {
+			return true;
+		}
+
+		if self.read_only {
+			return false;
+		}
+
+		actor.account.as_ref().is_some_and(|account| {
+			account.eq_ignore_ascii_case(&self.owner)
+				|| self
+					.editors
+					.iter()
+					.any(|editor| editor.eq_ignore_ascii_case(account))
+		})
+	}
+}
+
+// Who is trying to modify the canvas
+pub struct RegionActor {
+	pub account: Option<String>, // Guests cannot own or edit regions
+	pub manager: bool,           // Can manage all regions and draw in them
+}
+
+pub struct RegionSystem {
+	regions: Vec<ProtectedRegion>,
+	database: Arc<Mutex<Database>>,
+}
+
+impl RegionSystem {
+	pub async fn load(database: Arc<Mutex<Database>>) -> anyhow::Result<Self> {
+		let regions = DatabaseFunc::region_load_all(&database).await?;
+		if !regions.is_empty() {
+			log::info!("Loaded {} protected regions", regions.len());
+		}
+		Ok(Self { regions, database })
+	}
+
+	pub fn list(&self) -> &[ProtectedRegion] {
+		&self.regions
+	}
+
+	pub fn get(&self, name: &str) -> Option<&ProtectedRegion> {
+		self.regions.iter().find(|region| region.name == name)
+	}
+
+	// Insert or replace the region with the same name
+	pub async fn save(&mut self, region: ProtectedRegion) -> anyhow::Result<()> {
+		DatabaseFunc::region_save(&self.database, region.clone()).await?;
+		self.regions.retain(|r| r.name != region.name);
+		self.regions.push(region);
+		Ok(())
+	}
+
+	pub async fn remove(&mut self, name: &str) -> anyhow::Result<()> {
+		DatabaseFunc::region_remove(&self.database, String::from(name)).await?;
+		self.regions.retain(|region| region.name != name);
+		Ok(())
+	}
+
+	// Returns pixels the actor is allowed to write and the number of denied ones
+	pub fn filter_writable<'a>(
+		&self,
+		pixels: &'a [GlobalPixelRGBA],
+		actor: &RegionActor,
+	) -> (Cow<'a, [GlobalPixelRGBA]>, usize) {
+		if actor.manager || self.regions.is_empty() {
+			return (Cow::Borrowed(pixels), 0);
+		}
+
+		let denied_regions: Vec<&ProtectedRegion> = self
+			.regions
+			.iter()
+			.filter(|region| !region.can_write(actor))
+			.collect();
+
+		if denied_regions.is_empty() {
+			return (Cow::Borrowed(pixels), 0);
+		}
+
+		let allowed: Vec<GlobalPixelRGBA> = pixels
+			.iter()
+			.filter(|pixel| {
+				!denied_regions
+					.iter()
+					.any(|region| region.contains(pixel.pos))
+			})
+			.cloned()
+			.collect();
+
+		let denied = pixels.len() - allowed.len();
+		(Cow::Owned(allowed), denied)
+	}
+}
+
+pub type RegionSystemMutex = Arc<Mutex<RegionSystem>>;
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{pixel::ColorRGBA, storage::StorageBackend};
+
+	fn actor(account: Option<&str>) -> RegionActor {
+		RegionActor {
+			account: account.map(String::from),
+			manager: false,
+		}
+	}
+
+	fn pixels() -> Vec<GlobalPixelRGBA> {
+		[IVec2::new(5, 5), IVec2::new(10, 10), IVec2::new(50, 50)]
+			.into_iter()
+			.map(|pos| GlobalPixelRGBA {
+				pos,
+				color: ColorRGBA::new(0, 0, 0, 255),
+			})
+			.collect()
+	}
+
+	fn positions(pixels: &[GlobalPixelRGBA]) -> Vec<IVec2> {
+		pixels.iter().map(|pixel| pixel.pos).collect()
+	}
+
+	#[tokio::test]
+	async fn filter_writable_denies_pixels_in_foreign_regions() {
+		let database = Arc::new(Mutex::new(
+			Database::open(String::from(":memory:"), StorageBackend::Memory)
+				.await
+				.unwrap(),
+		));
+		let mut regions = RegionSystem::load(database.clone()).await.unwrap();
+		regions
+			.save(ProtectedRegion {
+				name: String::from("base"),
+				owner: String::from("Alice"),
+				start: IVec2::new(0, 0),
+				end: IVec2::new(10, 10),
+				read_only: false,
+				editors: vec![String::from("bob")],
+			})
+			.await
+			.unwrap();
+		let pixels = pixels();
+
+		let (allowed, denied) = regions.filter_writable(&pixels, &actor(None));
+		assert_eq!(positions(&allowed), [IVec2::new(50, 50)]);
+		assert_eq!(denied, 2);
+
+		let (allowed, denied) = regions.filter_writable(&pixels, &actor(Some("carol")));
+		assert_eq!(allowed.len(), 1);
+		assert_eq!(denied, 2);
+
+		// Owner and editors are compared case-insensitively
+		for account in ["alice", "BOB"] {
+			let (allowed, denied) = regions.filter_writable(&pixels, &actor(Some(account)));
+			assert!(matches!(allowed, Cow::Borrowed(_)));
+			assert_eq!(denied, 0);
+		}
+
+		let mut region = regions.get("base").unwrap().clone();
+		region.read_only = true;
+		regions.save(region).await.unwrap();
+		let (_, denied) = regions.filter_writable(&pixels, &actor(Some("alice")));
+		assert_eq!(denied, 2);
+
+		let manager = RegionActor {
+			account: None,
+			manager: true,
+		};
+		let (allowed, denied) = regions.filter_writable(&pixels, &manager);
+		assert_eq!(allowed.len(), 3);
+		assert_eq!(denied, 0);
+
+		database.lock().await.cleanup().await;
+	}
+}
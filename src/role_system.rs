@@ -0,0 +1,249 @@
+use std::{collections::HashMap, fmt, str::FromStr, sync::Arc};
+
+use tokio::sync::Mutex;
+
+use crate::database::{Database, DatabaseFunc};
+
+// Keys of the default role assignments, nick names cannot contain "@" so they never collide with accounts
+const ROLE_KEY_GUESTS: &str = "@guests";
+const ROLE_KEY_ACCOUNTS: &str = "@accounts";
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+	#[default]
+	Viewer,
+	Artist,
+	Moderator,
+	Owner,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capability {
+	Chat,
+	Draw,
+	Fill,
+	Kick,
+	Ban,
+	Mute,
+	ViewHistory,
+	Rollback,
+	ManageRegions, // Also allows drawing in protected regions
+	Export,
+	Import,
+	ManageRoles,
+	RegeneratePreviews,
+}
+
+impl Role {
+	pub const fn can(self, capability: Capability) -> bool {
+		match capability {
+			Capability::Chat => true,
+			Capability::Draw | Capability::Fill => !matches!(self, Self::Viewer),
+			// Bans apply to the whole server, only server admins can issue them
+			Capability::Ban => false,
+			Capability::Kick
+			| Capability::Mute
+			| Capability::ViewHistory
+			| Capability::Rollback
+			| Capability::ManageRegions => matches!(self, Self::Moderator | Self::Owner),
+			Capability::Export
+			| Capability::Import
+			| Capability::ManageRoles
+			| Capability::RegeneratePreviews => matches!(self, Self::Owner),
+		}
+	}
+
+	pub const fn as_str(self) -> &'static str {
+		match self {
+			Self::Viewer => "viewer",
+			Self::Artist => "artist",
+			Self::Moderator => "moderator",
+			Self::Owner => "owner",
+		}
+	}
+}
+
+impl fmt::Display for Role {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}", self.as_str())
+	}
+}
+
+impl FromStr for Role {
+	type Err = anyhow::Error;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"viewer" => Ok(Self::Viewer),
+			"artist" => Ok(Self::Artist),
+			"moderator" => Ok(Self::Moderator),
+			"owner" => Ok(Self::Owner),
+			_ => anyhow::bail!("Unknown role \"{s}\" (viewer, artist, moderator, owner)"),
+		}
+	}
+}
+
+// Per-room role assignments of accounts
+pub struct RoleSystem {
+	assignments: HashMap<String /* lowercase account name or default key */, Role>,
+	guest_role: Role,
+	account_role: Role,
+	database: Arc<Mutex<Database>>,
+}
+
+impl RoleSystem {
+	pub async fn load(
+		database: Arc<Mutex<Database>>,
+		guest_role: Role,
+		account_role: Role,
+	) -> anyhow::Result<Self> {
+		let mut assignments = HashMap::new();
+		for (key, role) in DatabaseFunc::role_load_all(&database).await? {
+			match role.parse() {
+				Ok(role) => {
+					assignments.insert(key, role);
+				}
+				Err(e) => log::error!("Invalid role of {key}: {e}"),
+			}
+		}
+
+		Ok(Self {
+			assignments,
+			guest_role,
+			account_role,
+			database,
+		})
+	}
+
+	// Role of the session, based on its account. Sessions without an account are guests.
+	pub fn resolve(&self, account: Option<&str>) -> Role {
+		let (guest_role, account_role) = self.default_roles();
+		account.map_or(guest_role, |account| {
+			self
+				.assignments
+				.get(&account.to_lowercase())
+				.copied()
+				.unwrap_or(account_role)
+		})
+	}
+
+	pub fn default_roles(&self) -> (Role /* guests */, Role /* accounts */) {
+		let get = |key: &str, fallback: Role| self.assignments.get(key).copied().unwrap_or(fallback);
+		(
+			get(ROLE_KEY_GUESTS, self.guest_role),
+			get(ROLE_KEY_ACCOUNTS, self.account_role),
+		)
+	}
+
+	// Explicit assignments, sorted by account name
+	pub fn list(&self) -> Vec<(&str, Role)> {
+		let mut list: Vec<_> = self
+			.assignments
+			.iter()
+			.filter(|(key, _)| !key.starts_with('@'))
+			.map(|(key, role)| (key.as_str(), *role))
+			.collect();
+		list.sort_unstable();
+		list
+	}
+
+	// Assign the role to the account, or remove the assignment if None
+	pub async fn assign(&mut self, account: &str, role: Option<Role>) -> anyhow::Result<()> {
+		self.store(account.to_lowercase(), role).await
+	}
+
+	// Role of guests or accounts without an explicit assignment in this room
+	pub async fn set_default(&mut self, guests: bool, role: Role) -> anyhow::Result<()> {
+		let key = if guests {
+			ROLE_KEY_GUESTS
+		} else {
+			ROLE_KEY_ACCOUNTS
+		};
+		self.store(String::from(key), Some(role)).await
+	}
+
+	async fn store(&mut self, key: String, role: Option<Role>) -> anyhow::Result<()> {
+		DatabaseFunc::role_save(
+			&self.database,
+			key.clone(),
+			role.map(|role| String::from(role.as_str())),
+		)
+		.await?;
+
+		if let Some(role) = role {
+			self.assignments.insert(key, role);
+		} else {
+			self.assignments.remove(&key);
+		}
+		Ok(())
+	}
+}
+
+pub type RoleSystemMutex = Arc<Mutex<RoleSystem>>;
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::storage::StorageBackend;
+
+	#[test]
+	fn capabilities_grow_with_role() {
+		assert!(Role::Viewer.can(Capability::Chat));
+		assert!(!Role::Viewer.can(Capability::Draw));
+		assert!(Role::Artist.can(Capability::Fill));
+		assert!(!Role::Artist.can(Capability::Kick));
+		assert!(Role::Moderator.can(Capability::Rollback));
+		assert!(!Role::Moderator.can(Capability::Export));
+		assert!(Role::Owner.can(Capability::ManageRoles));
+		assert!(!Role::Owner.can(Capability::Ban));
+	}
+
+	#[test]
+	fn role_round_trips_through_text() {
+		for role in [Role::Viewer, Role::Artist, Role::Moderator, Role::Owner] {
+			assert_eq!(role.as_str().parse::<Role>().unwrap(), role);
+		}
+		assert!("admin".parse::<Role>().is_err());
+	}
+
+	#[tokio::test]
+	async fn room_defaults_and_assignments() {
+		let database = Arc::new(Mutex::new(
+			Database::open(String::from(":memory:"), StorageBackend::Memory)
+				.await
+				.unwrap(),
+		));
+		let mut roles = RoleSystem::load(database.clone(), Role::Viewer, Role::Artist)
+			.await
+			.unwrap();
+
+		// Server config defaults
+		assert_eq!(roles.resolve(None), Role::Viewer);
+		assert_eq!(roles.resolve(Some("alice")), Role::Artist);
+
+		// Per-room defaults override them
+		roles.set_default(true, Role::Artist).await.unwrap();
+		roles.set_default(false, Role::Moderator).await.unwrap();
+		assert_eq!(roles.default_roles(), (Role::Artist, Role::Moderator));
+		assert_eq!(roles.resolve(None), Role::Artist);
+		assert_eq!(roles.resolve(Some("alice")), Role::Moderator);
+
+		// Explicit assignments override the defaults, account names are case-insensitive
+		roles.assign("Alice", Some(Role::Owner)).await.unwrap();
+		assert_eq!(roles.resolve(Some("ALICE")), Role::Owner);
+		assert_eq!(roles.list(), [("alice", Role::Owner)]);
+
+		// Assignments and defaults are persisted
+		let reloaded = RoleSystem::load(database.clone(), Role::Viewer, Role::Viewer)
+			.await
+			.unwrap();
+		assert_eq!(reloaded.resolve(Some("alice")), Role::Owner);
+		assert_eq!(reloaded.resolve(Some("bob")), Role::Moderator);
+
+		roles.assign("alice", None).await.unwrap();
+		assert_eq!(roles.resolve(Some("alice")), Role::Moderator);
+
+		database.lock().await.cleanup().await;
+	}
+}
@@ -1,21 +1,29 @@
 use crate::{
-	chunk::system::{ChunkSystem, ChunkSystemMutex, ChunkSystemSignal},
+	chunk::{
+		journal::StrokeJournal,
+		system::{ChunkSystem, ChunkSystemMutex, ChunkSystemSignal},
+	},
 	config::Config,
 	database::Database,
 	event_queue::{EventQueue, NotifySender},
 	packet_server,
+	plugin_system::{PluginSystem, PluginSystemMutex},
 	preview_system::{PreviewSystem, PreviewSystemMutex},
+	region_system::{RegionSystem, RegionSystemMutex},
+	role_system::{Role, RoleSystem, RoleSystemMutex},
 	server::Server,
 	session::{SessionHandle, SessionInstanceWeak, SessionState},
 	tool::iter_brush::BrushShapes,
 };
 use parking_lot::Mutex as SyncMutex;
-use std::sync::Arc;
+use std::{
+	fs::{File, OpenOptions, TryLockError},
+	sync::{Arc, Weak},
+};
 use tokio::sync::{Mutex, Notify};
 
 #[derive(Clone)]
 pub struct RoomSessionData {
-	#[allow(dead_code)]
 	pub instance_mtx: SessionInstanceWeak,
 	pub queue_send: EventQueue<packet_server::Packet>,
 	pub handle: SessionHandle,
@@ -24,27 +32,76 @@ pub struct RoomSessionData {
 
 pub struct RoomRefs {
 	pub room_mtx: RoomInstanceMutex,
+	pub database: Arc<Mutex<Database>>,
 	pub chunk_system_mtx: ChunkSystemMutex,
 	pub preview_system_mtx: PreviewSystemMutex,
+	pub plugin_system_mtx: PluginSystemMutex,
+	pub region_system_mtx: RegionSystemMutex,
+	pub role_system_mtx: RoleSystemMutex,
 	pub brush_shapes_mtx: Arc<Mutex<BrushShapes>>,
 	pub chunk_system_sender: NotifySender<ChunkSystemSignal>,
 }
 
 pub struct RoomInstance {
+	pub name: String,
 	sessions: Vec<RoomSessionData>,
+	lock: Option<RoomLock>, // Released once the room is saved
 	pub database: Arc<Mutex<Database>>,
 	pub chunk_system: ChunkSystemMutex,
 	pub chunk_system_sender: NotifySender<ChunkSystemSignal>,
 	pub brush_shapes: Arc<Mutex<BrushShapes>>,
 	pub preview_system: PreviewSystemMutex,
+	pub plugin_system: PluginSystemMutex,
+	pub region_system: RegionSystemMutex,
+	pub role_system: RoleSystemMutex,
 	cleaned_up: bool,
 }
 
+pub fn get_database_path(room_name: &str) -> String {
+	format!("rooms/{room_name}.db")
+}
+
+pub fn get_journal_path(room_name: &str) -> String {
+	format!("rooms/{room_name}.journal")
+}
+
+pub fn get_lock_path(room_name: &str) -> String {
+	format!("rooms/{room_name}.lock")
+}
+
+// Held while the server has the room loaded or the room tool works with it, so they never write to the same room.
+// The OS releases it when the process exits, a crash doesn't leave a stale lock behind.
+pub struct RoomLock {
+	_file: File,
+}
+
+impl RoomLock {
+	pub fn acquire(room_name: &str) -> anyhow::Result<Self> {
+		let file = OpenOptions::new()
+			.create(true)
+			.truncate(false)
+			.write(true)
+			.open(get_lock_path(room_name))?;
+
+		match file.try_lock() {
+			Ok(()) => Ok(Self { _file: file }),
+			Err(TryLockError::WouldBlock) => {
+				anyhow::bail!("Room {room_name} is in use by another process")
+			}
+			Err(TryLockError::Error(e)) => Err(e.into()),
+		}
+	}
+}
+
 impl RoomInstance {
 	pub async fn new(room_name: &str, config: &Config) -> anyhow::Result<Self> {
-		let db_path = format!("rooms/{room_name}.db");
+		let lock = RoomLock::acquire(room_name)?;
 
-		let db = Database::new(db_path.as_str())?;
+		let db = Database::open(
+			get_database_path(room_name),
+			config.storage_backend.unwrap_or_default(),
+		)
+		.await?;
 		let from_db_version = db.migrated_from_version;
 
 		let database = Arc::new(Mutex::new(db));
@@ -60,8 +117,15 @@ impl RoomInstance {
 			&chunk_system_sender,
 			preview_system_mtx.clone(),
 			config.autosave_interval_ms,
+			config.snapshot_retention.clone(),
+			StrokeJournal::new(
+				get_journal_path(room_name),
+				config.journal_durability.unwrap_or_default(),
+			),
 		)));
 
+		ChunkSystem::replay_journal(chunk_system_mtx.clone()).await?;
+
 		if from_db_version == 0 {
 			ChunkSystem::regenerate_all_previews(chunk_system_mtx.clone()).await;
 		}
@@ -73,13 +137,29 @@ impl RoomInstance {
 			ChunkSystem::launch_task_processor(&mut chunk_system, Arc::downgrade(&chunk_system_mtx));
 		}
 
+		let plugin_system_mtx = Arc::new(Mutex::new(PluginSystem::new(&config.plugin_list)));
+		let region_system_mtx = Arc::new(Mutex::new(RegionSystem::load(database.clone()).await?));
+		let role_system_mtx = Arc::new(Mutex::new(
+			RoleSystem::load(
+				database.clone(),
+				config.guest_role.unwrap_or(Role::Artist),
+				config.account_role.unwrap_or(Role::Artist),
+			)
+			.await?,
+		));
+
 		Ok(Self {
+			name: String::from(room_name),
+			lock: Some(lock),
 			sessions: Vec::new(),
 			database: database.clone(),
 			cleaned_up: false,
 			chunk_system: chunk_system_mtx,
 			chunk_system_sender,
 			preview_system: preview_system_mtx,
+			plugin_system: plugin_system_mtx,
+			region_system: region_system_mtx,
+			role_system: role_system_mtx,
 			brush_shapes: Arc::new(Mutex::new(BrushShapes::new())),
 		})
 	}
@@ -169,11 +249,21 @@ impl RoomInstance {
 		Self::gen_suitable_name(current, occupied_num)
 	}
 
+	pub async fn launch_tasks(room_mtx: &RoomInstanceMutex) {
+		let plugin_system_mtx = room_mtx.lock().await.plugin_system.clone();
+		PluginSystem::launch_task_tick(
+			&mut *plugin_system_mtx.lock().await,
+			Arc::downgrade(room_mtx),
+		);
+	}
+
 	pub async fn cleanup(&mut self) {
 		log::trace!("Cleaning-up room");
+		self.plugin_system.lock().await.cleanup();
 		ChunkSystem::cleanup(self.chunk_system.clone()).await;
 		PreviewSystem::process_all(self.preview_system.clone()).await;
-		self.database.lock().await.cleanup();
+		self.database.lock().await.cleanup().await;
+		self.lock = None;
 		self.cleaned_up = true;
 		log::debug!("Room cleaned up");
 	}
@@ -187,3 +277,4 @@ impl Drop for RoomInstance {
 }
 
 pub type RoomInstanceMutex = Arc<Mutex<RoomInstance>>;
+pub type RoomInstanceWeak = Weak<Mutex<RoomInstance>>;
@@ -0,0 +1,383 @@
+use std::{collections::VecDeque, sync::Arc};
+
+use glam::IVec2;
+use tokio::sync::Mutex;
+
+use crate::{
+	chunk::{
+		layer::{LayerRGBA, RGBAData},
+		system::ChunkSystem,
+	},
+	compression::decompress_lz4,
+	config,
+	database::{CompressionType, Database, DatabaseFunc},
+	export::{self, Region},
+	limits::{self, CHUNK_IMAGE_SIZE_BYTES_RGBA, CHUNK_SIZE_PX},
+	migration::{self, MigrationProgress, DATABASE_VERSION},
+	pixel::ColorRGBA,
+	room::{self, RoomLock},
+};
+
+// Offline room database tooling, invoked as `multipixel room <name> <command> [args]`.
+// The server must not have the room loaded while modifying it.
+
+fn print_help() {
+	println!("Usage: multipixel room <name> <command> [args]");
+	println!("Commands:");
+	println!("  info - Print bounds, chunk and snapshot counts");
+	println!("  list - List all chunks with their snapshot counts");
+	println!("  export <x1> <y1> <x2> <y2> <output.png> - Export the region to PNG");
+	println!("  tiles <directory> - Export every chunk as a separate PNG tile");
+	println!("  copy <target room> <x1> <y1> <x2> <y2> <target x> <target y> - Copy the region into another room");
+	println!(
+		"  migrate [--dry-run] - Upgrade the database to the current version, backing it up first"
+	);
+	println!("  vacuum - Reclaim unused space");
+}
+
+fn parse_coords<const N: usize>(args: &mut VecDeque<String>) -> anyhow::Result<[i32; N]> {
+	let mut coords = [0; N];
+	for coord in &mut coords {
+		let Some(arg) = args.pop_front() else {
+			anyhow::bail!("Missing coordinate");
+		};
+		*coord = arg.parse()?;
+	}
+	Ok(coords)
+}
+
+// Returns the database path. The lock has to be held for as long as the room is used.
+fn lock_room(room_name: &str, create: bool) -> anyhow::Result<(String, RoomLock)> {
+	if room_name.is_empty() || !room_name.chars().all(|ch| ch.is_ascii_alphanumeric()) {
+		anyhow::bail!("Invalid room name");
+	}
+
+	let path = room::get_database_path(room_name);
+	if !create && !std::fs::exists(&path).unwrap_or(false) {
+		anyhow::bail!("Room {room_name} does not exist");
+	}
+
+	let lock = RoomLock::acquire(room_name)
+		.map_err(|e| anyhow::anyhow!("{e}, stop the server or wait until the room is unloaded"))?;
+
+	// The server would replay it over any changes made here
+	let journal_path = room::get_journal_path(room_name);
+	if std::fs::exists(&journal_path).unwrap_or(false)
+		|| std::fs::exists(format!("{journal_path}.old")).unwrap_or(false)
+	{
+		anyhow::bail!(
+			"Room {room_name} has unsaved strokes in {journal_path}, load it in the server once to apply them"
+		);
+	}
+
+	Ok((path, lock))
+}
+
+async fn open_room(
+	room_name: &str,
+	create: bool,
+) -> anyhow::Result<(Arc<Mutex<Database>>, RoomLock)> {
+	let (path, lock) = lock_room(room_name, create)?;
+
+	// New rooms use the storage backend of the server
+	let backend = config::load()
+		.await
+		.ok()
+		.and_then(|config| config.storage_backend)
+		.unwrap_or_default();
+
+	Ok((
+		Arc::new(Mutex::new(Database::open(path, backend).await?)),
+		lock,
+	))
+}
+
+async fn close_room(database: Arc<Mutex<Database>>) {
+	database.lock().await.cleanup().await;
+}
+
+async fn command_info(database: &Arc<Mutex<Database>>, room_name: &str) -> anyhow::Result<()> {
+	let stats = DatabaseFunc::chunk_list_stats(database).await?;
+	let snapshot_count: u32 = stats.iter().map(|chunk| chunk.snapshot_count).sum();
+
+	println!("Room: {room_name}");
+	println!("Database version: {DATABASE_VERSION}");
+	println!("Storage: {}", database.lock().await.storage_backend);
+	println!("Chunks: {}", stats.len());
+	println!("Snapshots: {snapshot_count}");
+
+	if let Some(first) = stats.first() {
+		let (min, max) = stats
+			.iter()
+			.fold((first.pos, first.pos), |(min, max), chunk| {
+				(min.min(chunk.pos), max.max(chunk.pos))
+			});
+		let chunk_size = CHUNK_SIZE_PX as i32;
+		println!("Bounds (chunks): {} {} {} {}", min.x, min.y, max.x, max.y);
+		println!(
+			"Bounds (pixels): {} {} {} {}",
+			min.x * chunk_size,
+			min.y * chunk_size,
+			(max.x + 1) * chunk_size - 1,
+			(max.y + 1) * chunk_size - 1
+		);
+	}
+
+	if let Ok(metadata) = std::fs::metadata(room::get_database_path(room_name)) {
+		println!("File size: {} KiB", metadata.len() / 1024);
+	}
+
+	Ok(())
+}
+
+async fn command_list(database: &Arc<Mutex<Database>>) -> anyhow::Result<()> {
+	println!("x\ty\tsnapshots\tmodified");
+	for chunk in DatabaseFunc::chunk_list_stats(database).await? {
+		println!(
+			"{}\t{}\t{}\t{}",
+			chunk.pos.x, chunk.pos.y, chunk.snapshot_count, chunk.modified_at
+		);
+	}
+	Ok(())
+}
+
+async fn write_region_png(
+	database: &Arc<Mutex<Database>>,
+	region: Region,
+	path: String,
+) -> anyhow::Result<()> {
+	let rgba = export::render_region(database, region).await?;
+	let size = region.size();
+	tokio::task::spawn_blocking(move || export::write_png(&path, size, &rgba)).await??;
+	Ok(())
+}
+
+async fn command_export(
+	database: &Arc<Mutex<Database>>,
+	args: &mut VecDeque<String>,
+) -> anyhow::Result<()> {
+	let [x1, y1, x2, y2] = parse_coords(args)?;
+	let Some(path) = args.pop_front() else {
+		anyhow::bail!("Missing output path");
+	};
+
+	let region = Region::from_corners(IVec2::new(x1, y1), IVec2::new(x2, y2));
+	if region.area() > u64::from(limits::EXPORT_AREA_MAX_PX) {
+		anyhow::bail!(
+			"Region too large (max {} pixels)",
+			limits::EXPORT_AREA_MAX_PX
+		);
+	}
+
+	write_region_png(database, region, path.clone()).await?;
+	println!("Exported to {path}");
+	Ok(())
+}
+
+async fn command_tiles(
+	database: &Arc<Mutex<Database>>,
+	args: &mut VecDeque<String>,
+) -> anyhow::Result<()> {
+	let Some(dir) = args.pop_front() else {
+		anyhow::bail!("Missing output directory");
+	};
+	tokio::fs::create_dir_all(&dir).await?;
+
+	let chunks = DatabaseFunc::chunk_list_all(database).await?;
+	for chunk_pos in &chunks {
+		let start = *chunk_pos * CHUNK_SIZE_PX as i32;
+		let region = Region {
+			start,
+			end: start + IVec2::splat(CHUNK_SIZE_PX as i32 - 1),
+		};
+		write_region_png(
+			database,
+			region,
+			format!("{dir}/{}_{}.png", chunk_pos.x, chunk_pos.y),
+		)
+		.await?;
+	}
+
+	println!("Exported {} tiles to {dir}", chunks.len());
+	Ok(())
+}
+
+async fn command_copy(
+	database: &Arc<Mutex<Database>>,
+	room_name: &str,
+	args: &mut VecDeque<String>,
+) -> anyhow::Result<()> {
+	let Some(target_room_name) = args.pop_front() else {
+		anyhow::bail!("Missing target room");
+	};
+	let [x1, y1, x2, y2, target_x, target_y] = parse_coords(args)?;
+
+	let region = Region::from_corners(IVec2::new(x1, y1), IVec2::new(x2, y2));
+	if region.area() > u64::from(limits::EXPORT_AREA_MAX_PX) {
+		anyhow::bail!(
+			"Region too large (max {} pixels)",
+			limits::EXPORT_AREA_MAX_PX
+		);
+	}
+
+	let rgba = export::render_region(database, region).await?;
+	let size = region.size();
+	let target = Region {
+		start: IVec2::new(target_x, target_y),
+		end: IVec2::new(target_x, target_y) + size - IVec2::ONE,
+	};
+
+	if target_room_name == room_name {
+		write_region(database, target, &rgba).await?;
+	} else {
+		let (target_database, _target_lock) = open_room(&target_room_name, true).await?;
+		let res = write_region(&target_database, target, &rgba).await;
+		close_room(target_database).await;
+		res?;
+	}
+
+	println!(
+		"Copied {} pixels into room {target_room_name}",
+		region.area()
+	);
+	println!("Previews of {target_room_name} are outdated, run process_preview_system as admin to refresh them");
+	Ok(())
+}
+
+// Overwrite the region in the database with the given RGBA image
+async fn write_region(
+	database: &Arc<Mutex<Database>>,
+	region: Region,
+	rgba: &[u8],
+) -> anyhow::Result<()> {
+	let size = region.size();
+	let chunk_start = ChunkSystem::global_pixel_pos_to_chunk_pos(region.start);
+	let chunk_end = ChunkSystem::global_pixel_pos_to_chunk_pos(region.end);
+
+	let mut layer = LayerRGBA::new();
+
+	for chunk_y in chunk_start.y..=chunk_end.y {
+		for chunk_x in chunk_start.x..=chunk_end.x {
+			let chunk_pos = IVec2::new(chunk_x, chunk_y);
+
+			let record = DatabaseFunc::chunk_load_data(database, chunk_pos).await?;
+			match record.and_then(|record| decompress_lz4(&record.data, CHUNK_IMAGE_SIZE_BYTES_RGBA)) {
+				Some(raw) => layer.set_data(RGBAData(raw)),
+				None => layer.alloc_transparent_black(),
+			}
+
+			let chunk_origin = chunk_pos * CHUNK_SIZE_PX as i32;
+			let from = region.start.max(chunk_origin);
+			let to = region
+				.end
+				.min(chunk_origin + IVec2::splat(CHUNK_SIZE_PX as i32 - 1));
+
+			for y in from.y..=to.y {
+				for x in from.x..=to.x {
+					let pos = IVec2::new(x, y);
+					let offset =
+						((y - region.start.y) as usize * size.x as usize + (x - region.start.x) as usize) * 4;
+					layer.set_pixel(
+						ChunkSystem::global_pixel_pos_to_local_pixel_pos(pos),
+						ColorRGBA::new(
+							rgba[offset],
+							rgba[offset + 1],
+							rgba[offset + 2],
+							rgba[offset + 3],
+						),
+					);
+				}
+			}
+
+			DatabaseFunc::chunk_save_data(
+				database,
+				chunk_pos,
+				Arc::new(layer.compress_lz4()),
+				CompressionType::Lz4,
+			)
+			.await?;
+		}
+	}
+
+	Ok(())
+}
+
+async fn command_vacuum(database: &Arc<Mutex<Database>>) -> anyhow::Result<()> {
+	let reclaimed = DatabaseFunc::vacuum(database).await?;
+	println!("Reclaimed {} KiB", reclaimed / 1024);
+	Ok(())
+}
+
+// Runs on the bare connection, opening the room would already migrate it
+async fn command_migrate(room_name: &str, args: &mut VecDeque<String>) -> anyhow::Result<()> {
+	let dry_run = match args.pop_front().as_deref() {
+		None => false,
+		Some("--dry-run") => true,
+		Some(arg) => anyhow::bail!("Unknown argument \"{arg}\""),
+	};
+
+	let (path, _lock) = lock_room(room_name, false)?;
+	let report = tokio::task::spawn_blocking(move || {
+		let mut conn = rusqlite::Connection::open(&path)?;
+		let mut output = |message: String| println!("{message}");
+		migration::migrate(
+			&mut conn,
+			&path,
+			dry_run,
+			&mut MigrationProgress::new(&mut output),
+		)
+	})
+	.await??;
+
+	if report.applied.is_empty() {
+		println!("Database is already at version {DATABASE_VERSION}");
+	} else if dry_run {
+		println!(
+			"Dry run succeeded, {} migrations would be applied, nothing was changed",
+			report.applied.len()
+		);
+	} else {
+		println!(
+			"Database updated from version {} to version {DATABASE_VERSION}",
+			report.from_version
+		);
+	}
+
+	Ok(())
+}
+
+async fn run_command(
+	database: &Arc<Mutex<Database>>,
+	room_name: &str,
+	command: &str,
+	args: &mut VecDeque<String>,
+) -> anyhow::Result<()> {
+	match command {
+		"info" => command_info(database, room_name).await,
+		"list" => command_list(database).await,
+		"export" => command_export(database, args).await,
+		"tiles" => command_tiles(database, args).await,
+		"copy" => command_copy(database, room_name, args).await,
+		"vacuum" => command_vacuum(database).await,
+		_ => {
+			print_help();
+			anyhow::bail!("Unknown command \"{command}\"")
+		}
+	}
+}
+
+pub async fn run(mut args: VecDeque<String>) -> anyhow::Result<()> {
+	let (Some(room_name), Some(command)) = (args.pop_front(), args.pop_front()) else {
+		print_help();
+		return Ok(());
+	};
+
+	if command == "migrate" {
+		return command_migrate(&room_name, &mut args).await;
+	}
+
+	let (database, _lock) = open_room(&room_name, false).await?;
+	let res = run_command(&database, &room_name, &command, &mut args).await;
+	close_room(database).await;
+	res
+}
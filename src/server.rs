@@ -1,13 +1,16 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, net::IpAddr, sync::Arc, time::Duration};
 
 use tokio::sync::Mutex;
 use tokio_util::sync::CancellationToken;
 
 use crate::{
+	account::AccountSystem,
+	ban_system::BanSystemMutex,
 	config,
 	event_queue::EventQueue,
+	metrics::METRICS,
 	packet_server,
-	room::{RoomInstance, RoomInstanceMutex},
+	room::{RoomInstance, RoomInstanceMutex, RoomSessionData},
 	session::{self, SessionHandle, SessionInstance, SessionInstanceMutex, SessionState, SessionVec},
 };
 
@@ -17,47 +20,90 @@ pub struct Server {
 	pub sessions: SessionVec,
 	pub rooms: HashMap<String /* Room name */, RoomInstanceMutex>,
 	pub config: config::Config,
+	pub accounts: Arc<AccountSystem>,
+	pub bans: BanSystemMutex,
 }
 
 pub type ServerMutex = Arc<Mutex<Server>>;
 
 impl Server {
-	pub fn new(config: config::Config, cancel_token: CancellationToken) -> ServerMutex {
+	pub fn new(
+		config: config::Config,
+		accounts: Arc<AccountSystem>,
+		bans: BanSystemMutex,
+		cancel_token: CancellationToken,
+	) -> ServerMutex {
 		Arc::new(Mutex::new(Self {
 			cancel_token,
 			sessions: SessionVec::new(),
 			rooms: HashMap::new(),
 			config,
+			accounts,
+			bans,
 		}))
 	}
 
-	pub async fn save_and_exit(&mut self) -> anyhow::Result<()> {
-		self.kick_all_sessions().await?;
-		self.cleanup_rooms(true).await;
+	pub async fn save_and_exit(server_mtx: &ServerMutex) -> anyhow::Result<()> {
+		let shutdown = server_mtx
+			.lock()
+			.await
+			.config
+			.shutdown
+			.clone()
+			.unwrap_or_default();
+		Self::kick_all_sessions(
+			server_mtx,
+			&shutdown.message,
+			Duration::from_secs(shutdown.drain_timeout_s),
+		)
+		.await?;
 
-		self.cancel_token.cancel();
+		let mut server = server_mtx.lock().await;
+		server.cleanup_rooms(true).await;
+		server.cancel_token.cancel();
 		Ok(())
 	}
 
-	async fn kick_all_sessions(&mut self) -> anyhow::Result<()> {
-		let mut handles = Vec::new();
-
-		for (idx, cell) in self.sessions.vec.iter().enumerate() {
-			if let Some(cell) = cell {
-				let handle = SessionVec::get_handle(cell, idx);
-				log::info!("Cleaning-up session ID {idx}");
-				let mut session = cell.obj.session.lock().await;
-				session.kick("Server closed");
+	// Sessions are drained concurrently, so a stalled client cannot hold up the others.
+	// Sessions which don't finish within the drain timeout are left to their connection task; their rooms are still saved.
+	// The server is unlocked while draining, session cleanup locks it to leave the room.
+	// Sessions are removed from the server by their connection task, which ends once the session is cleaned up.
+	async fn kick_all_sessions(
+		server_mtx: &ServerMutex,
+		cause: &str,
+		drain_timeout: Duration,
+	) -> anyhow::Result<()> {
+		let sessions: Vec<(SessionHandle, SessionInstanceMutex)> = server_mtx
+			.lock()
+			.await
+			.sessions
+			.vec
+			.iter()
+			.enumerate()
+			.filter_map(|(idx, cell)| {
+				let cell = cell.as_ref()?;
+				Some((SessionVec::get_handle(cell, idx), cell.obj.session.clone()))
+			})
+			.collect();
+
+		let drains = sessions.iter().map(|(handle, session_mtx)| async move {
+			let drained = tokio::time::timeout(drain_timeout, async {
+				let mut session = session_mtx.lock().await;
+				session.kick(cause);
 				// Send remaining data to the client
 				let _dont_care = session.send_all().await;
-				session.cleanup(&handle).await;
-				handles.push(handle);
+				session.cleanup(handle).await;
+			})
+			.await;
+
+			if drained.is_err() {
+				log::warn!(
+					"Session ID {} didn't drain in time, dropping it",
+					handle.id()
+				);
 			}
-		}
-
-		for handle in handles {
-			self.remove_session(&handle).await;
-		}
+		});
+		futures_util::future::join_all(drains).await;
 
 		Ok(())
 	}
@@ -65,8 +111,15 @@ impl Server {
 	pub fn create_session(
 		&mut self,
 		cancel_token: CancellationToken,
+		ip: IpAddr,
 	) -> (SessionHandle, SessionInstanceMutex) {
-		let session_mtx = Arc::new(Mutex::new(SessionInstance::new(cancel_token)));
+		let rate_limit = self.config.rate_limit.clone().unwrap_or_default();
+		METRICS.sessions.inc();
+		let session_mtx = Arc::new(Mutex::new(SessionInstance::new(
+			cancel_token,
+			ip,
+			rate_limit,
+		)));
 		(
 			self.sessions.add(session::SessionContainer {
 				session: session_mtx.clone(),
@@ -85,7 +138,9 @@ impl Server {
 		let room = Arc::new(Mutex::new(
 			RoomInstance::new(room_name, &self.config).await?,
 		));
+		RoomInstance::launch_tasks(&room).await;
 		self.rooms.insert(String::from(room_name), room.clone());
+		METRICS.rooms.inc();
 		Ok(room)
 	}
 
@@ -120,12 +175,25 @@ impl Server {
 		for room_name in rooms_to_remove {
 			log::info!("Freeing room with name {room_name}");
 			self.rooms.remove(room_name.as_str());
+			METRICS.rooms.dec();
 		}
 	}
 
+	// Sessions of all loaded rooms
+	pub async fn get_all_room_sessions(&self) -> Vec<RoomSessionData> {
+		let mut sessions = Vec::new();
+		for room in self.rooms.values() {
+			sessions.extend(room.lock().await.get_all_sessions(None));
+		}
+		sessions
+	}
+
 	pub async fn remove_session(&mut self, session_handle: &SessionHandle) {
+		if !self.sessions.remove(session_handle) {
+			return;
+		}
 		log::info!("Removing session ID {}", session_handle.id());
-		self.sessions.remove(session_handle);
+		METRICS.sessions.dec();
 		self.cleanup_rooms(false).await;
 	}
 }
@@ -1,14 +1,28 @@
+use crate::account::{self, AuthResult, Credentials};
+use crate::ban_system::{BanSystem, BanTarget};
 use crate::canvas_cache::CanvasCache;
 use crate::chunk::cache::ChunkCache;
-use crate::chunk::chunk::{ChunkInstanceWeak, ChunkPixelRGBA};
+use crate::chunk::chunk::{get_empty_chunk_rgba, ChunkInstanceWeak, ChunkPixelRGBA};
 use crate::chunk::compositor::LayerID;
+use crate::chunk::layer::{LayerRGBA, RGBAData};
 use crate::chunk::system::ChunkSystem;
 use crate::chunk::writer::ChunkWriterRGBA;
+use crate::compression::decompress_lz4;
+use crate::config::RateLimit;
+use crate::database::{get_unix_timestamp, DatabaseFunc};
 use crate::event_queue::EventQueue;
-use crate::limits::CHUNK_SIZE_PX;
+use crate::export::{self, Region};
+use crate::history::{History, HistoryCell, HistoryPixel};
+use crate::import;
+use crate::limits::{CHUNK_IMAGE_SIZE_BYTES_RGBA, CHUNK_SIZE_PX};
+use crate::metrics::METRICS;
 use crate::packet_client::ClientCmd;
 use crate::pixel::{ColorRGB, ColorRGBA, GlobalPixelRGBA};
-use crate::room::{RoomInstanceMutex, RoomRefs};
+use crate::plugin_system::{PluginEvent, PluginSystem};
+use crate::rate_limit::{RateLimiter, Verdict};
+use crate::region_system::{ProtectedRegion, RegionActor};
+use crate::role_system::{Capability, Role};
+use crate::room::{RoomInstanceMutex, RoomRefs, RoomSessionData};
 use crate::serial_generator::SerialGenerator;
 use crate::server::ServerMutex;
 use crate::tool::iter_line::LineMoveIter;
@@ -18,10 +32,11 @@ use binary_reader::BinaryReader;
 use futures_util::SinkExt;
 use glam::IVec2;
 use parking_lot::Mutex as SyncMutex;
-use std::collections::{HashSet, VecDeque};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::error::Error;
+use std::net::IpAddr;
 use std::sync::{Arc, Weak};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::{fmt, io};
 use tokio::sync::{Mutex, Notify};
 use tokio::task::JoinHandle;
@@ -97,10 +112,6 @@ impl LinkedChunk {
 	}
 }
 
-struct HistoryCell {
-	pixels: Vec<GlobalPixelRGBA>,
-}
-
 #[derive(Default)]
 struct Boundary {
 	start_x: i32,
@@ -110,45 +121,9 @@ struct Boundary {
 	zoom: f32,
 }
 
-#[derive(Default)]
-struct History {
-	cells: Vec<HistoryCell>,
-	modified_pixel_coords: HashSet<IVec2>,
-}
-
-impl History {
-	fn create_snapshot(&mut self) {
-		if self.cells.len() > 50 {
-			self.cells.remove(0);
-		}
-
-		self.modified_pixel_coords.clear();
-
-		self.cells.push(HistoryCell {
-			pixels: Vec::default(),
-		});
-	}
-
-	fn add_pixel(&mut self, pixel: GlobalPixelRGBA) {
-		if self.cells.is_empty() {
-			self.create_snapshot();
-		}
-
-		if let Some(last) = self.cells.last_mut() {
-			if self.modified_pixel_coords.insert(pixel.pos) {
-				last.pixels.push(pixel);
-			}
-		}
-	}
-
-	fn undo(&mut self) -> Option<HistoryCell> {
-		self.cells.pop()
-	}
-}
-
 #[derive(Default)]
 pub struct Cursor {
-	pos: packet_client::PacketCursorPos,
+	pub pos: packet_client::PacketCursorPos,
 	pos_prev: packet_client::PacketCursorPos,
 	pos_sent: Option<packet_client::PacketCursorPos>,
 	down: bool,
@@ -159,13 +134,19 @@ pub struct Cursor {
 pub struct SessionState {
 	pub nick_name: String, // Max 255 characters
 	pub cursor: Cursor,
+	pub account: Option<String>, // Name of the logged-in account, None for guests
+	pub role: Role,              // Role in the current room
+	pub ip: Option<IpAddr>,
+	pub muted: bool,
+	pub spectator: bool,     // Receives the canvas, but cannot draw
+	pub hidden: bool,        // Not announced to other sessions in the room
+	pub server_admin: bool,  // Logged in with an admin account or admin_password, owner of every room
+	pub history_owner: bool, // Loads and saves the undo history of its account, one session per account in a room
 }
 
 pub struct SessionInstance {
 	pub state: Arc<SyncMutex<SessionState>>,
 
-	admin_mode: bool,
-
 	notifier: Arc<Notify>,
 	pub queue_send: EventQueue<packet_server::Packet>,
 
@@ -180,12 +161,28 @@ pub struct SessionInstance {
 
 	chunk_cache: ChunkCache,
 
+	// Canvas is shown as it was at this unix timestamp (read-only), live if None
+	view_timestamp: Option<u64>,
+
+	// Status text about writes denied by protected regions is currently shown
+	region_denied_shown: bool,
+
 	room_refs: Option<Arc<RoomRefs>>,
 
 	history: History,
+	// Undo history of logged-in users is saved periodically, guests keep it in memory only
+	history_save_interval: Duration,
+	history_saved_at: Instant,
+	// Nick name requested when announcing, the name /register creates the account with
+	identity: Option<String>,
 
 	tool: ToolData,
 
+	rate_limiter: RateLimiter,
+	rate_limit_notified_at: Option<Instant>, // Throttling is reported at most once a second
+	// Kicked for exceeding rate limits, the connection is closed even if the client ignores the kick
+	rate_limit_exceeded: bool,
+
 	kicked: bool,
 	announced: bool,
 	cleaned_up: bool,
@@ -207,30 +204,40 @@ enum UpdateBrushType {
 }
 
 impl SessionInstance {
-	pub fn new(cancel_token: CancellationToken) -> Self {
+	pub fn new(cancel_token: CancellationToken, ip: IpAddr, rate_limit: RateLimit) -> Self {
 		let notifier = Arc::new(Notify::new());
 
 		Self {
 			cancel_token,
-			state: Arc::new(SyncMutex::new(SessionState::default())),
+			state: Arc::new(SyncMutex::new(SessionState {
+				ip: Some(ip),
+				..Default::default()
+			})),
 			kicked: false,
 			announced: false,
 			needs_boundary_test: false,
 			room_refs: None,
 			chunk_cache: ChunkCache::default(),
+			view_timestamp: None,
+			region_denied_shown: false,
 			tool: ToolData::default(),
+			rate_limiter: RateLimiter::new(rate_limit),
+			rate_limit_notified_at: None,
+			rate_limit_exceeded: false,
 			notifier: notifier.clone(),
-			queue_send: EventQueue::new(notifier),
+			queue_send: EventQueue::new(notifier).with_depth_gauge(&METRICS.session_send_queue),
 			chunks_received: 0,
 			chunks_sent: 0,
 			linked_chunks: Vec::default(),
 			boundary: Boundary::default(),
 			history: History::default(),
+			history_save_interval: Duration::ZERO,
+			history_saved_at: Instant::now(),
+			identity: None,
 			cleaned_up: false,
 			task_sender: None,
 			task_tick: None,
 			writer: None,
-			admin_mode: false,
 			tool_state: ToolState::None,
 			serial_generator: SerialGenerator::new(),
 		}
@@ -259,9 +266,13 @@ impl SessionInstance {
 
 				session.update_tool_state(&room_refs).await;
 
-				if ticks % 20 == 0 {
+				if ticks.is_multiple_of(20) {
 					session.tick_chunks_cleanup(session_handle).await;
 				}
+
+				if session.history_saved_at.elapsed() >= session.history_save_interval {
+					session.save_history(&room_refs).await;
+				}
 			}
 
 			ticks += 1;
@@ -342,6 +353,15 @@ impl SessionInstance {
 			return Ok(());
 		}
 
+		if self.state().spectator
+			&& matches!(
+				command,
+				ClientCmd::CursorDown | ClientCmd::ToolType | ClientCmd::Undo | ClientCmd::Redo
+			) {
+			log::trace!("Rejected {command:?} from a spectator");
+			return Ok(());
+		}
+
 		if let Some(refs) = &self.room_refs {
 			let refs = refs.clone();
 			match command {
@@ -350,7 +370,13 @@ impl SessionInstance {
 				}
 				ClientCmd::Message => {
 					self
-						.process_command_message(reader, session_weak.clone(), &refs, server_mtx)
+						.process_command_message(
+							reader,
+							session_weak.clone(),
+							&refs,
+							session_handle,
+							server_mtx,
+						)
 						.await?;
 				}
 				ClientCmd::Ping => { /* do nothing */ }
@@ -372,11 +398,17 @@ impl SessionInstance {
 				ClientCmd::Boundary => self.process_command_boundary(reader)?,
 				ClientCmd::ChunksReceived => self.process_command_chunks_received(reader)?,
 				ClientCmd::PreviewRequest => self.process_command_preview_request(&refs, reader).await?,
+				ClientCmd::ViewTimestamp => {
+					self
+						.process_command_view_timestamp(reader, session_handle)
+						.await?;
+				}
 				ClientCmd::ToolSize => self.process_command_tool_size(reader)?,
 				ClientCmd::ToolFlow => self.process_command_tool_flow(reader)?,
 				ClientCmd::ToolColor => self.process_command_tool_color(reader)?,
 				ClientCmd::ToolType => self.process_command_tool_type(reader)?,
 				ClientCmd::Undo => self.process_command_undo(&refs, reader).await,
+				ClientCmd::Redo => self.process_command_redo(&refs, reader).await,
 			}
 		}
 
@@ -407,12 +439,16 @@ impl SessionInstance {
 		server_mtx: &ServerMutex,
 	) -> anyhow::Result<()> {
 		if let Err(e) = self
-			.process_payload_wrap(session_weak, session_handle, data, server_mtx)
+			.process_payload_wrap(session_weak.clone(), session_handle, data, server_mtx)
 			.await
 		{
 			self.handle_error(&e)?;
 		}
 
+		if std::mem::take(&mut self.rate_limit_exceeded) {
+			Self::disconnect(session_weak, String::from("Rate limit exceeded"));
+		}
+
 		Ok(())
 	}
 
@@ -428,6 +464,23 @@ impl SessionInstance {
 
 		let command = ClientCmd::try_from(reader.read_u16()?)?;
 
+		if self.is_rate_limited() {
+			let verdict = self.rate_limiter.check_packet();
+			if command.is_throttleable() {
+				let action = if command == ClientCmd::Message {
+					"sending messages"
+				} else {
+					"drawing"
+				};
+				if !self.handle_rate_limit(verdict, action) {
+					return Ok(());
+				}
+			} else if matches!(verdict, Verdict::Kick) {
+				// Control packets are never dropped, the client would get out of sync
+				self.handle_rate_limit(verdict, "sending packets");
+			}
+		}
+
 		//log::trace!("Session ID: {}, command {:?}", session_id, command);
 		self
 			.parse_command(
@@ -442,7 +495,7 @@ impl SessionInstance {
 		Ok(())
 	}
 
-	fn state(&self) -> parking_lot::MutexGuard<SessionState> {
+	fn state(&self) -> parking_lot::MutexGuard<'_, SessionState> {
 		self.state.lock()
 	}
 
@@ -643,7 +696,7 @@ impl SessionInstance {
 		drop(brush_shapes);
 
 		for (index, line) in iter.enumerate() {
-			if index as u32 % u32::from(step) != 0 {
+			if !(index as u32).is_multiple_of(u32::from(step)) {
 				continue;
 			}
 
@@ -982,10 +1035,53 @@ impl SessionInstance {
 		pixels: &[GlobalPixelRGBA],
 		with_history: bool,
 	) {
+		if self.is_rate_limited() {
+			let verdict = self.rate_limiter.check_pixels(pixels.len());
+			if !self.handle_rate_limit(verdict, "drawing") {
+				// Lift the pen, the stroke has to be started again
+				self.state().cursor.down = false;
+				return;
+			}
+		}
+
+		let (changed, denied) = self.write_pixels_main(refs, pixels, None).await;
+
+		if denied > 0 && !self.region_denied_shown {
+			self.queue_send_status_text("Cannot draw in a protected region");
+			self.region_denied_shown = true;
+		} else if denied == 0 && self.region_denied_shown {
+			self.queue_send_status_text("");
+			self.region_denied_shown = false;
+		}
+
+		if with_history {
+			for pixel in changed {
+				self.history.add_pixel(pixel);
+			}
+		}
+	}
+
+	// Returns changed pixels along with the colors they replaced.
+	// Pixels inside protected regions this session cannot modify are skipped and counted.
+	// If `expected` is set, pixels not holding the expected color anymore are skipped and counted too.
+	async fn write_pixels_main(
+		&mut self,
+		refs: &RoomRefs,
+		pixels: &[GlobalPixelRGBA],
+		expected: Option<&HashMap<IVec2, ColorRGBA>>,
+	) -> (Vec<HistoryPixel>, usize) {
+		let actor = self.region_actor();
+		let (pixels, mut skipped) = refs
+			.region_system_mtx
+			.lock()
+			.await
+			.filter_writable(pixels, &actor);
+
+		let mut changed = Vec::new();
 		let mut writer = ChunkWriterRGBA::new();
 
 		writer
-			.generate_affected(pixels, &mut self.chunk_cache, &refs.chunk_system_mtx)
+			.generate_affected(&pixels, &mut self.chunk_cache, &refs.chunk_system_mtx)
 			.await;
 
 		// For every affected chunk
@@ -997,26 +1093,65 @@ impl SessionInstance {
 			let mut chunk = cell.chunk.lock().await;
 			chunk.allocate_image();
 
-			if with_history {
-				for (local_pos, global_pos) in &cell.queued_pixels {
-					let color = chunk.get_pixel_main(local_pos.pos);
+			let mut queued_pixels = Vec::<ChunkPixelRGBA>::with_capacity(cell.queued_pixels.len());
 
-					if local_pos.color != color {
-						self.history.add_pixel(GlobalPixelRGBA {
-							pos: *global_pos,
-							color,
-						});
+			for (local_pos, global_pos) in &cell.queued_pixels {
+				let color = chunk.get_pixel_main(local_pos.pos);
+
+				if let Some(expected) = expected {
+					if expected.get(global_pos) != Some(&color) {
+						// Modified by someone else in the meantime, leave it as is
+						skipped += 1;
+						continue;
 					}
 				}
+
+				if local_pos.color != color {
+					changed.push(HistoryPixel {
+						pos: *global_pos,
+						previous: color,
+						written: local_pos.color,
+					});
+				}
+
+				queued_pixels.push(local_pos.clone());
 			}
 
-			let queued_pixels: Vec<ChunkPixelRGBA> =
-				cell.queued_pixels.iter().map(|c| c.0.clone()).collect();
+			if queued_pixels.is_empty() {
+				continue;
+			}
 
 			let threshold = CHUNK_SIZE_PX * (CHUNK_SIZE_PX / 5); // over 1/5th of chunk modified
-			let send_whole_chunk = cell.queued_pixels.len() > threshold as usize;
+			let send_whole_chunk = queued_pixels.len() > threshold as usize;
 			chunk.set_pixels(&queued_pixels, send_whole_chunk);
 		}
+
+		(changed, skipped)
+	}
+
+	// Restores previous colors of the cell, but only for pixels still holding the color written by this session.
+	// Returns the pixels needed to revert this operation and the number of skipped pixels.
+	async fn apply_history_cell(
+		&mut self,
+		refs: &RoomRefs,
+		cell: &HistoryCell,
+	) -> (Vec<HistoryPixel>, usize) {
+		let pixels: Vec<GlobalPixelRGBA> = cell
+			.pixels
+			.iter()
+			.map(|pixel| GlobalPixelRGBA {
+				pos: pixel.pos,
+				color: pixel.previous,
+			})
+			.collect();
+
+		let expected: HashMap<IVec2, ColorRGBA> = cell
+			.pixels
+			.iter()
+			.map(|pixel| (pixel.pos, pixel.written))
+			.collect();
+
+		self.write_pixels_main(refs, &pixels, Some(&expected)).await
 	}
 
 	pub async fn send_all(&self) -> anyhow::Result<()> {
@@ -1033,6 +1168,10 @@ impl SessionInstance {
 		}
 	}
 
+	pub const fn is_announced(&self) -> bool {
+		self.announced
+	}
+
 	pub fn kick(&mut self, cause: &str) {
 		if self.kicked {
 			//Enough
@@ -1044,7 +1183,32 @@ impl SessionInstance {
 		self.kicked = true;
 	}
 
+	// Kick the session and close its connection, even if the client ignores the kick packet.
+	// Runs in a separate task, so a session can disconnect another one without locking it directly.
+	pub fn disconnect(session_weak: SessionInstanceWeak, cause: String) {
+		tokio::spawn(async move {
+			let Some(session_mtx) = session_weak.upgrade() else {
+				return;
+			};
+
+			let cancel_token = {
+				let mut session = session_mtx.lock().await;
+				session.kick(&cause);
+				let _dont_care = session.send_all().await;
+				session.cancel_token.clone()
+			};
+
+			// Don't keep the session alive while it is being freed
+			drop(session_mtx);
+			cancel_token.cancel();
+		});
+	}
+
+	// Called by the connection task and by the shutdown drain, whichever comes first
 	pub async fn cleanup(&mut self, session_handle: &SessionHandle) {
+		if self.cleaned_up {
+			return;
+		}
 		self.cleaned_up = true;
 
 		// Cancel all session tasks
@@ -1056,33 +1220,91 @@ impl SessionInstance {
 			task.abort();
 		}
 
-		if let Some(refs) = &self.room_refs {
-			let refs = refs.clone();
-
+		if let Some(refs) = self.room_refs.take() {
 			// Unlink all chunks
-			while let Some(lchunk) = self.linked_chunks.last() {
-				self
-					.unlink_chunk(session_handle, lchunk.chunk.clone())
-					.await;
-			}
+			self.unlink_all_chunks(session_handle).await;
 
+			self.save_history(&refs).await;
 			self.leave_room(&refs, session_handle).await;
 		}
 
 		self.cancel_token.cancel();
 	}
 
+	// History is persisted only for accounts, a guest nick name can be taken by anyone.
+	// Further sessions of the same account keep their history in memory, so they don't overwrite each other's saves.
+	async fn load_history(&mut self, refs: &RoomRefs) {
+		let Some(account) = self.state().account.clone() else {
+			return;
+		};
+
+		{
+			let room = refs.room_mtx.lock().await;
+			let taken = room.get_all_sessions(None).iter().any(|session| {
+				let state = session.state.lock();
+				state.history_owner
+					&& state
+						.account
+						.as_deref()
+						.is_some_and(|other| other.eq_ignore_ascii_case(&account))
+			});
+			if taken {
+				log::debug!("History of {account} is used by another session, not persisting it");
+				return;
+			}
+			self.state().history_owner = true;
+		}
+
+		match DatabaseFunc::history_load(&refs.database, account.clone()).await {
+			Ok(records) => {
+				self.history.load(&records);
+				if !records.is_empty() {
+					log::debug!("Loaded {} history cells for {account}", records.len());
+				}
+			}
+			Err(e) => log::error!("Failed to load history for {account}: {e}"),
+		}
+	}
+
+	async fn save_history(&mut self, refs: &RoomRefs) {
+		self.history_saved_at = Instant::now();
+
+		if !self.history.is_modified() {
+			return;
+		}
+
+		let account = {
+			let state = self.state();
+			match &state.account {
+				Some(account) if state.history_owner => account.clone(),
+				_ => return,
+			}
+		};
+
+		if let Err(e) =
+			DatabaseFunc::history_save(&refs.database, account.clone(), self.history.encode()).await
+		{
+			log::error!("Failed to save history for {account}: {e}");
+			return;
+		}
+		self.history.mark_saved();
+	}
+
 	pub async fn leave_room(&self, refs: &RoomRefs, session_handle: &SessionHandle) {
+		PluginSystem::dispatch(refs, PluginEvent::UserLeave(session_handle.id())).await;
+
 		let mut room = refs.room_mtx.lock().await;
 
 		// Remove itself from the room
 		room.remove_session(session_handle);
 
 		// Announce to all other sessions that our session is leaving this room
-		room.broadcast(
-			&packet_server::prepare_packet_user_remove(session_handle.id()),
-			Some(session_handle),
-		);
+		if !self.state().hidden {
+			room.broadcast(
+				&packet_server::prepare_packet_user_remove(session_handle.id()),
+				Some(session_handle),
+			);
+		}
 	}
 
 	async fn join_room(
@@ -1103,18 +1325,37 @@ impl SessionInstance {
 			)
 			.await?;
 
+		let history_max_bytes = server
+			.config
+			.history_max_bytes
+			.unwrap_or(limits::HISTORY_MAX_BYTES_DEFAULT);
+		self.history_save_interval =
+			Duration::from_millis(u64::from(server.config.autosave_interval_ms));
+		drop(server);
+
+		self.history = History::new(history_max_bytes);
+		self.history_saved_at = Instant::now();
+
 		let room = room_mtx.lock().await;
+		let database = room.database.clone();
 		let brush_shapes_mtx = room.brush_shapes.clone();
 		let chunk_system_mtx = room.chunk_system.clone();
 		let preview_system_mtx = room.preview_system.clone();
+		let plugin_system_mtx = room.plugin_system.clone();
+		let region_system_mtx = room.region_system.clone();
+		let role_system_mtx = room.role_system.clone();
 		let chunk_system_sender = room.chunk_system_sender.clone();
 		drop(room);
 
 		self.room_refs = Some(Arc::new(RoomRefs {
 			room_mtx: room_mtx.clone(),
+			database,
 			brush_shapes_mtx,
 			chunk_system_mtx,
 			preview_system_mtx,
+			plugin_system_mtx,
+			region_system_mtx,
+			role_system_mtx,
 			chunk_system_sender,
 		}));
 
@@ -1188,6 +1429,15 @@ impl SessionInstance {
 			}
 		}
 
+		let (identity, server_admin) = self
+			.authenticate(server_mtx, &packet.nick_name, packet.credentials)
+			.await?;
+		self.state().server_admin = server_admin;
+
+		self
+			.set_spectator(server_mtx, &packet.room_name, packet.spectator)
+			.await;
+
 		self
 			.queue_send
 			.send(packet_server::prepare_packet_your_id(session_handle.id()));
@@ -1200,29 +1450,114 @@ impl SessionInstance {
 		let suitable_nick = room_mtx
 			.lock()
 			.await
-			.get_suitable_nick_name(packet.nick_name.as_str(), session_handle);
+			.get_suitable_nick_name(identity.as_str(), session_handle);
 
 		self.state().nick_name = suitable_nick;
 
+		// Restore undo history left by the previous session of this user
+		self.identity = Some(identity);
+		if let Some(refs) = self.room_refs.clone() {
+			let role = if server_admin {
+				Role::Owner
+			} else {
+				let account = self.state().account.clone();
+				refs
+					.role_system_mtx
+					.lock()
+					.await
+					.resolve(account.as_deref())
+			};
+			self.state().role = role;
+
+			self.load_history(&refs).await;
+		}
+
 		// Broadcast to all users that this user is available
 		self.broadcast_self(room_mtx, session_handle).await;
 
+		if let Some(refs) = &self.room_refs {
+			PluginSystem::dispatch(refs, PluginEvent::UserJoin(session_handle.id())).await;
+		}
+
 		// Reset tool state
 		self.tool = ToolData::default();
 
 		Ok(())
 	}
 
+	// Returns the identity of the user, which is the account name if logged in, and whether the account is a server admin.
+	// Fails if the account or IP address is banned.
+	async fn authenticate(
+		&self,
+		server_mtx: &ServerMutex,
+		nick_name: &str,
+		credentials: Credentials,
+	) -> anyhow::Result<(String, bool)> {
+		let (accounts, bans) = {
+			let server = server_mtx.lock().await;
+			(server.accounts.clone(), server.bans.clone())
+		};
+
+		let res = match accounts.authenticate(nick_name, credentials).await? {
+			AuthResult::Guest => (String::from(nick_name), false),
+			AuthResult::LoggedIn(account) => {
+				log::info!("Session logged in as {}", account.name);
+				self.state().account = Some(account.name.clone());
+				(account.name, account.admin)
+			}
+			AuthResult::NicknameReserved => Err(UserError::new(
+				"This nick name is registered. Log in or choose a different one.",
+			))?,
+			AuthResult::InvalidCredentials => Err(UserError::new("Invalid credentials"))?,
+		};
+
+		let (ip, account) = {
+			let state = self.state();
+			(state.ip, state.account.clone())
+		};
+
+		let ban = bans.lock().await.check(ip, account.as_deref());
+		if let Some(ban) = ban {
+			Err(UserError::new(&ban.describe()))?;
+		}
+
+		Ok(res)
+	}
+
+	// Guests always join spectator rooms as spectators
+	async fn set_spectator(&self, server_mtx: &ServerMutex, room_name: &str, requested: bool) {
+		let (spectator_room, hide_spectators) = {
+			let server = server_mtx.lock().await;
+			(
+				server
+					.config
+					.spectator_rooms
+					.as_ref()
+					.is_some_and(|rooms| rooms.iter().any(|room| room == room_name)),
+				server.config.hide_spectators.unwrap_or(false),
+			)
+		};
+
+		let mut state = self.state();
+		state.spectator = requested || (spectator_room && state.account.is_none());
+		state.hidden = state.spectator && hide_spectators;
+	}
+
 	async fn broadcast_self(&self, room_mtx: RoomInstanceMutex, session_handle: &SessionHandle) {
 		let room = room_mtx.lock().await;
 
-		let nick_name = self.state().nick_name.clone();
+		let (nick_name, hidden) = {
+			let state = self.state();
+			(state.nick_name.clone(), state.hidden)
+		};
 
 		// Announce itself to other existing sessions
-		room.broadcast(
-			&packet_server::prepare_packet_user_create(session_handle.id(), &nick_name),
-			Some(session_handle),
-		);
+		if !hidden {
+			room.broadcast(
+				&packet_server::prepare_packet_user_create(session_handle.id(), &nick_name),
+				Some(session_handle),
+			);
+		}
 
 		// Send annountement packets to this session from all other existing sessions (its positions and states)
 		let other_sessions = room.get_all_sessions(Some(session_handle));
@@ -1233,6 +1568,9 @@ impl SessionInstance {
 			let other_session_id = other_session.handle.id();
 
 			let other_state = other_session.state.lock();
+			if other_state.hidden {
+				continue;
+			}
 
 			//Send user creation packet
 			self
@@ -1258,6 +1596,7 @@ impl SessionInstance {
 		reader: &mut BinaryReader,
 		session_weak: SessionInstanceWeak,
 		refs: &RoomRefs,
+		session_handle: &SessionHandle,
 		server_mtx: &ServerMutex,
 	) -> anyhow::Result<()> {
 		let msg_len = reader.read_u16()?;
@@ -1274,13 +1613,20 @@ impl SessionInstance {
 			return Ok(()); // unexpected but ok, just ignore it
 		}
 
+		if self.is_rate_limited() {
+			let verdict = self.rate_limiter.check_message();
+			if !self.handle_rate_limit(verdict, "sending messages") {
+				return Ok(());
+			}
+		}
+
 		if let Some(ch) = str.chars().nth(0) {
 			if ch == '/' {
 				self
-					.handle_chat_command(session_weak, server_mtx, refs, &str[1..])
+					.handle_chat_command(session_weak, server_mtx, refs, session_handle, &str[1..])
 					.await?;
 			} else {
-				self.handle_chat_message(refs, str).await;
+				self.handle_chat_message(refs, session_handle, str).await;
 			}
 		}
 
@@ -1303,12 +1649,82 @@ impl SessionInstance {
 		));
 	}
 
-	fn send_unauthenticated(&self) {
-		self.send_reply_stylized("[error]Unauthenticated[/error]");
+	fn send_insufficient_permissions(&self) {
+		self.send_reply_stylized("[error]Insufficient permissions[/error]");
+	}
+
+	fn can(&self, capability: Capability) -> bool {
+		let state = self.state();
+		if state.spectator && matches!(capability, Capability::Draw | Capability::Fill) {
+			return false;
+		}
+		if capability == Capability::Ban {
+			return state.server_admin;
+		}
+		state.role.can(capability)
+	}
+
+	// Moderators and owners are trusted, they also run large imports and rollbacks
+	fn is_rate_limited(&self) -> bool {
+		!self.can(Capability::Kick)
+	}
+
+	// Returns true if the action is allowed, otherwise notifies the user or kicks them
+	fn handle_rate_limit(&mut self, verdict: Verdict, action: &str) -> bool {
+		match verdict {
+			Verdict::Allowed => return true,
+			Verdict::Denied | Verdict::Throttled => {
+				let now = Instant::now();
+				if self
+					.rate_limit_notified_at
+					.is_none_or(|notified_at| now.duration_since(notified_at) >= Duration::from_secs(1))
+				{
+					self.rate_limit_notified_at = Some(now);
+					self.queue_send_status_text(format!("You are {action} too fast, slow down").as_str());
+				}
+			}
+			Verdict::Muted(remaining) => {
+				self.queue_send_status_text(
+					format!(
+						"You are {action} too fast, muted for {} seconds",
+						remaining.as_secs().max(1)
+					)
+					.as_str(),
+				);
+			}
+			Verdict::Kick => {
+				log::warn!(
+					"Kicking {} for exceeding rate limits",
+					self.state().nick_name
+				);
+				self.kick("Rate limit exceeded");
+				self.rate_limit_exceeded = true;
+			}
+		}
+		false
+	}
+
+	fn region_actor(&self) -> RegionActor {
+		let state = self.state();
+		RegionActor {
+			account: state.account.clone(),
+			manager: state.role.can(Capability::ManageRegions),
+		}
 	}
 
-	async fn handle_chat_message(&self, refs: &RoomRefs, mut msg: &str) {
+	async fn handle_chat_message(
+		&self,
+		refs: &RoomRefs,
+		session_handle: &SessionHandle,
+		mut msg: &str,
+	) {
 		msg = msg.trim();
+
+		if !self.can(Capability::Chat) || self.state().muted {
+			self.queue_send_status_text("You cannot send messages");
+			return;
+		}
+
 		let nick_name = self.state().nick_name.clone();
 		log::info!("Chat message: {msg}");
 
@@ -1322,6 +1738,13 @@ impl SessionInstance {
 			),
 			None,
 		);
+		drop(room);
+
+		PluginSystem::dispatch(
+			refs,
+			PluginEvent::Message(session_handle.id(), String::from(msg)),
+		)
+		.await;
 	}
 
 	async fn handle_chat_command(
@@ -1329,13 +1752,19 @@ impl SessionInstance {
 		_session_weak: SessionInstanceWeak,
 		server_mtx: &ServerMutex,
 		refs: &RoomRefs,
+		session_handle: &SessionHandle,
 		msg: &str,
 	) -> anyhow::Result<()> {
-		log::info!("Command requested: {msg}");
-
 		let mut parts: VecDeque<&str> = msg.split(' ').collect();
 		if let Some(command) = parts.pop_front() {
-			let redacted = command == "admin";
+			// Commands carrying credentials
+			let redacted = matches!(command, "admin" | "register" | "passwd");
+
+			if redacted {
+				log::info!("Command requested: {command} <redacted>");
+			} else {
+				log::info!("Command requested: {msg}");
+			}
 
 			self.send_reply_stylized(&format!(
 				"[color=green]/{}[/color]",
@@ -1346,73 +1775,718 @@ impl SessionInstance {
 				}
 			));
 
-			match command {
-				"help" | "?" => {
-					self.send_reply_stylized(&String::from(
-						"
-						User commands:
-						[color=blue]help[/color]: [i]Show this message[/i]
-						[color=blue]leave[/color]: [i]Kick yourself[/i]
-						Admin commands:
-						[color=red]admin[/color]: [i]Log-in as admin[/i]
-						[color=red]process_preview_system[/color]: [i]Force-refresh preview system[/i]
-						",
-					));
+			if let Some(capability) = Self::get_command_capability(command) {
+				if !self.can(capability) {
+					self.send_insufficient_permissions();
+					return Ok(());
 				}
+			}
+
+			match command {
+				"help" | "?" => self.send_help(),
 				"leave" => self.kick("Goodbye."),
+				"role" => self.handle_role_command(refs, &mut parts).await,
 				"admin" => {
-					let server = server_mtx.lock().await;
-					if let Some(password) = parts.pop_front() {
-						if let Some(config_password) = &server.config.admin_password {
-							if config_password == password {
-								self.admin_mode = true;
-								self.send_reply_stylized("[color=blue]Authenticated[/color]");
-							} else {
-								self.send_reply_stylized("[color=red]Invalid password[/color]");
-							}
-						} else {
-							self.send_reply_stylized(
-								"[color=red]admin_password in settings.json is not set[/color]",
-							);
-						}
-					} else {
-						self.send_reply("Usage: admin <password>");
-					}
+					self
+						.handle_admin_command(server_mtx, parts.pop_front())
+						.await;
 				}
 				"process_preview_system" => {
-					if self.admin_mode {
-						self.send_reply("Preview regeneration started");
-						let cs = refs.chunk_system_mtx.clone();
-						tokio::spawn(async {
-							ChunkSystem::regenerate_all_previews(cs).await;
-						});
-					} else {
-						self.send_unauthenticated();
+					self.send_reply("Preview regeneration started");
+					let cs = refs.chunk_system_mtx.clone();
+					tokio::spawn(async {
+						ChunkSystem::regenerate_all_previews(cs).await;
+					});
+				}
+				"register" | "passwd" | "token" => {
+					self
+						.handle_account_command(command, server_mtx, &mut parts)
+						.await;
+				}
+				"region" => self.handle_region_command(refs, &mut parts).await,
+				"kick" | "ban" | "unban" | "bans" | "mute" | "unmute" => {
+					let res = self
+						.handle_moderation_command(command, server_mtx, refs, &mut parts)
+						.await;
+					match res {
+						Ok(text) => self.send_reply_stylized(&format!("[color=blue]{text}[/color]")),
+						Err(e) => self.send_reply_stylized(&format!("[color=red]{e}[/color]")),
 					}
 				}
+				"view" | "export" | "import" | "rollback" => {
+					self
+						.handle_canvas_command(command, server_mtx, refs, session_handle, &mut parts)
+						.await;
+				}
 				_ => {
-					self.send_reply_stylized("[color=red]Unknown command[/color]");
+					// Left to plugins, with the same permissions as chat messages
+					if !self.can(Capability::Chat) || self.state().muted {
+						self.send_insufficient_permissions();
+						return Ok(());
+					}
+
+					let handled_by_plugin = PluginSystem::dispatch(
+						refs,
+						PluginEvent::Command(session_handle.id(), String::from(msg)),
+					)
+					.await;
+					if !handled_by_plugin {
+						self.send_reply_stylized("[color=red]Unknown command[/color]");
+					}
 				}
 			}
 		}
 		Ok(())
 	}
 
-	async fn process_command_cursor_pos(
+	fn get_command_capability(command: &str) -> Option<Capability> {
+		match command {
+			"process_preview_system" => Some(Capability::RegeneratePreviews),
+			"view" => Some(Capability::ViewHistory),
+			"rollback" => Some(Capability::Rollback),
+			"export" => Some(Capability::Export),
+			"import" => Some(Capability::Import),
+			"kick" => Some(Capability::Kick),
+			"ban" | "unban" | "bans" => Some(Capability::Ban),
+			"mute" | "unmute" => Some(Capability::Mute),
+			_ => None,
+		}
+	}
+
+	fn send_help(&self) {
+		self.send_reply_stylized(&String::from(
+			"
+			User commands:
+			[color=blue]help[/color]: [i]Show this message[/i]
+			[color=blue]leave[/color]: [i]Kick yourself[/i]
+			[color=blue]register <password>[/color]: [i]Register your nick name as an account[/i]
+			[color=blue]passwd <password>[/color]: [i]Change your account password[/i]
+			[color=blue]token[/color]: [i]Generate a login token, replacing the previous one[/i]
+			[color=blue]region list[/color]: [i]List protected regions[/i]
+			[color=blue]region allow|deny <name> <user>[/color]: [i]Add or remove an editor of your region[/i]
+			[color=blue]region readonly <name> <on|off>[/color]: [i]Lock your region for everyone[/i]
+			[color=blue]region remove <name>[/color]: [i]Remove your region[/i]
+			[color=blue]role [list][/color]: [i]Show your role or roles of this room[/i]
+			[color=blue]admin <password>[/color]: [i]Log-in as server admin, owner of every room[/i]
+			Moderator commands:
+			[color=orange]kick <nick> [reason][/color]: [i]Disconnect the user[/i]
+			[color=orange]mute|unmute <nick>[/color]: [i]Prevent the user from sending chat messages[/i]
+			[color=orange]view <time|live>[/color]: [i]View the canvas as it was at the given time[/i]
+			[color=orange]rollback <time> <x1> <y1> <x2> <y2>[/color]: [i]Restore the region to its state at the given time[/i]
+			[color=orange]region create <name> <x1> <y1> <x2> <y2> [owner][/color]: [i]Protect the region, only its owner and editors can draw there[/i]
+			Owner commands:
+			[color=red]process_preview_system[/color]: [i]Force-refresh preview system[/i]
+			Server admin commands:
+			[color=red]ban <nick|account|ip> [duration][/color]: [i]Ban the user from the whole server, permanently if no duration is given (e.g. 30m, 7d)[/i]
+			[color=red]unban <account|ip>[/color]: [i]Remove the ban[/i]
+			[color=red]bans[/color]: [i]List active bans[/i]
+			[color=red]export <x1> <y1> <x2> <y2>[/color]: [i]Export the region to PNG on the server[/i]
+			[color=red]import <file> <x> <y> [blend][/color]: [i]Stamp a PNG from the imports directory at the given position[/i]
+			[color=red]role set <account> <role>[/color]: [i]Assign a role (viewer, artist, moderator, owner) in this room[/i]
+			[color=red]role unset <account>[/color]: [i]Remove the role assignment[/i]
+			[color=red]role guests|accounts <role>[/color]: [i]Set the default role of guests or logged-in users[/i]
+			Time is a unix timestamp or time ago, e.g. 30m, 4h, 2d
+			",
+		));
+	}
+
+	async fn handle_canvas_command(
 		&mut self,
+		command: &str,
+		server_mtx: &ServerMutex,
 		refs: &RoomRefs,
-		reader: &mut BinaryReader,
 		session_handle: &SessionHandle,
-	) -> anyhow::Result<()> {
-		{
-			let mut state = self.state();
-			state.cursor.pos_prev = state.cursor.pos.clone();
-			state.cursor.pos = packet_client::PacketCursorPos::read(reader)?;
+		parts: &mut VecDeque<&str>,
+	) {
+		match command {
+			"view" => self.handle_view_command(session_handle, parts).await,
+			"export" => self.handle_export_command(server_mtx, refs, parts).await,
+			"import" => self.handle_import_command(refs, parts).await,
+			"rollback" => self.handle_rollback_command(refs, parts).await,
+			_ => {}
 		}
+	}
 
-		self.update_cursor(refs, session_handle).await;
-
-		let (pos_sent, cursor_pos) = {
+	async fn handle_admin_command(&self, server_mtx: &ServerMutex, password: Option<&str>) {
+		let server = server_mtx.lock().await;
+		if let Some(password) = password {
+			if let Some(config_password) = &server.config.admin_password {
+				if account::verify_secret(password, config_password) {
+					let mut state = self.state();
+					state.role = Role::Owner;
+					state.server_admin = true;
+					drop(state);
+					self.send_reply_stylized("[color=blue]Authenticated[/color]");
+				} else {
+					self.send_reply_stylized("[color=red]Invalid password[/color]");
+				}
+			} else {
+				self.send_reply_stylized("[color=red]admin_password in settings.json is not set[/color]");
+			}
+		} else {
+			self.send_reply("Usage: admin <password>");
+		}
+	}
+
+	async fn handle_account_command(
+		&self,
+		command: &str,
+		server_mtx: &ServerMutex,
+		parts: &mut VecDeque<&str>,
+	) {
+		let accounts = server_mtx.lock().await.accounts.clone();
+		let account = self.state().account.clone();
+
+		let res = match (command, &account, &self.identity) {
+			("register", None, Some(identity)) => {
+				let Some(password) = parts.pop_front() else {
+					self.send_reply("Usage: register <password>");
+					return;
+				};
+
+				let name = identity.clone();
+				accounts
+					.register(&name, String::from(password))
+					.await
+					.map(|()| {
+						// A new account has no other session, this one keeps its history
+						let mut state = self.state();
+						state.account = Some(name.clone());
+						state.history_owner = true;
+						drop(state);
+						format!("Registered account {name}, use your password to log in next time")
+					})
+			}
+			("passwd", Some(name), _) => {
+				let Some(password) = parts.pop_front() else {
+					self.send_reply("Usage: passwd <password>");
+					return;
+				};
+
+				accounts
+					.set_password(name, String::from(password))
+					.await
+					.map(|()| String::from("Password changed, login token revoked"))
+			}
+			("token", Some(name), _) => accounts
+				.issue_token(name)
+				.await
+				.map(|token| format!("Login token: {token}")),
+			("register", Some(_), _) => {
+				self.send_reply_stylized("[color=red]Already logged in[/color]");
+				return;
+			}
+			_ => {
+				self.send_reply_stylized("[color=red]Not logged in[/color]");
+				return;
+			}
+		};
+
+		match res {
+			Ok(text) => self.send_reply_stylized(&format!("[color=blue]{text}[/color]")),
+			Err(e) => self.send_reply_stylized(&format!("[color=red]{e}[/color]")),
+		}
+	}
+
+	// Moderators cannot act on users with the same or a higher role, owners can act on anyone
+	fn can_moderate(&self, target: &RoomSessionData) -> bool {
+		let role = self.state().role;
+		role == Role::Owner || target.state.lock().role < role
+	}
+
+	async fn handle_moderation_command(
+		&self,
+		command: &str,
+		server_mtx: &ServerMutex,
+		refs: &RoomRefs,
+		parts: &mut VecDeque<&str>,
+	) -> anyhow::Result<String> {
+		let bans = server_mtx.lock().await.bans.clone();
+
+		if command == "bans" {
+			let mut ban_system = bans.lock().await;
+			let list: Vec<String> = ban_system.list().iter().map(ToString::to_string).collect();
+			return Ok(if list.is_empty() {
+				String::from("No active bans")
+			} else {
+				list.join(", ")
+			});
+		}
+
+		let Some(target) = parts.pop_front() else {
+			anyhow::bail!("Usage: {command} <nick>");
+		};
+
+		if command == "unban" {
+			let target = BanTarget::parse(target);
+			if !BanSystem::remove(&bans, &target).await? {
+				anyhow::bail!("{target} is not banned");
+			}
+			return Ok(format!("Unbanned {target}"));
+		}
+
+		let room_sessions = refs.room_mtx.lock().await.get_all_sessions(None);
+		let matching: Vec<RoomSessionData> = room_sessions
+			.iter()
+			.filter(|session| session.state.lock().nick_name == target)
+			.cloned()
+			.collect();
+
+		if matching.iter().any(|session| !self.can_moderate(session)) {
+			anyhow::bail!("Cannot moderate users with the same or a higher role");
+		}
+
+		if command == "ban" {
+			let duration_s = match parts.pop_front() {
+				Some(duration) => {
+					Some(util::parse_duration(duration).ok_or_else(|| anyhow::anyhow!("Invalid duration"))?)
+				}
+				None => None,
+			};
+
+			let all_sessions = server_mtx.lock().await.get_all_room_sessions().await;
+			let targets =
+				BanSystem::ban_user(&bans, &matching, &all_sessions, target, duration_s).await?;
+			let targets: Vec<String> = targets.iter().map(ToString::to_string).collect();
+			return Ok(format!("Banned {}", targets.join(", ")));
+		}
+
+		if matching.is_empty() {
+			anyhow::bail!("No user named {target} in this room");
+		}
+
+		if command == "kick" {
+			let reason = Vec::from(std::mem::take(parts)).join(" ");
+			for session in &matching {
+				Self::disconnect(
+					session.instance_mtx.clone(),
+					if reason.is_empty() {
+						String::from("Kicked by a moderator")
+					} else {
+						format!("Kicked by a moderator: {reason}")
+					},
+				);
+			}
+			return Ok(format!("Kicked {target}"));
+		}
+
+		let muted = command == "mute";
+		for session in &matching {
+			session.state.lock().muted = muted;
+			session
+				.queue_send
+				.send(packet_server::prepare_packet_message(
+					packet_server::MessageType::PlainText,
+					SERVER_STR,
+					if muted {
+						"You have been muted"
+					} else {
+						"You have been unmuted"
+					},
+				));
+		}
+
+		Ok(format!(
+			"{} {target}",
+			if muted { "Muted" } else { "Unmuted" }
+		))
+	}
+
+	async fn handle_role_command(&self, refs: &RoomRefs, parts: &mut VecDeque<&str>) {
+		let subcommand = parts.pop_front();
+
+		match subcommand {
+			None => {
+				let role = self.state().role;
+				self.send_reply(&format!("Your role: {role}"));
+			}
+			Some("list") => {
+				let role_system = refs.role_system_mtx.lock().await;
+				let (guest_role, account_role) = role_system.default_roles();
+				self.send_reply(&format!(
+					"Default roles: guests {guest_role}, accounts {account_role}"
+				));
+				for (account, role) in role_system.list() {
+					self.send_reply(&format!("{account}: {role}"));
+				}
+			}
+			Some(subcommand) => {
+				if !self.can(Capability::ManageRoles) {
+					self.send_insufficient_permissions();
+					return;
+				}
+
+				match Self::update_roles(refs, subcommand, parts).await {
+					Ok(text) => self.send_reply_stylized(&format!("[color=blue]{text}[/color]")),
+					Err(e) => self.send_reply_stylized(&format!("[color=red]{e}[/color]")),
+				}
+			}
+		}
+	}
+
+	async fn update_roles(
+		refs: &RoomRefs,
+		subcommand: &str,
+		parts: &mut VecDeque<&str>,
+	) -> anyhow::Result<String> {
+		let mut role_system = refs.role_system_mtx.lock().await;
+
+		let account = match (subcommand, parts.pop_front(), parts.pop_front()) {
+			("set", Some(account), Some(role)) => {
+				role_system.assign(account, Some(role.parse()?)).await?;
+				account
+			}
+			("unset", Some(account), None) => {
+				role_system.assign(account, None).await?;
+				account
+			}
+			(target @ ("guests" | "accounts"), Some(role), None) => {
+				let role: Role = role.parse()?;
+				role_system.set_default(target == "guests", role).await?;
+				return Ok(format!(
+					"Default role of {target} set to {role}, applies to users joining from now on"
+				));
+			}
+			_ => anyhow::bail!("Usage: role <set|unset|guests|accounts> [account] [role]"),
+		};
+
+		// Apply to sessions of this account which are already in the room
+		let role = role_system.resolve(Some(account));
+		let sessions = refs.room_mtx.lock().await.get_all_sessions(None);
+		for session in sessions {
+			let mut state = session.state.lock();
+			if state
+				.account
+				.as_ref()
+				.is_some_and(|name| name.eq_ignore_ascii_case(account))
+			{
+				state.role = role;
+				session
+					.queue_send
+					.send(packet_server::prepare_packet_message(
+						packet_server::MessageType::PlainText,
+						SERVER_STR,
+						&format!("Your role is now {role}"),
+					));
+			}
+		}
+
+		Ok(format!("Role of {account} set to {role}"))
+	}
+
+	async fn handle_region_command(&self, refs: &RoomRefs, parts: &mut VecDeque<&str>) {
+		let subcommand = parts.pop_front();
+		let name = parts.pop_front();
+
+		if subcommand == Some("list") {
+			let region_system = refs.region_system_mtx.lock().await;
+			if region_system.list().is_empty() {
+				self.send_reply("No protected regions");
+			}
+			for region in region_system.list() {
+				self.send_reply(&format!(
+					"{}: {} {} {} {}, owner {}{}{}",
+					region.name,
+					region.start.x,
+					region.start.y,
+					region.end.x,
+					region.end.y,
+					region.owner,
+					if region.editors.is_empty() {
+						String::new()
+					} else {
+						format!(", editors {}", region.editors.join(" "))
+					},
+					if region.read_only { ", read-only" } else { "" }
+				));
+			}
+			return;
+		}
+
+		let (Some(subcommand), Some(name)) = (subcommand, name) else {
+			self.send_reply("Usage: region <list|create|remove|allow|deny|readonly> [name] [args]");
+			return;
+		};
+
+		if name.len() > 32
+			|| !name
+				.chars()
+				.all(|ch| ch.is_ascii_alphanumeric() || ch == '_' || ch == '-')
+		{
+			self.send_reply_stylized("[color=red]Invalid region name[/color]");
+			return;
+		}
+
+		if subcommand == "create" {
+			self.handle_region_create_command(refs, name, parts).await;
+			return;
+		}
+
+		let actor = self.region_actor();
+
+		let mut region_system = refs.region_system_mtx.lock().await;
+		let Some(mut region) = region_system.get(name).cloned() else {
+			self.send_reply_stylized("[color=red]No such region[/color]");
+			return;
+		};
+
+		if !region.is_owner(&actor) {
+			self.send_insufficient_permissions();
+			return;
+		}
+
+		let user = parts.pop_front();
+		match (subcommand, user) {
+			("remove", _) => {
+				if let Err(e) = region_system.remove(name).await {
+					log::error!("Failed to remove region {name}: {e}");
+				}
+				self.send_reply_stylized(&format!("[color=blue]Removed region {name}[/color]"));
+				return;
+			}
+			("allow", Some(user)) => {
+				if !region.editors.iter().any(|editor| editor == user) {
+					region.editors.push(String::from(user));
+				}
+			}
+			("deny", Some(user)) => region.editors.retain(|editor| editor != user),
+			("readonly", Some("on")) => region.read_only = true,
+			("readonly", Some("off")) => region.read_only = false,
+			_ => {
+				self
+					.send_reply("Usage: region <allow|deny> <name> <user> | region readonly <name> <on|off>");
+				return;
+			}
+		}
+
+		if let Err(e) = region_system.save(region).await {
+			log::error!("Failed to save region {name}: {e}");
+		}
+		self.send_reply_stylized(&format!("[color=blue]Updated region {name}[/color]"));
+	}
+
+	async fn handle_region_create_command(
+		&self,
+		refs: &RoomRefs,
+		name: &str,
+		parts: &VecDeque<&str>,
+	) {
+		if !self.can(Capability::ManageRegions) {
+			self.send_insufficient_permissions();
+			return;
+		}
+
+		let coords: Vec<i32> = parts
+			.iter()
+			.take(4)
+			.filter_map(|part| part.parse().ok())
+			.collect();
+		let [x1, y1, x2, y2] = coords.as_slice() else {
+			self.send_reply("Usage: region create <name> <x1> <y1> <x2> <y2> [owner]");
+			return;
+		};
+
+		let account = self.state().account.clone();
+		let Some(owner) = parts.get(4).copied().or(account.as_deref()) else {
+			self.send_reply("Guests cannot own regions, specify the owner account");
+			return;
+		};
+
+		let bounds = Region::from_corners(IVec2::new(*x1, *y1), IVec2::new(*x2, *y2));
+		let mut region_system = refs.region_system_mtx.lock().await;
+		if region_system.get(name).is_some() {
+			self.send_reply_stylized("[color=red]Region with this name already exists[/color]");
+			return;
+		}
+
+		let res = region_system
+			.save(ProtectedRegion {
+				name: String::from(name),
+				owner: String::from(owner),
+				start: bounds.start,
+				end: bounds.end,
+				read_only: false,
+				editors: Vec::new(),
+			})
+			.await;
+
+		if let Err(e) = res {
+			self.send_reply_stylized(&format!("[color=red]Failed to create region: {e}[/color]"));
+		} else {
+			self.send_reply_stylized(&format!(
+				"[color=blue]Created region {name} owned by {owner}[/color]"
+			));
+		}
+	}
+
+	async fn handle_view_command(
+		&mut self,
+		session_handle: &SessionHandle,
+		parts: &mut VecDeque<&str>,
+	) {
+		match parts.pop_front() {
+			Some("live") => self.set_view_timestamp(session_handle, None).await,
+			Some(time) => {
+				if let Some(timestamp) = util::parse_timestamp(time, get_unix_timestamp()) {
+					self
+						.set_view_timestamp(session_handle, Some(timestamp))
+						.await;
+				} else {
+					self.send_reply_stylized("[color=red]Invalid time[/color]");
+				}
+			}
+			None => self.send_reply("Usage: view <time|live>"),
+		}
+	}
+
+	async fn handle_export_command(
+		&self,
+		server_mtx: &ServerMutex,
+		refs: &RoomRefs,
+		parts: &VecDeque<&str>,
+	) {
+		let coords: Vec<i32> = parts.iter().filter_map(|part| part.parse().ok()).collect();
+		let [x1, y1, x2, y2] = coords.as_slice() else {
+			self.send_reply("Usage: export <x1> <y1> <x2> <y2>");
+			return;
+		};
+
+		let region = Region::from_corners(IVec2::new(*x1, *y1), IVec2::new(*x2, *y2));
+		let room_name = refs.room_mtx.lock().await.name.clone();
+
+		match export::export_region_png(server_mtx, &room_name, region).await {
+			Ok(path) => self.send_reply_stylized(&format!("[color=blue]Exported to {path}[/color]")),
+			Err(e) => self.send_reply_stylized(&format!("[color=red]Export failed: {e}[/color]")),
+		}
+	}
+
+	async fn handle_import_command(&mut self, refs: &RoomRefs, parts: &mut VecDeque<&str>) {
+		let file_name = parts.pop_front();
+		let x = parts.pop_front().and_then(|part| part.parse::<i32>().ok());
+		let y = parts.pop_front().and_then(|part| part.parse::<i32>().ok());
+		let blend = parts.pop_front() == Some("blend");
+
+		let (Some(file_name), Some(x), Some(y)) = (file_name, x, y) else {
+			self.send_reply("Usage: import <file> <x> <y> [blend]");
+			return;
+		};
+
+		let image = match import::load_image(file_name).await {
+			Ok(image) => image,
+			Err(e) => {
+				self.send_reply_stylized(&format!("[color=red]Import failed: {e}[/color]"));
+				return;
+			}
+		};
+
+		self.queue_send_status_text("Importing image...");
+		let count = self
+			.stamp_image(refs, &image, IVec2::new(x, y), blend)
+			.await;
+		self.queue_send_status_text("");
+
+		self.send_reply_stylized(&format!(
+			"[color=blue]Imported {file_name} ({count} pixels){}[/color]",
+			self.undo_note()
+		));
+	}
+
+	// Appended to replies of bulk operations which didn't fit in the undo history
+	const fn undo_note(&self) -> &'static str {
+		if self.history.is_oversized() {
+			", too large to undo"
+		} else {
+			""
+		}
+	}
+
+	// Write the image with its top-left corner at the given position. Goes through the history, so it can be undone.
+	async fn stamp_image(
+		&mut self,
+		refs: &RoomRefs,
+		image: &import::ImportedImage,
+		origin: IVec2,
+		blend: bool,
+	) -> usize {
+		let mut pixels = Vec::<GlobalPixelRGBA>::with_capacity(image.rgba.len() / 4);
+		let mut canvas_cache = CanvasCache::default();
+
+		for (idx, rgba) in image.rgba.chunks_exact(4).enumerate() {
+			let offset = IVec2::new(idx as i32 % image.size.x, idx as i32 / image.size.x);
+			let Some(pos) = origin.checked_add(offset) else {
+				continue;
+			};
+
+			let mut color = ColorRGBA::new(rgba[0], rgba[1], rgba[2], rgba[3]);
+
+			if blend {
+				if color.a == 0 {
+					continue; // Nothing to blend
+				}
+
+				let current = canvas_cache.get_pixel(&refs.chunk_system_mtx, &pos).await;
+				color = ColorRGBA::blend_gamma_corrected(color.a, current, color);
+			}
+
+			pixels.push(GlobalPixelRGBA { pos, color });
+		}
+
+		self.history.create_snapshot();
+		self.set_pixels_main(refs, &pixels, true).await;
+
+		pixels.len()
+	}
+
+	async fn handle_rollback_command(&mut self, refs: &RoomRefs, parts: &mut VecDeque<&str>) {
+		let timestamp = parts
+			.pop_front()
+			.and_then(|time| util::parse_timestamp(time, get_unix_timestamp()));
+		let coords: Vec<i32> = parts.iter().filter_map(|part| part.parse().ok()).collect();
+
+		let (Some(timestamp), [x1, y1, x2, y2]) = (timestamp, coords.as_slice()) else {
+			self.send_reply("Usage: rollback <time> <x1> <y1> <x2> <y2>");
+			return;
+		};
+
+		let start = IVec2::new(*x1.min(x2), *y1.min(y2));
+		let end = IVec2::new(*x1.max(x2), *y1.max(y2));
+
+		let area = i64::from(end.x - start.x + 1) * i64::from(end.y - start.y + 1);
+		if area > i64::from(limits::ROLLBACK_AREA_MAX_PX) {
+			self.send_reply_stylized(&format!(
+				"[color=red]Region too large (max {} pixels)[/color]",
+				limits::ROLLBACK_AREA_MAX_PX
+			));
+			return;
+		}
+
+		match self.rollback_region(refs, timestamp, start, end).await {
+			Ok(count) => self.send_reply_stylized(&format!(
+				"[color=blue]Rolled back {count} pixels to {timestamp}{}[/color]",
+				self.undo_note()
+			)),
+			Err(e) => {
+				log::error!("Rollback failed: {e}");
+				self.send_reply_stylized("[color=red]Rollback failed[/color]");
+			}
+		}
+	}
+
+	async fn process_command_cursor_pos(
+		&mut self,
+		refs: &RoomRefs,
+		reader: &mut BinaryReader,
+		session_handle: &SessionHandle,
+	) -> anyhow::Result<()> {
+		{
+			let mut state = self.state();
+			state.cursor.pos_prev = state.cursor.pos.clone();
+			state.cursor.pos = packet_client::PacketCursorPos::read(reader)?;
+		}
+
+		self.update_cursor(refs, session_handle).await;
+
+		let (pos_sent, cursor_pos) = {
 			let state = self.state();
 			(state.cursor.pos_sent.clone(), state.cursor.pos.clone())
 		};
@@ -1434,6 +2508,10 @@ impl SessionInstance {
 		_reader: &mut BinaryReader,
 		session_handle: &SessionHandle,
 	) -> Result<(), UserError> {
+		if !self.ensure_live_view() || !self.ensure_can_use_tool() {
+			return Ok(());
+		}
+
 		{
 			let mut state = self.state();
 
@@ -1451,6 +2529,8 @@ impl SessionInstance {
 		self.history.create_snapshot();
 		self.update_cursor(refs, session_handle).await;
 
+		PluginSystem::dispatch(refs, PluginEvent::UserMouseDown(session_handle.id())).await;
+
 		Ok(())
 	}
 
@@ -1549,6 +2629,25 @@ impl SessionInstance {
 		Ok(())
 	}
 
+	async fn process_command_view_timestamp(
+		&mut self,
+		reader: &mut BinaryReader,
+		session_handle: &SessionHandle,
+	) -> anyhow::Result<()> {
+		let timestamp = reader.read_u64()?;
+
+		if !self.can(Capability::ViewHistory) {
+			self.send_insufficient_permissions();
+			return Ok(());
+		}
+
+		self
+			.set_view_timestamp(session_handle, (timestamp != 0).then_some(timestamp))
+			.await;
+
+		Ok(())
+	}
+
 	fn process_command_tool_size(&mut self, reader: &mut BinaryReader) -> anyhow::Result<()> {
 		let size = reader.read_u8()?;
 		self.tool.size_raw = size;
@@ -1623,17 +2722,50 @@ impl SessionInstance {
 			.send(packet_server::prepare_packet_status_text(text));
 	}
 
+	fn queue_send_status_text_skipped(&self, skipped: usize) {
+		if skipped > 0 {
+			self.queue_send_status_text(
+				format!("Skipped {skipped} pixels modified by other users or in protected regions")
+					.as_str(),
+			);
+		} else {
+			self.queue_send_status_text("");
+		}
+	}
+
 	async fn process_command_undo(&mut self, refs: &RoomRefs, _reader: &mut BinaryReader) {
+		if !self.ensure_live_view() || !self.ensure_can_draw() {
+			return;
+		}
+
 		if let Some(cell) = self.history.undo() {
 			self.queue_send_status_text(format!("Undoing {} pixels...", cell.pixels.len()).as_str());
-			self.set_pixels_main(refs, &cell.pixels, false).await;
-			self.queue_send_status_text("");
+			let (reverted, skipped) = self.apply_history_cell(refs, &cell).await;
+			self.history.push_redo(reverted);
+			self.queue_send_status_text_skipped(skipped);
+		}
+	}
+
+	async fn process_command_redo(&mut self, refs: &RoomRefs, _reader: &mut BinaryReader) {
+		if !self.ensure_live_view() || !self.ensure_can_draw() {
+			return;
+		}
+
+		if let Some(cell) = self.history.redo() {
+			self.queue_send_status_text(format!("Redoing {} pixels...", cell.pixels.len()).as_str());
+			let (reverted, skipped) = self.apply_history_cell(refs, &cell).await;
+			self.history.push_undo(reverted);
+			self.queue_send_status_text_skipped(skipped);
 		}
 	}
 
 	async fn send_cursor_pos_to_all(&self, refs: &RoomRefs, session_handle: &SessionHandle) {
 		let cursor_pos = {
 			let mut state = self.state();
+			if state.hidden {
+				return;
+			}
+
 			state.cursor.pos_sent = Some(state.cursor.pos.clone());
 			state.cursor.pos.clone()
 		};
@@ -1674,24 +2806,142 @@ impl SessionInstance {
 			.send(packet_server::prepare_packet_chunk_create(chunk_pos));
 	}
 
-	async fn unlink_chunk(&mut self, session_handle: &SessionHandle, wchunk: ChunkInstanceWeak) {
-		if let Some(chunk) = wchunk.upgrade() {
-			let mut chunk = chunk.lock().await;
-			chunk.unlink_session(session_handle);
-			let chunk_pos = chunk.position;
-			drop(chunk);
-			for (idx, lchunk) in self.linked_chunks.iter().enumerate() {
-				if Weak::ptr_eq(&lchunk.chunk, &wchunk) {
-					self.linked_chunks.remove(idx);
-					break;
-				}
+	async fn unlink_chunk(&mut self, session_handle: &SessionHandle, chunk_pos: IVec2) {
+		let Some(idx) = self
+			.linked_chunks
+			.iter()
+			.position(|lchunk| lchunk.pos == chunk_pos)
+		else {
+			return;
+		};
+
+		let lchunk = self.linked_chunks.remove(idx);
+
+		// Chunks sent from a snapshot aren't backed by a chunk instance
+		if let Some(chunk) = lchunk.chunk.upgrade() {
+			chunk.lock().await.unlink_session(session_handle);
+		}
+
+		// Send packet to the client
+		self
+			.queue_send
+			.send(packet_server::prepare_packet_chunk_remove(chunk_pos));
+	}
+
+	async fn unlink_all_chunks(&mut self, session_handle: &SessionHandle) {
+		while let Some(lchunk) = self.linked_chunks.last() {
+			let chunk_pos = lchunk.pos;
+			self.unlink_chunk(session_handle, chunk_pos).await;
+		}
+	}
+
+	// Send the chunk as it was at the given time, bypassing the live chunk instance
+	async fn send_chunk_snapshot(&self, refs: &RoomRefs, chunk_pos: IVec2, timestamp: u64) {
+		let data = match DatabaseFunc::chunk_load_data_at(&refs.database, chunk_pos, timestamp).await {
+			Ok(Some(record)) => record.data,
+			Ok(None) => get_empty_chunk_rgba().lock().unwrap().to_vec(), // Didn't exist back then
+			Err(e) => {
+				log::error!("Failed to load chunk snapshot at {chunk_pos}: {e}");
+				return;
 			}
+		};
 
-			// Send packet to the client
-			self
-				.queue_send
-				.send(packet_server::prepare_packet_chunk_remove(chunk_pos));
+		self
+			.queue_send
+			.send(packet_server::prepare_packet_chunk_image(chunk_pos, &data));
+	}
+
+	async fn set_view_timestamp(&mut self, session_handle: &SessionHandle, timestamp: Option<u64>) {
+		if self.view_timestamp == timestamp {
+			return;
 		}
+
+		// Chunks will be re-sent by the boundary check
+		self.unlink_all_chunks(session_handle).await;
+		self.view_timestamp = timestamp;
+		self.needs_boundary_test = true;
+
+		if let Some(timestamp) = timestamp {
+			self.queue_send_status_text(format!("Viewing canvas as of {timestamp} (read-only)").as_str());
+		} else {
+			self.queue_send_status_text("");
+		}
+	}
+
+	fn ensure_can_draw(&self) -> bool {
+		if !self.can(Capability::Draw) {
+			self.queue_send_status_text("Your role does not allow drawing");
+			return false;
+		}
+		true
+	}
+
+	fn ensure_can_use_tool(&self) -> bool {
+		let capability = match self.tool.tool_type {
+			Some(packet_client::ToolType::Fill) => Capability::Fill,
+			_ => Capability::Draw,
+		};
+
+		if !self.can(capability) {
+			self.queue_send_status_text("Your role does not allow using this tool");
+			return false;
+		}
+		true
+	}
+
+	// Drawing is disabled while viewing the canvas from the past
+	fn ensure_live_view(&self) -> bool {
+		if self.view_timestamp.is_some() {
+			self.queue_send_status_text("Canvas is read-only, switch back to the live view to draw");
+			return false;
+		}
+		true
+	}
+
+	// Restore the region to its state at the given time. Goes through the regular history, so it can be undone.
+	async fn rollback_region(
+		&mut self,
+		refs: &RoomRefs,
+		timestamp: u64,
+		start: IVec2,
+		end: IVec2,
+	) -> anyhow::Result<usize> {
+		let chunk_start = ChunkSystem::global_pixel_pos_to_chunk_pos(start);
+		let chunk_end = ChunkSystem::global_pixel_pos_to_chunk_pos(end);
+
+		let mut pixels = Vec::<GlobalPixelRGBA>::new();
+		let mut layer = LayerRGBA::new();
+
+		for chunk_y in chunk_start.y..=chunk_end.y {
+			for chunk_x in chunk_start.x..=chunk_end.x {
+				let chunk_pos = IVec2::new(chunk_x, chunk_y);
+
+				let record = DatabaseFunc::chunk_load_data_at(&refs.database, chunk_pos, timestamp).await?;
+				match record.and_then(|record| decompress_lz4(&record.data, CHUNK_IMAGE_SIZE_BYTES_RGBA)) {
+					Some(raw) => layer.set_data(RGBAData(raw)),
+					None => layer.alloc_transparent_black(), // Didn't exist back then
+				}
+
+				let chunk_origin = chunk_pos * CHUNK_SIZE_PX as i32;
+				let from = start.max(chunk_origin);
+				let to = end.min(chunk_origin + IVec2::splat(CHUNK_SIZE_PX as i32 - 1));
+
+				for y in from.y..=to.y {
+					for x in from.x..=to.x {
+						let pos = IVec2::new(x, y);
+						pixels.push(GlobalPixelRGBA {
+							pos,
+							color: layer.get_pixel(ChunkSystem::global_pixel_pos_to_local_pixel_pos(pos)),
+						});
+					}
+				}
+			}
+		}
+
+		self.history.create_snapshot();
+		self.set_pixels_main(refs, &pixels, true).await;
+
+		Ok(pixels.len())
 	}
 
 	pub async fn update_tool_state(&mut self, refs: &RoomRefs) {
@@ -1774,7 +3024,12 @@ impl SessionInstance {
 			// Announce chunk
 			self.chunks_sent += 1;
 
-			if let Some(chunk_mtx) = self
+			if let Some(timestamp) = self.view_timestamp {
+				self.link_chunk(LinkedChunk::new(closest_position, &Weak::new()));
+				self
+					.send_chunk_snapshot(refs, closest_position, timestamp)
+					.await;
+			} else if let Some(chunk_mtx) = self
 				.chunk_cache
 				.get(&refs.chunk_system_mtx, closest_position)
 				.await
@@ -1800,33 +3055,31 @@ impl SessionInstance {
 
 	pub async fn tick_chunks_cleanup(&mut self, session_handle: &SessionHandle) {
 		// Remove chunks outside bounds and left for longer time
-		let mut chunks_to_unload: Vec<ChunkInstanceWeak> = Vec::new();
+		let mut chunks_to_unload: Vec<IVec2> = Vec::new();
 
 		for i in 0..self.linked_chunks.len() {
 			let linked_chunk = &mut self.linked_chunks[i];
-			if let Some(chunk) = linked_chunk.chunk.upgrade() {
-				let pos = chunk.lock().await.position;
-				if self.boundary.zoom <= limits::MIN_ZOOM
-					|| pos.y < self.boundary.start_y
-					|| pos.y > self.boundary.end_y
-					|| pos.x < self.boundary.start_x
-					|| pos.x > self.boundary.end_x
+			let pos = linked_chunk.pos;
+			if self.boundary.zoom <= limits::MIN_ZOOM
+				|| pos.y < self.boundary.start_y
+				|| pos.y > self.boundary.end_y
+				|| pos.x < self.boundary.start_x
+				|| pos.x > self.boundary.end_x
+			{
+				linked_chunk.outside_boundary_duration += 1;
+				if linked_chunk.outside_boundary_duration == 5
+				/* 5 seconds */
 				{
-					linked_chunk.outside_boundary_duration += 1;
-					if linked_chunk.outside_boundary_duration == 5
-					/* 5 seconds */
-					{
-						chunks_to_unload.push(linked_chunk.chunk.clone());
-					}
-				} else {
-					linked_chunk.outside_boundary_duration = 0;
+					chunks_to_unload.push(pos);
 				}
+			} else {
+				linked_chunk.outside_boundary_duration = 0;
 			}
 		}
 
 		// Deannounce chunks from list
-		for chunk in &chunks_to_unload {
-			self.unlink_chunk(session_handle, chunk.clone()).await;
+		for chunk_pos in chunks_to_unload {
+			self.unlink_chunk(session_handle, chunk_pos).await;
 		}
 	}
 }
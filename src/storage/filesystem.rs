@@ -0,0 +1,355 @@
+use std::{
+	collections::HashMap,
+	fs::{self, File},
+	io::{self, Write},
+	path::{Path, PathBuf},
+	time::{Duration, SystemTime},
+};
+
+use glam::{I64Vec2, IVec2};
+
+use crate::{
+	config::SnapshotRetention,
+	database::{
+		get_unix_timestamp, ChunkDatabaseRecord, ChunkStats, CompressionType, PreviewDatabaseRecord,
+	},
+};
+
+use super::{in_area, select_snapshots_to_prune, starts_new_snapshot, ChunkStorage};
+
+// Plain files in a directory next to the room database, which can be copied with rsync while the room is not loaded:
+//  chunks/{x}_{y}/{created}.lz4 - snapshots of a chunk, the file modification time is the snapshot modification time
+//  previews/{zoom}/{x}_{y}.lz4
+
+#[derive(Clone, Copy)]
+struct SnapshotInfo {
+	created: u64,
+	modified: u64,
+}
+
+pub struct FilesystemChunkStorage {
+	root: PathBuf,
+	index: HashMap<IVec2, Vec<SnapshotInfo>>, // Oldest snapshot first
+	read_only: bool, // Opened by a reader while the room may be loaded, leftover files belong to the writer
+}
+
+fn parse_pos(name: &str) -> Option<IVec2> {
+	let (x, y) = name.split_once('_')?;
+	Some(IVec2::new(x.parse().ok()?, y.parse().ok()?))
+}
+
+fn snapshot_path(root: &Path, pos: IVec2, created: u64) -> PathBuf {
+	root
+		.join("chunks")
+		.join(format!("{}_{}", pos.x, pos.y))
+		.join(format!("{created}.lz4"))
+}
+
+fn to_unix_timestamp(time: SystemTime) -> u64 {
+	time
+		.duration_since(SystemTime::UNIX_EPOCH)
+		.map_or(0, |n| n.as_secs())
+}
+
+fn read_optional(path: &Path) -> io::Result<Option<Vec<u8>>> {
+	match fs::read(path) {
+		Ok(data) => Ok(Some(data)),
+		Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+		Err(e) => Err(e),
+	}
+}
+
+// Writes through a temporary file, so a crash never leaves a partially written file behind
+fn write_atomic(path: &Path, data: &[u8], modified: u64) -> io::Result<()> {
+	if let Some(parent) = path.parent() {
+		fs::create_dir_all(parent)?;
+	}
+
+	let tmp_path = path.with_extension("tmp");
+	let mut file = File::create(&tmp_path)?;
+	file.write_all(data)?;
+	file.set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(modified))?;
+	drop(file);
+
+	fs::rename(tmp_path, path)
+}
+
+impl FilesystemChunkStorage {
+	pub fn open(root: PathBuf, read_only: bool) -> io::Result<Self> {
+		let mut storage = Self {
+			root,
+			index: HashMap::new(),
+			read_only,
+		};
+
+		if !read_only {
+			fs::create_dir_all(storage.root.join("chunks"))?;
+		} else if !storage.root.join("chunks").exists() {
+			return Ok(storage); // Nothing saved yet
+		}
+		storage.scan()?;
+		log::debug!(
+			"Found {} chunks in {}",
+			storage.index.len(),
+			storage.root.display()
+		);
+
+		Ok(storage)
+	}
+
+	fn preview_path(&self, pos: IVec2, zoom: u8) -> PathBuf {
+		self
+			.root
+			.join("previews")
+			.join(zoom.to_string())
+			.join(format!("{}_{}.lz4", pos.x, pos.y))
+	}
+
+	fn scan(&mut self) -> io::Result<()> {
+		for chunk_entry in fs::read_dir(self.root.join("chunks"))? {
+			let chunk_entry = chunk_entry?;
+			let Some(pos) = chunk_entry.file_name().to_str().and_then(parse_pos) else {
+				continue;
+			};
+
+			let mut snapshots = Vec::new();
+			for entry in fs::read_dir(chunk_entry.path())? {
+				let entry = entry?;
+				let path = entry.path();
+				let name = entry.file_name();
+				let Some(name) = name.to_str() else {
+					continue;
+				};
+
+				if let Some(created) = name.strip_suffix(".lz4").and_then(|s| s.parse().ok()) {
+					snapshots.push(SnapshotInfo {
+						created,
+						modified: to_unix_timestamp(entry.metadata()?.modified()?),
+					});
+				} else if !self.read_only && path.extension().is_some_and(|ext| ext == "tmp") {
+					// Interrupted write
+					fs::remove_file(path)?;
+				}
+			}
+
+			if !snapshots.is_empty() {
+				snapshots.sort_by_key(|snapshot| snapshot.created);
+				self.index.insert(pos, snapshots);
+			}
+		}
+
+		if !self.read_only {
+			self.remove_interrupted_previews()?;
+		}
+
+		Ok(())
+	}
+
+	fn remove_interrupted_previews(&self) -> io::Result<()> {
+		let previews = match fs::read_dir(self.root.join("previews")) {
+			Ok(previews) => previews,
+			Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+			Err(e) => return Err(e),
+		};
+
+		for zoom_entry in previews {
+			let zoom_entry = zoom_entry?;
+			if !zoom_entry.file_type()?.is_dir() {
+				continue;
+			}
+
+			for entry in fs::read_dir(zoom_entry.path())? {
+				let path = entry?.path();
+				if path.extension().is_some_and(|ext| ext == "tmp") {
+					fs::remove_file(path)?;
+				}
+			}
+		}
+
+		Ok(())
+	}
+}
+
+impl ChunkStorage for FilesystemChunkStorage {
+	fn chunk_load_data_at(
+		&mut self,
+		pos: IVec2,
+		timestamp: u64,
+	) -> anyhow::Result<Option<ChunkDatabaseRecord>> {
+		let Some(snapshot) = self.index.get(&pos).and_then(|snapshots| {
+			snapshots
+				.iter()
+				.filter(|snapshot| snapshot.modified <= timestamp)
+				.max_by_key(|snapshot| snapshot.modified)
+				.copied()
+		}) else {
+			return Ok(None);
+		};
+
+		Ok(
+			read_optional(&snapshot_path(&self.root, pos, snapshot.created))?.map(|data| {
+				ChunkDatabaseRecord {
+					compression_type: CompressionType::Lz4,
+					created_at: snapshot.created,
+					modified_at: snapshot.modified,
+					data,
+				}
+			}),
+		)
+	}
+
+	fn chunk_save_data(
+		&mut self,
+		pos: IVec2,
+		data: &[u8],
+		_compression_type: CompressionType,
+	) -> anyhow::Result<()> {
+		let now = get_unix_timestamp();
+
+		let newest = self
+			.index
+			.get(&pos)
+			.and_then(|snapshots| snapshots.last())
+			.copied();
+
+		let created = match newest {
+			Some(newest) if !starts_new_snapshot(newest.created, now) => newest.created,
+			_ => now,
+		};
+
+		write_atomic(&snapshot_path(&self.root, pos, created), data, now)?;
+
+		let snapshots = self.index.entry(pos).or_default();
+		match snapshots.last_mut() {
+			Some(newest) if newest.created == created => newest.modified = now,
+			_ => snapshots.push(SnapshotInfo {
+				created,
+				modified: now,
+			}),
+		}
+
+		Ok(())
+	}
+
+	fn chunk_list_stats(&mut self) -> anyhow::Result<Vec<ChunkStats>> {
+		let mut res: Vec<_> = self
+			.index
+			.iter()
+			.map(|(pos, snapshots)| ChunkStats {
+				pos: *pos,
+				snapshot_count: snapshots.len() as u32,
+				modified_at: snapshots
+					.iter()
+					.map(|snapshot| snapshot.modified)
+					.max()
+					.unwrap_or(0),
+			})
+			.collect();
+		res.sort_by_key(|chunk| (chunk.pos.y, chunk.pos.x));
+		Ok(res)
+	}
+
+	fn chunk_modified_in_area(&mut self, from: I64Vec2, to: I64Vec2) -> anyhow::Result<Option<u64>> {
+		Ok(
+			self
+				.index
+				.iter()
+				.filter(|(pos, _)| in_area(**pos, from, to))
+				.flat_map(|(_, snapshots)| snapshots.iter().map(|snapshot| snapshot.modified))
+				.max(),
+		)
+	}
+
+	fn chunk_prune_snapshots(
+		&mut self,
+		retention: &SnapshotRetention,
+		now: u64,
+	) -> anyhow::Result<usize> {
+		let mut deleted = 0;
+
+		for (pos, snapshots) in &mut self.index {
+			let mut newest_first = snapshots.clone();
+			newest_first.sort_by_key(|snapshot| std::cmp::Reverse(snapshot.modified));
+			let list: Vec<_> = newest_first
+				.iter()
+				.map(|snapshot| (*pos, snapshot.modified))
+				.collect();
+
+			for idx in select_snapshots_to_prune(&list, retention, now) {
+				let created = newest_first[idx].created;
+				fs::remove_file(snapshot_path(&self.root, *pos, created))?;
+				snapshots.retain(|snapshot| snapshot.created != created);
+				deleted += 1;
+			}
+		}
+
+		Ok(deleted)
+	}
+
+	fn preview_load_data(
+		&mut self,
+		pos: IVec2,
+		zoom: u8,
+	) -> anyhow::Result<Option<PreviewDatabaseRecord>> {
+		Ok(read_optional(&self.preview_path(pos, zoom))?.map(|data| PreviewDatabaseRecord { data }))
+	}
+
+	fn preview_save_data(&mut self, pos: IVec2, zoom: u8, data: &[u8]) -> anyhow::Result<()> {
+		write_atomic(&self.preview_path(pos, zoom), data, get_unix_timestamp())?;
+		Ok(())
+	}
+
+	fn preview_clear_all(&mut self) -> anyhow::Result<()> {
+		match fs::remove_dir_all(self.root.join("previews")) {
+			Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+			_ => Ok(()),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn scan_removes_interrupted_writes() {
+		let dir = tempfile::tempdir().unwrap();
+		let root = dir.path().join("room.tiles");
+		let pos = IVec2::new(-1, 2);
+
+		let mut storage = FilesystemChunkStorage::open(root.clone(), false).unwrap();
+		storage
+			.chunk_save_data(pos, b"chunk", CompressionType::Lz4)
+			.unwrap();
+		storage.preview_save_data(pos, 1, b"preview").unwrap();
+		drop(storage);
+
+		// Left behind by writes interrupted by a crash
+		let chunk_tmp = root.join("chunks").join("-1_2").join("123.tmp");
+		let preview_tmp = root.join("previews").join("1").join("-1_2.tmp");
+		fs::write(&chunk_tmp, b"partial").unwrap();
+		fs::write(&preview_tmp, b"partial").unwrap();
+
+		// Readers leave them to the writer
+		let mut storage = FilesystemChunkStorage::open(root.clone(), true).unwrap();
+		assert!(chunk_tmp.exists() && preview_tmp.exists());
+		assert_eq!(
+			storage.chunk_load_data(pos).unwrap().unwrap().data,
+			b"chunk"
+		);
+		drop(storage);
+
+		let mut storage = FilesystemChunkStorage::open(root, false).unwrap();
+		assert!(!chunk_tmp.exists());
+		assert!(!preview_tmp.exists());
+		assert_eq!(storage.chunk_list_stats().unwrap().len(), 1);
+		assert_eq!(
+			storage.chunk_load_data(pos).unwrap().unwrap().data,
+			b"chunk"
+		);
+		assert_eq!(
+			storage.preview_load_data(pos, 1).unwrap().unwrap().data,
+			b"preview"
+		);
+	}
+}
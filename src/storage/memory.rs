@@ -0,0 +1,197 @@
+use std::collections::HashMap;
+
+use glam::{I64Vec2, IVec2};
+
+use crate::{
+	config::SnapshotRetention,
+	database::{
+		get_unix_timestamp, ChunkDatabaseRecord, ChunkStats, CompressionType, PreviewDatabaseRecord,
+	},
+};
+
+use super::{in_area, select_snapshots_to_prune, starts_new_snapshot, ChunkStorage};
+
+struct Snapshot {
+	created: u64,
+	modified: u64,
+	data: Vec<u8>,
+}
+
+// Keeps everything in memory, for tests and throwaway rooms. Together with the ":memory:" database path nothing touches the disk.
+#[derive(Default)]
+pub struct MemoryChunkStorage {
+	chunks: HashMap<IVec2, Vec<Snapshot>>, // Oldest snapshot first
+	previews: HashMap<(IVec2, u8 /* zoom */), Vec<u8>>,
+}
+
+impl ChunkStorage for MemoryChunkStorage {
+	fn chunk_load_data_at(
+		&mut self,
+		pos: IVec2,
+		timestamp: u64,
+	) -> anyhow::Result<Option<ChunkDatabaseRecord>> {
+		Ok(self.chunks.get(&pos).and_then(|snapshots| {
+			snapshots
+				.iter()
+				.filter(|snapshot| snapshot.modified <= timestamp)
+				.max_by_key(|snapshot| snapshot.modified)
+				.map(|snapshot| ChunkDatabaseRecord {
+					compression_type: CompressionType::Lz4,
+					created_at: snapshot.created,
+					modified_at: snapshot.modified,
+					data: snapshot.data.clone(),
+				})
+		}))
+	}
+
+	fn chunk_save_data(
+		&mut self,
+		pos: IVec2,
+		data: &[u8],
+		_compression_type: CompressionType,
+	) -> anyhow::Result<()> {
+		let now = get_unix_timestamp();
+		let snapshots = self.chunks.entry(pos).or_default();
+
+		match snapshots.last_mut() {
+			Some(newest) if !starts_new_snapshot(newest.created, now) => {
+				newest.modified = now;
+				newest.data = data.to_vec();
+			}
+			_ => snapshots.push(Snapshot {
+				created: now,
+				modified: now,
+				data: data.to_vec(),
+			}),
+		}
+
+		Ok(())
+	}
+
+	fn chunk_list_stats(&mut self) -> anyhow::Result<Vec<ChunkStats>> {
+		let mut res: Vec<_> = self
+			.chunks
+			.iter()
+			.map(|(pos, snapshots)| ChunkStats {
+				pos: *pos,
+				snapshot_count: snapshots.len() as u32,
+				modified_at: snapshots
+					.iter()
+					.map(|snapshot| snapshot.modified)
+					.max()
+					.unwrap_or(0),
+			})
+			.collect();
+		res.sort_by_key(|chunk| (chunk.pos.y, chunk.pos.x));
+		Ok(res)
+	}
+
+	fn chunk_modified_in_area(&mut self, from: I64Vec2, to: I64Vec2) -> anyhow::Result<Option<u64>> {
+		Ok(
+			self
+				.chunks
+				.iter()
+				.filter(|(pos, _)| in_area(**pos, from, to))
+				.flat_map(|(_, snapshots)| snapshots.iter().map(|snapshot| snapshot.modified))
+				.max(),
+		)
+	}
+
+	fn chunk_prune_snapshots(
+		&mut self,
+		retention: &SnapshotRetention,
+		now: u64,
+	) -> anyhow::Result<usize> {
+		let mut deleted = 0;
+
+		for (pos, snapshots) in &mut self.chunks {
+			snapshots.sort_by_key(|snapshot| std::cmp::Reverse(snapshot.modified));
+			let list: Vec<_> = snapshots
+				.iter()
+				.map(|snapshot| (*pos, snapshot.modified))
+				.collect();
+
+			for idx in select_snapshots_to_prune(&list, retention, now)
+				.into_iter()
+				.rev()
+			{
+				snapshots.remove(idx);
+				deleted += 1;
+			}
+
+			snapshots.sort_by_key(|snapshot| snapshot.created);
+		}
+
+		Ok(deleted)
+	}
+
+	fn preview_load_data(
+		&mut self,
+		pos: IVec2,
+		zoom: u8,
+	) -> anyhow::Result<Option<PreviewDatabaseRecord>> {
+		Ok(
+			self
+				.previews
+				.get(&(pos, zoom))
+				.map(|data| PreviewDatabaseRecord { data: data.clone() }),
+		)
+	}
+
+	fn preview_save_data(&mut self, pos: IVec2, zoom: u8, data: &[u8]) -> anyhow::Result<()> {
+		self.previews.insert((pos, zoom), data.to_vec());
+		Ok(())
+	}
+
+	fn preview_clear_all(&mut self) -> anyhow::Result<()> {
+		self.previews.clear();
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::storage::SECONDS_BETWEEN_SNAPSHOTS;
+
+	#[test]
+	fn save_starts_new_snapshot_after_interval() {
+		let mut storage = MemoryChunkStorage::default();
+		let pos = IVec2::new(1, 1);
+
+		storage
+			.chunk_save_data(pos, b"first", CompressionType::Lz4)
+			.unwrap();
+		storage
+			.chunk_save_data(pos, b"second", CompressionType::Lz4)
+			.unwrap();
+		assert_eq!(storage.chunks[&pos].len(), 1, "updates the newest snapshot");
+		assert_eq!(
+			storage.chunk_load_data(pos).unwrap().unwrap().data,
+			b"second"
+		);
+
+		// Make the snapshot old enough to be kept as it is
+		let snapshot = &mut storage.chunks.get_mut(&pos).unwrap()[0];
+		snapshot.created -= SECONDS_BETWEEN_SNAPSHOTS + 1;
+		snapshot.modified -= SECONDS_BETWEEN_SNAPSHOTS + 1;
+		let old_modified = snapshot.modified;
+
+		storage
+			.chunk_save_data(pos, b"third", CompressionType::Lz4)
+			.unwrap();
+		assert_eq!(storage.chunks[&pos].len(), 2);
+		assert_eq!(
+			storage.chunk_load_data(pos).unwrap().unwrap().data,
+			b"third"
+		);
+		assert_eq!(
+			storage
+				.chunk_load_data_at(pos, old_modified)
+				.unwrap()
+				.unwrap()
+				.data,
+			b"second"
+		);
+	}
+}
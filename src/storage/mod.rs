@@ -0,0 +1,249 @@
+use std::{collections::HashSet, fmt, path::Path, str::FromStr};
+
+use glam::{I64Vec2, IVec2};
+
+use crate::{
+	config::SnapshotRetention,
+	database::{ChunkDatabaseRecord, ChunkStats, CompressionType, PreviewDatabaseRecord},
+};
+
+pub mod filesystem;
+pub mod memory;
+pub mod sqlite;
+
+const SECONDS_BETWEEN_SNAPSHOTS: u64 = 14400;
+
+// Where chunks of a room are kept. Chosen when the room is created and stored in its database,
+// room metadata (regions, roles, history) always stays in the database.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+	#[default]
+	Sqlite, // In the room database
+	Filesystem, // One LZ4 file per chunk snapshot and preview, next to the room database
+	Memory,     // Lost when the room is unloaded
+}
+
+impl StorageBackend {
+	pub const fn as_str(self) -> &'static str {
+		match self {
+			Self::Sqlite => "sqlite",
+			Self::Filesystem => "filesystem",
+			Self::Memory => "memory",
+		}
+	}
+}
+
+impl fmt::Display for StorageBackend {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}", self.as_str())
+	}
+}
+
+impl FromStr for StorageBackend {
+	type Err = anyhow::Error;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"sqlite" => Ok(Self::Sqlite),
+			"filesystem" => Ok(Self::Filesystem),
+			"memory" => Ok(Self::Memory),
+			_ => anyhow::bail!("Unknown storage backend \"{s}\" (sqlite, filesystem, memory)"),
+		}
+	}
+}
+
+// Chunk snapshots and previews of a single room. Called from the database thread only, so implementations may block.
+pub trait ChunkStorage: Send {
+	// Newest snapshot saved no later than the given unix timestamp
+	fn chunk_load_data_at(
+		&mut self,
+		pos: IVec2,
+		timestamp: u64,
+	) -> anyhow::Result<Option<ChunkDatabaseRecord>>;
+
+	// Updates the newest snapshot of the chunk, or starts a new one if it's older than SECONDS_BETWEEN_SNAPSHOTS
+	fn chunk_save_data(
+		&mut self,
+		pos: IVec2,
+		data: &[u8],
+		compression_type: CompressionType,
+	) -> anyhow::Result<()>;
+
+	// Sorted by y, then x
+	fn chunk_list_stats(&mut self) -> anyhow::Result<Vec<ChunkStats>>;
+
+	// Newest modification time of the chunks within [from, to), None if there are no chunks
+	fn chunk_modified_in_area(&mut self, from: I64Vec2, to: I64Vec2) -> anyhow::Result<Option<u64>>;
+
+	// Delete chunk snapshots not covered by the retention tiers. The newest snapshot of each chunk is always kept.
+	// Returns the number of deleted snapshots.
+	fn chunk_prune_snapshots(
+		&mut self,
+		retention: &SnapshotRetention,
+		now: u64,
+	) -> anyhow::Result<usize>;
+
+	fn preview_load_data(
+		&mut self,
+		pos: IVec2,
+		zoom: u8,
+	) -> anyhow::Result<Option<PreviewDatabaseRecord>>;
+
+	fn preview_save_data(&mut self, pos: IVec2, zoom: u8, data: &[u8]) -> anyhow::Result<()>;
+
+	fn preview_clear_all(&mut self) -> anyhow::Result<()>;
+
+	// Returns the number of bytes reclaimed
+	fn vacuum(&mut self) -> anyhow::Result<u64> {
+		Ok(0)
+	}
+
+	fn chunk_load_data(&mut self, pos: IVec2) -> anyhow::Result<Option<ChunkDatabaseRecord>> {
+		self.chunk_load_data_at(pos, i64::MAX as u64)
+	}
+
+	fn chunk_list_all(&mut self) -> anyhow::Result<Vec<IVec2>> {
+		Ok(
+			self
+				.chunk_list_stats()?
+				.into_iter()
+				.map(|chunk| chunk.pos)
+				.collect(),
+		)
+	}
+}
+
+// Opens the storage of the room database at `database_path`, a read-only storage doesn't touch the files on open
+pub fn open(
+	backend: StorageBackend,
+	database_path: &str,
+	read_only: bool,
+) -> anyhow::Result<Box<dyn ChunkStorage>> {
+	Ok(match backend {
+		StorageBackend::Sqlite => Box::new(sqlite::SqliteChunkStorage::open(database_path, read_only)?),
+		StorageBackend::Filesystem => Box::new(filesystem::FilesystemChunkStorage::open(
+			Path::new(database_path).with_extension("tiles"),
+			read_only,
+		)?),
+		StorageBackend::Memory => Box::<memory::MemoryChunkStorage>::default(),
+	})
+}
+
+const fn starts_new_snapshot(newest_created: u64, now: u64) -> bool {
+	now.saturating_sub(newest_created) > SECONDS_BETWEEN_SNAPSHOTS
+}
+
+// Indices of the snapshots to delete, `snapshots` has to be grouped by chunk and sorted by modification time, newest first
+fn select_snapshots_to_prune(
+	snapshots: &[(IVec2, u64 /* modified */)],
+	retention: &SnapshotRetention,
+	now: u64,
+) -> Vec<usize> {
+	let mut to_delete = Vec::new();
+	let mut kept_periods = HashSet::<(usize /* tier */, u64 /* period */)>::new();
+	let mut current_chunk: Option<IVec2> = None;
+
+	for (idx, (pos, modified)) in snapshots.iter().enumerate() {
+		if current_chunk != Some(*pos) {
+			// Newest snapshot of this chunk, holds the live data
+			current_chunk = Some(*pos);
+			kept_periods.clear();
+			continue;
+		}
+
+		let age = now.saturating_sub(*modified);
+		let tier = retention
+			.tiers
+			.iter()
+			.enumerate()
+			.find(|(_, tier)| tier.max_age_s.is_none_or(|max_age| age < max_age));
+
+		let keep = tier.is_some_and(|(tier_idx, tier)| {
+			kept_periods.insert((tier_idx, modified / tier.interval_s.max(1)))
+		});
+
+		if !keep {
+			to_delete.push(idx);
+		}
+	}
+
+	to_delete
+}
+
+const fn in_area(pos: IVec2, from: I64Vec2, to: I64Vec2) -> bool {
+	let (x, y) = (pos.x as i64, pos.y as i64);
+	x >= from.x && x < to.x && y >= from.y && y < to.y
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::config::SnapshotRetentionTier;
+
+	const HOUR: u64 = 3600;
+	const DAY: u64 = 24 * HOUR;
+
+	fn retention() -> SnapshotRetention {
+		SnapshotRetention {
+			tiers: vec![
+				SnapshotRetentionTier {
+					max_age_s: Some(2 * DAY),
+					interval_s: 4 * HOUR,
+				},
+				SnapshotRetentionTier {
+					max_age_s: Some(30 * DAY),
+					interval_s: DAY,
+				},
+			],
+			compaction_interval_s: DAY,
+		}
+	}
+
+	#[test]
+	fn prune_keeps_newest_snapshot_of_each_chunk() {
+		let now = 100 * DAY;
+		let a = IVec2::new(0, 0);
+		let b = IVec2::new(1, 0);
+		// Both are older than every tier
+		let snapshots = [(a, now - 60 * DAY), (b, now - 90 * DAY)];
+
+		assert!(select_snapshots_to_prune(&snapshots, &retention(), now).is_empty());
+	}
+
+	#[test]
+	fn prune_keeps_one_snapshot_per_period() {
+		// Periods are aligned to the unix epoch, the middle of a day keeps 3 and 4 in the same one
+		let now = 100 * DAY + 12 * HOUR;
+		let pos = IVec2::new(0, 0);
+		let snapshots = [
+			(pos, now),                  // 0: newest, always kept
+			(pos, now - 4 * HOUR),       // 1: first tier
+			(pos, now - 8 * HOUR),       // 2: first tier, another period
+			(pos, now - 3 * DAY),        // 3: second tier
+			(pos, now - 3 * DAY - HOUR), // 4: second tier, same day as 3
+			(pos, now - 5 * DAY),        // 5: second tier, another day
+			(pos, now - 40 * DAY),       // 6: older than every tier
+		];
+
+		assert_eq!(
+			select_snapshots_to_prune(&snapshots, &retention(), now),
+			vec![4, 6]
+		);
+	}
+
+	#[test]
+	fn prune_tracks_periods_per_chunk() {
+		let now = 100 * DAY;
+		let a = IVec2::new(0, 0);
+		let b = IVec2::new(0, 1);
+		let snapshots = [
+			(a, now),
+			(a, now - 3 * DAY),
+			(b, now),
+			(b, now - 3 * DAY), // Same period as in chunk a, but a different chunk
+		];
+
+		assert!(select_snapshots_to_prune(&snapshots, &retention(), now).is_empty());
+	}
+}
@@ -0,0 +1,295 @@
+use glam::{I64Vec2, IVec2};
+use rusqlite::{params, OpenFlags};
+
+use crate::{
+	config::SnapshotRetention,
+	database::{
+		get_unix_timestamp, ChunkDatabaseRecord, ChunkStats, CompressionType, PreviewDatabaseRecord,
+		BUSY_TIMEOUT,
+	},
+};
+
+use super::{select_snapshots_to_prune, starts_new_snapshot, ChunkStorage};
+
+// Chunks in the chunk_data and previews tables of the room database, using its own connection
+pub struct SqliteChunkStorage {
+	conn: rusqlite::Connection,
+}
+
+impl SqliteChunkStorage {
+	pub fn open(database_path: &str, read_only: bool) -> rusqlite::Result<Self> {
+		if read_only {
+			let conn = rusqlite::Connection::open_with_flags(
+				database_path,
+				OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+			)?;
+			conn.busy_timeout(BUSY_TIMEOUT)?;
+			return Ok(Self { conn });
+		}
+
+		let conn = rusqlite::Connection::open(database_path)?;
+		conn.busy_timeout(BUSY_TIMEOUT)?;
+		conn.execute_batch("PRAGMA SYNCHRONOUS=OFF")?;
+		Ok(Self { conn })
+	}
+
+	fn chunk_insert(
+		&self,
+		pos: IVec2,
+		data: &[u8],
+		compression_type: CompressionType,
+	) -> rusqlite::Result<()> {
+		self.conn.execute(
+			"INSERT INTO chunk_data (x,y,data,modified,created,compression) VALUES(?,?,?,?,?,?)",
+			params![
+				pos.x,
+				pos.y,
+				data,
+				get_unix_timestamp(),
+				get_unix_timestamp(),
+				compression_type as i32,
+			],
+		)?;
+		Ok(())
+	}
+
+	fn get_size_bytes(&self) -> rusqlite::Result<u64> {
+		let page_count: u64 = self
+			.conn
+			.query_row("PRAGMA page_count", [], |row| row.get(0))?;
+		let page_size: u64 = self
+			.conn
+			.query_row("PRAGMA page_size", [], |row| row.get(0))?;
+		Ok(page_count * page_size)
+	}
+}
+
+impl ChunkStorage for SqliteChunkStorage {
+	fn chunk_load_data_at(
+		&mut self,
+		pos: IVec2,
+		timestamp: u64,
+	) -> anyhow::Result<Option<ChunkDatabaseRecord>> {
+		struct Row {
+			data: Vec<u8>,
+			compression: u8,
+			modified: i64,
+			created: i64,
+		}
+
+		if let Ok(row) = self.conn
+			.query_row(
+				"SELECT data, compression, modified, created FROM chunk_data WHERE x=? AND y=? AND modified<=? ORDER BY modified DESC",
+				params![pos.x, pos.y, timestamp as i64],
+				|row| {
+					Ok(Row {
+						data: row.get(0)?,
+						compression: row.get(1)?,
+						modified: row.get(2)?,
+						created: row.get(3)?,
+					})
+				},
+			) {
+				return Ok(Some(ChunkDatabaseRecord{
+					compression_type: CompressionType::try_from(row.compression).unwrap_or(CompressionType::Lz4),
+					created_at: row.created as u64,
+					modified_at: row.modified as u64,
+					data: row.data,
+				}));
+			}
+
+		Ok(None)
+	}
+
+	fn chunk_save_data(
+		&mut self,
+		pos: IVec2,
+		data: &[u8],
+		compression_type: CompressionType,
+	) -> anyhow::Result<()> {
+		struct Row {
+			timestamp: i64,
+			chunk_id: i64,
+		}
+
+		if let Ok(row) = self.conn.query_row(
+			"SELECT created, rowid FROM chunk_data WHERE x = ? AND y = ? ORDER BY created DESC",
+			params![pos.x, pos.y],
+			|row| {
+				Ok(Row {
+					timestamp: row.get(0)?,
+					chunk_id: row.get(1)?,
+				})
+			},
+		) {
+			//Chunk already exists, update chunk
+			log::trace!("Updating chunk");
+			if starts_new_snapshot(row.timestamp as u64, get_unix_timestamp()) {
+				//Insert a new chunk in its place
+				self.chunk_insert(pos, data, compression_type)?;
+			} else {
+				//Replace chunk
+				self.conn.execute(
+					"UPDATE chunk_data SET modified = ?, data = ?, compression = ? WHERE rowid = ?",
+					params![
+						get_unix_timestamp(),
+						data,
+						compression_type as i32,
+						row.chunk_id
+					],
+				)?;
+			}
+		} else {
+			// Chunk doesn't exist, create chunk
+			log::trace!("Creating chunk");
+			self.chunk_insert(pos, data, compression_type)?;
+		}
+
+		Ok(())
+	}
+
+	fn chunk_list_all(&mut self) -> anyhow::Result<Vec<IVec2>> {
+		struct Row {
+			x: i32,
+			y: i32,
+		}
+
+		let mut res = Vec::<IVec2>::new();
+
+		let mut stmt = self
+			.conn
+			.prepare("SELECT x, y FROM chunk_data GROUP BY x, y")?;
+		let iter = stmt.query_map([], |row| {
+			Ok(Row {
+				x: row.get(0)?,
+				y: row.get(1)?,
+			})
+		})?;
+
+		for row in iter.flatten() {
+			res.push(IVec2::new(row.x, row.y));
+		}
+
+		Ok(res)
+	}
+
+	fn chunk_list_stats(&mut self) -> anyhow::Result<Vec<ChunkStats>> {
+		let mut stmt = self.conn.prepare(
+			"SELECT x, y, COUNT(*), MAX(modified) FROM chunk_data GROUP BY x, y ORDER BY y, x",
+		)?;
+		let res: Vec<_> = stmt
+			.query_map([], |row| {
+				Ok(ChunkStats {
+					pos: IVec2::new(row.get(0)?, row.get(1)?),
+					snapshot_count: row.get(2)?,
+					modified_at: row.get::<_, i64>(3)? as u64,
+				})
+			})?
+			.flatten()
+			.collect();
+		Ok(res)
+	}
+
+	fn chunk_modified_in_area(&mut self, from: I64Vec2, to: I64Vec2) -> anyhow::Result<Option<u64>> {
+		let modified: Option<i64> = self.conn.query_row(
+			"SELECT MAX(modified) FROM chunk_data WHERE x>=? AND x<? AND y>=? AND y<?",
+			params![from.x, to.x, from.y, to.y],
+			|row| row.get(0),
+		)?;
+		Ok(modified.map(|modified| modified as u64))
+	}
+
+	fn chunk_prune_snapshots(
+		&mut self,
+		retention: &SnapshotRetention,
+		now: u64,
+	) -> anyhow::Result<usize> {
+		let rows: Vec<(i64 /* rowid */, IVec2, u64 /* modified */)> = {
+			let mut stmt = self
+				.conn
+				.prepare("SELECT rowid, x, y, modified FROM chunk_data ORDER BY x, y, modified DESC")?;
+			let res: Vec<_> = stmt
+				.query_map([], |row| {
+					Ok((
+						row.get(0)?,
+						IVec2::new(row.get(1)?, row.get(2)?),
+						row.get::<_, i64>(3)? as u64,
+					))
+				})?
+				.flatten()
+				.collect();
+			res
+		};
+
+		let snapshots: Vec<_> = rows.iter().map(|row| (row.1, row.2)).collect();
+		let to_delete = select_snapshots_to_prune(&snapshots, retention, now);
+
+		let transaction = self.conn.unchecked_transaction()?;
+		for idx in &to_delete {
+			transaction.execute(
+				"DELETE FROM chunk_data WHERE rowid=?",
+				params![rows[*idx].0],
+			)?;
+		}
+		transaction.commit()?;
+
+		Ok(to_delete.len())
+	}
+
+	fn preview_load_data(
+		&mut self,
+		pos: IVec2,
+		zoom: u8,
+	) -> anyhow::Result<Option<PreviewDatabaseRecord>> {
+		struct Row {
+			data: Vec<u8>,
+		}
+
+		if let Ok(row) = self.conn.query_row(
+			"SELECT data FROM previews WHERE x=? AND y=? AND zoom=?",
+			params![pos.x, pos.y, zoom],
+			|row| Ok(Row { data: row.get(0)? }),
+		) {
+			return Ok(Some(PreviewDatabaseRecord { data: row.data }));
+		}
+
+		Ok(None)
+	}
+
+	fn preview_save_data(&mut self, pos: IVec2, zoom: u8, data: &[u8]) -> anyhow::Result<()> {
+		if let Ok(rowid) = self.conn.query_row(
+			"SELECT rowid FROM previews WHERE x=? AND y=? AND zoom=?",
+			params![pos.x, pos.y, zoom],
+			|row| {
+				let res: u32 = row.get(0)?;
+				Ok(res)
+			},
+		) {
+			// Update preview data
+			self.conn.execute(
+				"UPDATE previews SET x=?, y=?, zoom=?, data=? WHERE rowid=?",
+				params![pos.x, pos.y, zoom, data, rowid],
+			)?;
+		} else {
+			// Insert new preview data
+			self.conn.execute(
+				"INSERT INTO previews (x,y,zoom,data) VALUES (?,?,?,?)",
+				params![pos.x, pos.y, zoom, data],
+			)?;
+		}
+
+		Ok(())
+	}
+
+	fn preview_clear_all(&mut self) -> anyhow::Result<()> {
+		self.conn.execute("DELETE FROM previews", [])?;
+		Ok(())
+	}
+
+	fn vacuum(&mut self) -> anyhow::Result<u64> {
+		let size_before = self.get_size_bytes()?;
+		self.conn.execute_batch("VACUUM")?;
+		let size_after = self.get_size_bytes()?;
+		Ok(size_before.saturating_sub(size_after))
+	}
+}
@@ -0,0 +1,190 @@
+use std::sync::Arc;
+
+use glam::{I64Vec2, IVec2};
+use tokio::sync::Mutex;
+
+use crate::{
+	compression::decompress_lz4,
+	database::{Database, DatabaseFunc},
+	export,
+	http::{Request, Response, RoomDatabase},
+	limits::{self, CHUNK_IMAGE_SIZE_BYTES_RGBA, CHUNK_SIZE_PX},
+	server::ServerMutex,
+};
+
+// Read-only canvas tiles for slippy map viewers (Leaflet, OpenLayers), rendered from the stored chunks and previews.
+// Tiles reflect the last autosave of the room, clients revalidate them with ETags.
+
+// Zoom level showing chunks at full resolution, every level below halves the resolution
+pub const TILE_ZOOM_MAX: u8 = limits::PREVIEW_SYSTEM_LAYER_COUNT;
+
+// Level of detail as stored in the database: 0 for chunks, 1..=PREVIEW_SYSTEM_LAYER_COUNT for preview layers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TileLayer(u8);
+
+impl TileLayer {
+	pub const fn from_zoom(zoom: u8) -> Option<Self> {
+		if zoom > TILE_ZOOM_MAX {
+			return None;
+		}
+		Some(Self(TILE_ZOOM_MAX - zoom))
+	}
+
+	pub const fn from_preview_zoom(zoom: u8) -> Option<Self> {
+		if zoom == 0 || zoom > limits::PREVIEW_SYSTEM_LAYER_COUNT {
+			return None;
+		}
+		Some(Self(zoom))
+	}
+
+	// Chunks covered by the tile at `pos`, from inclusive, to exclusive
+	fn chunk_area(self, pos: IVec2) -> (I64Vec2, I64Vec2) {
+		let scale = 1i64 << self.0;
+		let from = pos.as_i64vec2() * scale;
+		(from, from + I64Vec2::splat(scale))
+	}
+}
+
+// Parses "x" and "y.png" path segments
+pub fn parse_tile_pos(x: &str, y: &str) -> Option<IVec2> {
+	let y = y.strip_suffix(".png")?;
+	Some(IVec2::new(x.parse().ok()?, y.parse().ok()?))
+}
+
+enum TileLoad {
+	Missing,
+	NotModified { etag: String },
+	Loaded { etag: String, compressed: Vec<u8> },
+}
+
+fn etag_matches(if_none_match: Option<&str>, etag: &str) -> bool {
+	if_none_match.is_some_and(|if_none_match| {
+		if_none_match.split(',').any(|candidate| {
+			let candidate = candidate.trim();
+			candidate == "*" || candidate.trim_start_matches("W/") == etag
+		})
+	})
+}
+
+async fn load_tile(
+	database: &Arc<Mutex<Database>>,
+	layer: TileLayer,
+	pos: IVec2,
+	if_none_match: Option<&str>,
+) -> anyhow::Result<TileLoad> {
+	let (from, to) = layer.chunk_area(pos);
+	let Some(modified) = DatabaseFunc::chunk_modified_in_area(database, from, to).await? else {
+		return Ok(TileLoad::Missing); // Nothing drawn here
+	};
+
+	if layer.0 == 0 {
+		let etag = format!("\"{modified}\"");
+		if etag_matches(if_none_match, &etag) {
+			return Ok(TileLoad::NotModified { etag });
+		}
+
+		let compressed = DatabaseFunc::chunk_load_data(database, pos)
+			.await?
+			.map(|record| record.data);
+		return Ok(
+			compressed.map_or(TileLoad::Missing, |compressed| TileLoad::Loaded {
+				etag,
+				compressed,
+			}),
+		);
+	}
+
+	// Previews are regenerated some time after the chunks are saved, so their ETag comes from the preview itself
+	let Some(compressed) = DatabaseFunc::preview_load_data(database, pos, layer.0)
+		.await?
+		.map(|record| record.data)
+	else {
+		return Ok(TileLoad::Missing); // Not generated yet
+	};
+
+	let etag = format!("\"p{:08x}\"", crc32fast::hash(&compressed));
+	if etag_matches(if_none_match, &etag) {
+		return Ok(TileLoad::NotModified { etag });
+	}
+	Ok(TileLoad::Loaded { etag, compressed })
+}
+
+pub async fn serve_tile(
+	server_mtx: &ServerMutex,
+	request: &Request,
+	room_name: &str,
+	layer: TileLayer,
+	pos: IVec2,
+) -> anyhow::Result<Response> {
+	let Some(database) = RoomDatabase::open(server_mtx, room_name).await? else {
+		return Ok(Response::error(404, "Room not found"));
+	};
+	let tile = load_tile(database.get(), layer, pos, request.header("if-none-match")).await;
+	database.close().await;
+
+	match tile? {
+		TileLoad::Missing => Ok(Response::error(404, "Tile not found")),
+		TileLoad::NotModified { etag } => Ok(
+			Response::new(304, "image/png", Vec::new())
+				.with_header("ETag", etag)
+				.with_header("Cache-Control", String::from("no-cache")),
+		),
+		TileLoad::Loaded { etag, compressed } => {
+			let png = tokio::task::spawn_blocking(move || {
+				let Some(rgba) = decompress_lz4(&compressed, CHUNK_IMAGE_SIZE_BYTES_RGBA) else {
+					anyhow::bail!("Failed to decompress tile");
+				};
+				export::encode_png(IVec2::splat(CHUNK_SIZE_PX as i32), &rgba)
+			})
+			.await??;
+
+			Ok(
+				Response::new(200, "image/png", png)
+					.with_header("ETag", etag)
+					.with_header("Cache-Control", String::from("no-cache")),
+			)
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn tile_pos_requires_png_suffix() {
+		assert_eq!(parse_tile_pos("-3", "4.png"), Some(IVec2::new(-3, 4)));
+		assert_eq!(parse_tile_pos("1", "2"), None);
+		assert_eq!(parse_tile_pos("a", "2.png"), None);
+	}
+
+	#[test]
+	fn zoom_maps_to_layer() {
+		assert_eq!(TileLayer::from_zoom(TILE_ZOOM_MAX), Some(TileLayer(0)));
+		assert_eq!(TileLayer::from_zoom(0), Some(TileLayer(TILE_ZOOM_MAX)));
+		assert_eq!(TileLayer::from_zoom(TILE_ZOOM_MAX + 1), None);
+		assert_eq!(TileLayer::from_preview_zoom(1), Some(TileLayer(1)));
+		assert_eq!(TileLayer::from_preview_zoom(0), None);
+	}
+
+	#[test]
+	fn tile_covers_chunks_of_its_layer() {
+		assert_eq!(
+			TileLayer(0).chunk_area(IVec2::new(-1, 2)),
+			(I64Vec2::new(-1, 2), I64Vec2::new(0, 3))
+		);
+		assert_eq!(
+			TileLayer(2).chunk_area(IVec2::new(-1, 2)),
+			(I64Vec2::new(-4, 8), I64Vec2::new(0, 12))
+		);
+	}
+
+	#[test]
+	fn etag_matching() {
+		assert!(etag_matches(Some("\"5\""), "\"5\""));
+		assert!(etag_matches(Some("\"4\", W/\"5\""), "\"5\""));
+		assert!(etag_matches(Some("*"), "\"5\""));
+		assert!(!etag_matches(Some("\"4\""), "\"5\""));
+		assert!(!etag_matches(None, "\"5\""));
+	}
+}
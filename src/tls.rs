@@ -0,0 +1,211 @@
+use std::{
+	io,
+	pin::Pin,
+	sync::Arc,
+	task::{Context, Poll},
+};
+
+use parking_lot::Mutex as SyncMutex;
+use tokio::{
+	io::{AsyncRead, AsyncWrite, ReadBuf},
+	net::TcpStream,
+};
+use tokio_rustls::{
+	rustls::{
+		self,
+		pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+	},
+	server::TlsStream,
+	TlsAcceptor,
+};
+
+// Plain TCP or TLS-terminated stream of a client connection
+pub enum ServerStream {
+	Plain(TcpStream),
+	Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl AsyncRead for ServerStream {
+	fn poll_read(
+		self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &mut ReadBuf<'_>,
+	) -> Poll<io::Result<()>> {
+		match self.get_mut() {
+			Self::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+			Self::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+		}
+	}
+}
+
+impl AsyncWrite for ServerStream {
+	fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+		match self.get_mut() {
+			Self::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+			Self::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+		}
+	}
+
+	fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		match self.get_mut() {
+			Self::Plain(stream) => Pin::new(stream).poll_flush(cx),
+			Self::Tls(stream) => Pin::new(stream).poll_flush(cx),
+		}
+	}
+
+	fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		match self.get_mut() {
+			Self::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+			Self::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+		}
+	}
+}
+
+fn load_acceptor(cert_path: &str, key_path: &str) -> anyhow::Result<TlsAcceptor> {
+	let certs = CertificateDer::pem_file_iter(cert_path)
+		.and_then(Iterator::collect::<Result<Vec<_>, _>>)
+		.map_err(|e| anyhow::anyhow!("Failed to load certificate {cert_path}: {e}"))?;
+
+	if certs.is_empty() {
+		anyhow::bail!("No certificates found in {cert_path}");
+	}
+
+	let key = PrivateKeyDer::from_pem_file(key_path)
+		.map_err(|e| anyhow::anyhow!("Failed to load private key {key_path}: {e}"))?;
+
+	let config =
+		rustls::ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+			.with_safe_default_protocol_versions()?
+			.with_no_client_auth()
+			.with_single_cert(certs, key)?;
+
+	Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+// Certificate and key loaded from PEM files, can be reloaded while the server is running
+pub struct TlsState {
+	cert_path: String,
+	key_path: String,
+	acceptor: SyncMutex<TlsAcceptor>,
+}
+
+impl TlsState {
+	pub fn new(cert_path: String, key_path: String) -> anyhow::Result<Arc<Self>> {
+		let acceptor = load_acceptor(&cert_path, &key_path)?;
+		log::info!("Loaded TLS certificate {cert_path}");
+		Ok(Arc::new(Self {
+			cert_path,
+			key_path,
+			acceptor: SyncMutex::new(acceptor),
+		}))
+	}
+
+	// Keeps the previous certificate if the new one cannot be loaded
+	pub fn reload(&self) -> anyhow::Result<()> {
+		let acceptor = load_acceptor(&self.cert_path, &self.key_path)?;
+		*self.acceptor.lock() = acceptor;
+		log::info!("Reloaded TLS certificate {}", self.cert_path);
+		Ok(())
+	}
+
+	pub async fn accept(&self, tcp_conn: TcpStream) -> io::Result<ServerStream> {
+		let acceptor = self.acceptor.lock().clone();
+		let stream = acceptor.accept(tcp_conn).await?;
+		Ok(ServerStream::Tls(Box::new(stream)))
+	}
+
+	// Reload the certificate on every SIGHUP, so renewed certificates are picked up without a restart
+	pub fn spawn_reload_on_sighup(self: Arc<Self>) -> anyhow::Result<()> {
+		let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?;
+
+		tokio::task::Builder::new()
+			.name("TLS reload task")
+			.spawn(async move {
+				while hangup.recv().await.is_some() {
+					if let Err(e) = self.reload() {
+						log::error!("Failed to reload TLS certificate: {e}");
+					}
+				}
+			})?;
+
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use tokio::net::TcpListener;
+	use tokio_rustls::{rustls::pki_types::ServerName, TlsConnector};
+
+	// Writes a new self-signed certificate for localhost and its key, returns the certificate
+	fn write_self_signed(cert_path: &str, key_path: &str) -> CertificateDer<'static> {
+		let cert = rcgen::generate_simple_self_signed(vec![String::from("localhost")]).unwrap();
+		std::fs::write(cert_path, cert.cert.pem()).unwrap();
+		std::fs::write(key_path, cert.signing_key.serialize_pem()).unwrap();
+		cert.cert.der().clone()
+	}
+
+	// Connects to the server, trusting only the given certificate
+	async fn handshake(tls: &Arc<TlsState>, trusted: CertificateDer<'static>) -> anyhow::Result<()> {
+		let listener = TcpListener::bind("127.0.0.1:0").await?;
+		let addr = listener.local_addr()?;
+
+		let server_tls = tls.clone();
+		let server = tokio::spawn(async move {
+			let (tcp_conn, _) = listener.accept().await?;
+			server_tls.accept(tcp_conn).await.map(|_| ())
+		});
+
+		let mut roots = rustls::RootCertStore::empty();
+		roots.add(trusted)?;
+		let config = rustls::ClientConfig::builder_with_provider(Arc::new(
+			rustls::crypto::ring::default_provider(),
+		))
+		.with_safe_default_protocol_versions()?
+		.with_root_certificates(roots)
+		.with_no_client_auth();
+
+		let tcp_conn = TcpStream::connect(addr).await?;
+		let result = TlsConnector::from(Arc::new(config))
+			.connect(ServerName::try_from("localhost")?, tcp_conn)
+			.await;
+		let _dont_care = server.await;
+		result?;
+		Ok(())
+	}
+
+	#[tokio::test]
+	async fn reload_picks_up_new_certificate() {
+		let dir = tempfile::tempdir().unwrap();
+		let cert_path = dir.path().join("cert.pem").to_string_lossy().into_owned();
+		let key_path = dir.path().join("key.pem").to_string_lossy().into_owned();
+
+		let first = write_self_signed(&cert_path, &key_path);
+		let tls = TlsState::new(cert_path.clone(), key_path.clone()).unwrap();
+		handshake(&tls, first.clone()).await.unwrap();
+
+		// Swapping the files alone doesn't change the served certificate
+		let second = write_self_signed(&cert_path, &key_path);
+		handshake(&tls, first.clone()).await.unwrap();
+		assert!(handshake(&tls, second.clone()).await.is_err());
+
+		tls.reload().unwrap();
+		handshake(&tls, second).await.unwrap();
+		assert!(handshake(&tls, first).await.is_err());
+	}
+
+	#[tokio::test]
+	async fn failed_reload_keeps_previous_certificate() {
+		let dir = tempfile::tempdir().unwrap();
+		let cert_path = dir.path().join("cert.pem").to_string_lossy().into_owned();
+		let key_path = dir.path().join("key.pem").to_string_lossy().into_owned();
+
+		let cert = write_self_signed(&cert_path, &key_path);
+		let tls = TlsState::new(cert_path.clone(), key_path).unwrap();
+
+		std::fs::write(&cert_path, "not a certificate").unwrap();
+		assert!(tls.reload().is_err());
+		handshake(&tls, cert).await.unwrap();
+	}
+}
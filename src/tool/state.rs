@@ -154,7 +154,7 @@ impl ToolStateLine {
 		}
 
 		// convert set to vec
-		let out_pixels_vec = ToolStateLine::hashmap_to_vec(&out_pixels_map);
+		let out_pixels_vec = Self::hashmap_to_vec(&out_pixels_map);
 
 		chunk_cache
 			.set_pixels_for_layer(
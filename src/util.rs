@@ -19,3 +19,48 @@ pub fn angle_ivec2(from: IVec2, to: IVec2) -> f32 {
 pub fn step_angle(angle: f32) -> Vec2 {
 	angle.sin_cos().into()
 }
+
+// Parses either an absolute unix timestamp or a relative time ago ("30m", "4h", "2d")
+pub fn parse_timestamp(text: &str, now: u64) -> Option<u64> {
+	if let Ok(timestamp) = text.parse::<u64>() {
+		return Some(timestamp);
+	}
+
+	Some(now.saturating_sub(parse_duration(text)?))
+}
+
+// Parses a duration with a unit ("30s", "30m", "4h", "2d") into seconds
+pub fn parse_duration(text: &str) -> Option<u64> {
+	let unit = text.chars().last()?;
+	let amount = text[..text.len() - unit.len_utf8()].parse::<u64>().ok()?;
+	let multiplier = match unit {
+		's' => 1,
+		'm' => 60,
+		'h' => 60 * 60,
+		'd' => 60 * 60 * 24,
+		_ => return None,
+	};
+
+	amount.checked_mul(multiplier)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn parse_duration_units() {
+		assert_eq!(parse_duration("30s"), Some(30));
+		assert_eq!(parse_duration("30m"), Some(30 * 60));
+		assert_eq!(parse_duration("4h"), Some(4 * 60 * 60));
+		assert_eq!(parse_duration("2d"), Some(2 * 24 * 60 * 60));
+	}
+
+	#[test]
+	fn parse_duration_rejects_invalid_input() {
+		for text in ["", "s", "30", "30x", "-5m", "1.5h", "m30"] {
+			assert_eq!(parse_duration(text), None, "{text}");
+		}
+		assert_eq!(parse_duration(&format!("{}d", u64::MAX)), None);
+	}
+}
@@ -7,7 +7,7 @@ const header_offset = 2;
 const size_u8 = 1;
 const size_u16 = 2;
 const size_u32 = 4;
-//const size_u64 = 8;
+const size_u64 = 8;
 
 const size_s8 = 1;
 //const size_s16 = 2;
@@ -23,7 +23,7 @@ export enum ChatMessageType {
 
 enum ClientCmd {
 	message = 1,	// u16 text_size, utf-8 text
-	announce = 2, // u8 room_name_size, utf-8 room_name, u8 nickname_size, utf-8 nickname
+	announce = 2, // u8 room_name_size, utf-8 room_name, u8 nickname_size, utf-8 nickname, u8 auth_type, u8 secret_size, utf-8 secret
 	ping = 4,
 	cursor_pos = 100, // s32 x, s32 y
 	cursor_down = 101,
@@ -31,11 +31,13 @@ enum ClientCmd {
 	boundary = 103,
 	chunks_received = 104,
 	preview_request = 105, // s32 previewX, s32 previewY, u8 zoom
+	view_timestamp = 106,	 // u64 unix timestamp, 0 for live canvas
 	tool_type = 200,			 // u8 type
 	tool_color = 201,			 // u8 red, u8 green, u8 blue
 	tool_size = 202,			 // u8 size
 	tool_flow = 203,			 // f32 flow
-	undo = 300
+	undo = 300,
+	redo = 301
 }
 
 enum ServerCmd {
@@ -80,6 +82,17 @@ import { Buffer } from "buffer";
 import * as lz4 from "lz4js";
 import type { RoomInstance } from "./room_instance";
 
+export enum AuthType {
+	guest = 0,
+	password = 1,
+	token = 2,
+}
+
+export interface Credentials {
+	type: AuthType;
+	secret: string;
+}
+
 export class Client {
 	instance: RoomInstance;
 	users: Array<User> = [];
@@ -93,6 +106,7 @@ export class Client {
 		instance: RoomInstance;
 		address: string;
 		nickname: string;
+		credentials: Credentials;
 		room_name: string;
 		connection_callback: (error_str?: string) => void;
 	}) {
@@ -105,7 +119,7 @@ export class Client {
 		this.socket.onopen = function (e) {
 			e;
 			console.log("Socket connected");
-			c.socketSendAnnouncement(params.room_name, params.nickname);
+			c.socketSendAnnouncement(params.room_name, params.nickname, params.credentials);
 			params.connection_callback(undefined);
 
 		}
@@ -133,15 +147,18 @@ export class Client {
 		this.chat = chat;
 	}
 
-	socketSendAnnouncement(room_name: string, nickname: string) {
+	socketSendAnnouncement(room_name: string, nickname: string, credentials: Credentials) {
 		let room_name_utf8 = textToUTF8(room_name);
 		let room_name_utf8_size = room_name_utf8.length;
 
 		let nickname_utf8 = textToUTF8(nickname);
 		let nickname_utf8_size = nickname_utf8.length;
 
+		let secret_utf8 = credentials.type == AuthType.guest ? new Uint8Array() : textToUTF8(credentials.secret);
+		let secret_utf8_size = secret_utf8.length;
+
 		let buf = createMessage(ClientCmd.announce,
-			1 + room_name_utf8_size + 1 + nickname_utf8_size);
+			1 + room_name_utf8_size + 1 + nickname_utf8_size + 1 + (credentials.type == AuthType.guest ? 0 : 1 + secret_utf8_size));
 
 		let buf_u8 = new Uint8Array(buf);
 
@@ -159,6 +176,15 @@ export class Client {
 			buf_u8[offset++] = nickname_utf8[i];
 		}
 
+		//Fill credentials, guests send only the auth type
+		buf_u8[offset++] = credentials.type;
+		if (credentials.type != AuthType.guest) {
+			buf_u8[offset++] = secret_utf8_size;
+			for (let i = 0; i < secret_utf8_size; i++) {
+				buf_u8[offset++] = secret_utf8[i];
+			}
+		}
+
 		this.socket!.send(buf);
 	}
 
@@ -227,6 +253,11 @@ export class Client {
 		this.socket!.send(buf);
 	}
 
+	socketSendRedo() {
+		let buf = createMessage(ClientCmd.redo, 0);
+		this.socket!.send(buf);
+	}
+
 	socketSendCursorDown() {
 		let buf = createMessage(ClientCmd.cursor_down, 0);
 		this.socket!.send(buf);
@@ -264,6 +295,13 @@ export class Client {
 		this.socket!.send(buf);
 	}
 
+	socketSendViewTimestamp(timestamp: number) {
+		let buf = createMessage(ClientCmd.view_timestamp, size_u64);
+		let dataview = new DataView(buf, header_offset);
+		dataview.setBigUint64(0, BigInt(timestamp));
+		this.socket!.send(buf);
+	}
+
 	socketSendToolType(tool_id: number) {
 		let buf = createMessage(ClientCmd.tool_type, size_u8 * 1);
 		let dataview = new DataView(buf, header_offset);
@@ -3,6 +3,7 @@ import { globals } from ".";
 import { RoomScreenGlobals } from "./views/canvas/room_screen"
 import { ToolboxGlobals } from "./tool_panel";
 import { RoomInstance } from "./room_instance";
+import { Credentials } from "./client";
 
 
 function dec2hex(n: number) {
@@ -19,6 +20,7 @@ export function rgb2hex(red: number, green: number, blue: number) {
 export interface ConnectParams {
 	host: string;
 	nickname: string;
+	credentials: Credentials;
 	room_name: string;
 }
 export class Multipixel {
@@ -79,7 +81,12 @@ export class Multipixel {
 		}, 15000);
 
 		document.addEventListener('keydown', (event) => {
-			if (event.ctrlKey && event.key === 'z') {
+			if (event.ctrlKey && (event.key === 'y' || (event.shiftKey && event.key.toLowerCase() === 'z'))) {
+				const instance = this.room_instance;
+				if (instance && instance.state) {
+					instance.state.client.socketSendRedo();
+				}
+			} else if (event.ctrlKey && event.key === 'z') {
 				const instance = this.room_instance;
 				if (instance && instance.state) {
 					instance.state.client.socketSendUndo();
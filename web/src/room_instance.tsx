@@ -76,6 +76,7 @@ export class RoomInstance {
 			instance: this,
 			address: params.connect_params.host,
 			nickname: params.connect_params.nickname,
+			credentials: params.connect_params.credentials,
 			room_name: params.connect_params.room_name,
 			connection_callback: (error_str) => {
 				if (error_str) {
@@ -153,6 +154,12 @@ export class RoomInstance {
 		}
 	}
 
+	actionRedo() {
+		if (this.state) {
+			this.state.client.socketSendRedo();
+		}
+	}
+
 	private actionColorPick() {
 		if (!this.state) return;
 		let rgb = this.state.map.getPixel(this.cursor.canvas_x, this.cursor.canvas_y);
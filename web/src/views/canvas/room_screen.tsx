@@ -104,6 +104,13 @@ export function RoomScreen({ globals, instance, refs_callback }: { globals: Room
 						<Icon path="img/tool/undo.svg" />
 					</ButtonTool>
 				</Tooltip>
+				<Tooltip title="Redo">
+					<ButtonTool on_click={() => {
+						instance.actionRedo();
+					}}>
+						<Icon path="img/tool/redo.svg" />
+					</ButtonTool>
+				</Tooltip>
 				<Tooltip title="Reset zoom to 100%">
 					<ButtonTool on_click={() => {
 						instance.actionZoom1_1();
@@ -7,10 +7,14 @@ import { globals } from "../..";
 import * as defines from "../../global_defines";
 import { useLocalState } from "../../utils/useLocalState";
 import { parseAsString, useQueryState } from "nuqs";
+import { AuthType, Credentials } from "../../client";
 
 
 export function LoginScreen({ initial_error_text }: { initial_error_text?: string }) {
 	const [username, setUsername] = useLocalState("nickname", "");
+	const [password, setPassword] = useState("");
+	// Issued by the /token chat command, kept so registered users don't have to type their password every time
+	const [token, setToken] = useLocalState("login_token", "");
 	const [roomName, setRoomName] = useQueryState("room_name", parseAsString.withDefault("main"));
 	const [connecting, setConnecting] = useState(false);
 
@@ -21,6 +25,14 @@ export function LoginScreen({ initial_error_text }: { initial_error_text?: strin
 			error.setErrorMsg(initial_error_text);
 	}, []);
 
+	const getCredentials = (): Credentials => {
+		if (password != "")
+			return { type: AuthType.password, secret: password };
+		if (token.trim() != "")
+			return { type: AuthType.token, secret: token.trim() };
+		return { type: AuthType.guest, secret: "" };
+	};
+
 	const start = () => {
 		error.launch(async () => {
 			setConnecting(true);
@@ -32,6 +44,7 @@ export function LoginScreen({ initial_error_text }: { initial_error_text?: strin
 							connect_params: {
 								host: defines.connect_url,
 								nickname: username.trim(),
+								credentials: getCredentials(),
 								room_name: roomName.trim(),
 							},
 							connection_callback: (error_str) => {
@@ -65,6 +78,8 @@ export function LoginScreen({ initial_error_text }: { initial_error_text?: strin
 	else {
 		content = <>
 			<LabeledTextField required label="Username" valfunc={[username, setUsername]} onReturnPress={start} />
+			<LabeledTextField label="Password (registered users)" type="password" valfunc={[password, setPassword]} onReturnPress={start} />
+			<LabeledTextField label="Login token (instead of password)" type="password" valfunc={[token, setToken]} onReturnPress={start} />
 			<LabeledTextField required label="Room name" valfunc={[roomName, setRoomName]} onReturnPress={start} />
 			<Button on_click={start}>Join</Button>
 			{error.msg}